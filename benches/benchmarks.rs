@@ -1,6 +1,6 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use std::cmp::Ordering;
-use unicol_sandbox::{collate_no_tiebreak, CollationOptions, KeysSource};
+use unicol_sandbox::{collate, collate_no_tiebreak, sort_key, CollationOptions, KeysSource};
 
 fn conformance(path: &str, options: CollationOptions) {
     let test_data = std::fs::read_to_string(path).unwrap();
@@ -40,6 +40,7 @@ fn ducet_ni(c: &mut Criterion) {
                 CollationOptions {
                     keys_source: KeysSource::Ducet,
                     shifting: false,
+                    ..Default::default()
                 },
             )
         })
@@ -54,6 +55,7 @@ fn ducet_shifted(c: &mut Criterion) {
                 CollationOptions {
                     keys_source: KeysSource::Ducet,
                     shifting: true,
+                    ..Default::default()
                 },
             )
         })
@@ -68,6 +70,7 @@ fn cldr_ni(c: &mut Criterion) {
                 CollationOptions {
                     keys_source: KeysSource::Cldr,
                     shifting: false,
+                    ..Default::default()
                 },
             )
         })
@@ -82,11 +85,85 @@ fn cldr_shifted(c: &mut Criterion) {
                 CollationOptions {
                     keys_source: KeysSource::Cldr,
                     shifting: true,
+                    ..Default::default()
                 },
             )
         })
     });
 }
 
-criterion_group!(benches, ducet_ni, ducet_shifted, cldr_ni, cldr_shifted);
+fn long_prefix(c: &mut Criterion) {
+    let prefix = "abcdefghij".repeat(500);
+    let strings: Vec<String> = ["a", "b", "c", "d", "e", "f", "g", "h"]
+        .iter()
+        .map(|suffix| format!("{prefix}{suffix}"))
+        .collect();
+
+    c.bench_function("Long common prefix", |b| {
+        b.iter(|| {
+            for x in &strings {
+                for y in &strings {
+                    collate(x, y, CollationOptions::default());
+                }
+            }
+        })
+    });
+}
+
+fn combining_marks(c: &mut Criterion) {
+    // A contraction starter followed by a long run of combining marks; and many discontiguous
+    // matches in a row. Both should take time linear in their length
+    let marks: String = "\u{316}\u{301}\u{345}\u{334}\u{327}\u{308}\u{306}"
+        .chars()
+        .cycle()
+        .take(100)
+        .collect();
+    let run = format!("и{marks}");
+    let discontiguous = "и\u{316}\u{306}".repeat(1_000);
+
+    c.bench_function("Combining mark run", |b| {
+        b.iter(|| sort_key(&run, CollationOptions::default()))
+    });
+
+    c.bench_function("Discontiguous matches", |b| {
+        b.iter(|| sort_key(&discontiguous, CollationOptions::default()))
+    });
+}
+
+fn contractions(c: &mut Criterion) {
+    // Nearly every letter starts a contraction (Cyrillic with a breve or diaeresis, decomposed),
+    // and about half complete one
+    let text = "и\u{306}е\u{308}у\u{306}иеуо\u{308}аэ\u{308}ы\u{308}".repeat(200);
+
+    c.bench_function("Contractions", |b| {
+        b.iter(|| sort_key(&text, CollationOptions::default()))
+    });
+}
+
+fn ascii_words(c: &mut Criterion) {
+    let text = "The quick brown fox jumps over the lazy dog; pack my box with five dozen liquor \
+        jugs! How vexingly quick daft zebras jump. Sphinx of black quartz, judge my vow. The five \
+        boxing wizards jump quickly (at 10:45 pm), and Jackdaws love my big sphinx of quartz";
+    let words: Vec<&str> = text.split_whitespace().collect();
+
+    c.bench_function("ASCII words", |b| {
+        b.iter(|| {
+            let mut sorted = words.clone();
+            sorted.sort_by(|x, y| collate(x, y, CollationOptions::default()));
+            sorted
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    ducet_ni,
+    ducet_shifted,
+    cldr_ni,
+    cldr_shifted,
+    long_prefix,
+    combining_marks,
+    contractions,
+    ascii_words
+);
 criterion_main!(benches);
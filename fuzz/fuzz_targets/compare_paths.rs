@@ -0,0 +1,128 @@
+#![no_main]
+
+use std::cmp::Ordering;
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary, Unstructured};
+use libfuzzer_sys::fuzz_target;
+use unicol_sandbox::{
+    collate, collate_no_tiebreak, collation_elements, compare_ceas, sort_key, CollationOptions,
+    CollationStrength, KeysSource, MaxVariable, NormalizationForm, QuaternarySource, ReorderGroup,
+    TiebreakMode,
+};
+
+// A few reorderings, since the option takes a slice
+const REORDERS: &[&[ReorderGroup]] = &[
+    &[],
+    &[ReorderGroup::Kana, ReorderGroup::Latin, ReorderGroup::Han],
+    &[ReorderGroup::Greek, ReorderGroup::Cyrillic],
+    &[ReorderGroup::Digit, ReorderGroup::Latin],
+    &[ReorderGroup::Symbol, ReorderGroup::Punctuation],
+    &[ReorderGroup::Currency, ReorderGroup::Space],
+];
+
+// Options are drawn from the start of the input, covering everything that changes how the
+// collation elements are built, trimmed, walked, or turned into keys, and the tiebreak that
+// collate falls back on. Those that take a table or a closure (weight_overrides, primary_order,
+// etc.) are left at their defaults
+#[derive(Debug)]
+struct Input<'a> {
+    opt: CollationOptions<'static>,
+    a: &'a str,
+    b: &'a str,
+}
+
+impl<'a> Arbitrary<'a> for Input<'a> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let max_variable = if u.arbitrary()? {
+            Some(*u.choose(&[
+                MaxVariable::Space,
+                MaxVariable::Punct,
+                MaxVariable::Symbol,
+                MaxVariable::Currency,
+            ])?)
+        } else {
+            None
+        };
+
+        let opt = CollationOptions {
+            keys_source: *u.choose(&[KeysSource::Cldr, KeysSource::Ducet])?,
+            shifting: u.arbitrary()?,
+            strength: *u.choose(&[
+                CollationStrength::Primary,
+                CollationStrength::Secondary,
+                CollationStrength::Tertiary,
+                CollationStrength::Quaternary,
+            ])?,
+            backwards_secondary: u.arbitrary()?,
+            backwards_tertiary: u.arbitrary()?,
+            max_variable,
+            tiebreak: *u.choose(&[
+                TiebreakMode::Utf8Bytes,
+                TiebreakMode::Scalar,
+                TiebreakMode::Grapheme,
+                TiebreakMode::None,
+            ])?,
+            compress_secondaries: u.arbitrary()?,
+            ignore_trailing_variable: u.arbitrary()?,
+            quaternary: *u.choose(&[
+                QuaternarySource::Shifted,
+                QuaternarySource::CodePoints,
+                QuaternarySource::None,
+            ])?,
+            reorder: u.choose(REORDERS)?,
+            first_letter_case: u.arbitrary()?,
+            trim_prefix: u.arbitrary()?,
+            case_fold: u.arbitrary()?,
+            normalization: *u.choose(&[NormalizationForm::Nfd, NormalizationForm::Nfkd])?,
+            ignore_default_ignorables: u.arbitrary()?,
+            unify_whitespace: u.arbitrary()?,
+            ..Default::default()
+        };
+
+        // The rest is two strings separated by the first newline
+        let rest = std::str::from_utf8(u.bytes(u.len())?)
+            .map_err(|_| arbitrary::Error::IncorrectFormat)?;
+        let (a, b) = rest
+            .split_once('\n')
+            .ok_or(arbitrary::Error::IncorrectFormat)?;
+
+        Ok(Self { opt, a, b })
+    }
+}
+
+// The direct comparison of collation element arrays, the comparison of sort keys, and
+// collate_no_tiebreak (which may skip a shared prefix) must all agree. collate must too, unless
+// they tie, and it must be antisymmetric whatever the tiebreak
+fuzz_target!(|input: Input| {
+    let Input { opt, a, b } = input;
+
+    let via_keys = sort_key(a, opt).cmp(&sort_key(b, opt));
+
+    let direct = compare_ceas(
+        &collation_elements(a, opt),
+        &collation_elements(b, opt),
+        opt,
+    );
+
+    // A code point level isn't part of the collation elements, so the direct comparison is
+    // checked against keys without one
+    let without_code_points = CollationOptions {
+        quaternary: match opt.quaternary {
+            QuaternarySource::CodePoints => QuaternarySource::None,
+            other => other,
+        },
+        ..opt
+    };
+    let via_element_keys = sort_key(a, without_code_points).cmp(&sort_key(b, without_code_points));
+
+    assert_eq!(direct, via_element_keys, "{a:?} vs. {b:?} with {opt:?}");
+    assert_eq!(collate_no_tiebreak(a, b, opt), via_keys);
+
+    let collated = collate(a, b, opt);
+
+    if via_keys != Ordering::Equal {
+        assert_eq!(collated, via_keys, "{a:?} vs. {b:?} with {opt:?}");
+    }
+
+    assert_eq!(collate(b, a, opt), collated.reverse());
+});
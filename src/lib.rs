@@ -1,17 +1,24 @@
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::ops::{Range, RangeInclusive};
 
 use once_cell::sync::{Lazy, OnceCell};
 use regex::Regex;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tinyvec::{array_vec, ArrayVec};
 use unicode_canonical_combining_class::get_canonical_combining_class as get_ccc;
+use unicode_normalization::char::{decompose_canonical, decompose_compatible};
 use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 
 //
 // Structs etc.
 //
 
+// The derived Ord compares the variable flag first, then the weights. That's fine for keeping
+// weights in a map or a sorted list, but it isn't collation order; for that, use collation_cmp
 #[derive(
     Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize, Serialize,
 )]
@@ -26,831 +33,8288 @@ impl Weights {
     fn new() -> Self {
         Default::default()
     }
+
+    // The weights in one integer: the tertiary in the low 16 bits, then the secondary and primary,
+    // with the variable flag in bit 48. Packed values order the same way as the weights themselves
+    pub fn pack(&self) -> u64 {
+        (u64::from(self.variable) << 48)
+            | (u64::from(self.primary) << 32)
+            | (u64::from(self.secondary) << 16)
+            | u64::from(self.tertiary)
+    }
+
+    // How the collator orders these weights as a single element (i.e., a string with only this
+    // collation element): by primary, secondary, and tertiary weight, with variable weights
+    // shifted to the fourth level when shifting
+    pub fn collation_cmp(&self, other: &Self, shifting: bool) -> Ordering {
+        let levels = |w: &Self| {
+            if shifting {
+                get_weights_shifting(w, false)
+            } else {
+                array_vec!([u16; 4] => w.primary, w.secondary, w.tertiary)
+            }
+        };
+
+        levels(self).cmp(&levels(other))
+    }
+
+    // The reverse of pack; bits above 48 are ignored
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn unpack(packed: u64) -> Self {
+        Self {
+            variable: packed & (1 << 48) != 0,
+            primary: (packed >> 32) as u16,
+            secondary: (packed >> 16) as u16,
+            tertiary: packed as u16,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub struct CollationElement {
+    pub primary: u16,
+    pub secondary: u16,
+    pub tertiary: u16,
+    pub quaternary: u16,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
-pub struct CollationOptions {
+pub struct CollationOptions<'a> {
     pub keys_source: KeysSource,
     pub shifting: bool,
+    pub strength: CollationStrength,
+    // Apply full case folding before anything else, so that, e.g., "STRASSE" matches "straße"
+    // and "ΟΔΟΣ" matches "οδος". This is separate from the tertiary case distinctions
+    pub case_fold: bool,
+    // Base letters whose primary weights should follow this order (e.g., "iyjk..." to put y
+    // right after i). The listed letters' primaries are redistributed among themselves; nothing
+    // else moves. Only letters with a single collation element are affected, and anything built
+    // on them (case variants, accented forms) follows along. Contractions are left alone
+    pub primary_order: &'a [char],
+    // Give variation selectors (U+FE00..U+FE0F and U+E0100..U+E01EF) a tertiary weight, in order,
+    // rather than ignoring them completely as DUCET and CLDR do. Then, e.g., an ideograph with a
+    // selector sorts right after the bare ideograph, instead of tying with it
+    pub variation_selectors: bool,
+    pub normalization: NormalizationForm,
+    // Treat code points above this (after normalization) as completely ignorable; e.g., 0xFFFF to
+    // consider only the BMP. Strings that differ only in such code points (emoji, rare ideographs,
+    // etc.) then tie, apart from the tiebreaker in collate
+    pub ignore_above: Option<u32>,
+    // Run-length encode common secondary weights in sort keys, which mostly consist of them. The
+    // keys are shorter, and order the same way, but the secondary weights are no longer the ones
+    // in the table. This has no effect on direct comparison
+    pub compress_secondaries: bool,
+    // Skip the collation elements of a shared prefix when comparing, where that's known to be
+    // safe. Turning this off is only useful for ruling it out when debugging
+    pub trim_prefix: bool,
+    // Weights to use for particular code points (after normalization) in place of the table's;
+    // e.g., to make "&" sort like "and". An overridden code point no longer starts contractions,
+    // but can still be part of one that starts with something else. The input is always fully
+    // decomposed, so an override for a mark applies in precomposed letters too
+    pub weight_overrides: Option<&'a BTreeMap<u32, Vec<Weights>>>,
+    // Script groups to move ahead of all other scripts, in this order, as with CLDR's [reorder]
+    // (e.g., Kana, Latin, Han). Groups not listed follow, in their usual order. The special
+    // groups (space, punctuation, symbol, currency, digit) can be listed too; those that aren't
+    // stay first, in that order
+    pub reorder: &'a [ReorderGroup],
+    // Drop a trailing run of variable collation elements (punctuation, etc.), along with anything
+    // ignorable after them, so that "hello!" ties with "hello". Unlike shifting, this leaves
+    // variable elements anywhere else in the string fully significant: "a-b" still differs
+    // from "ab" at the primary level when not shifting
+    pub ignore_trailing_variable: bool,
+    pub quaternary: QuaternarySource,
+    // Treat default-ignorable code points (soft hyphen, zero-width space and joiners, bidi
+    // controls, Hangul fillers, variation selectors, tags, etc.) as completely ignorable, even
+    // where the table gives them weights. The built-in tables already ignore most of them, but
+    // not, e.g., the Hangul fillers or the unassigned ones. This takes precedence over
+    // variation_selectors, but not over weight_overrides
+    pub ignore_default_ignorables: bool,
+    // Compare secondary weights from the end of the string, as in traditional French: "cote",
+    // "côte", "coté", "côté", where the last accent counts the most
+    pub backwards_secondary: bool,
+    // The same for tertiary weights, independently of backwards_secondary. No natural language
+    // needs it, but some technical collations do (and ICU can reverse any level)
+    pub backwards_tertiary: bool,
+    // Weigh every whitespace code point (tab, newline, no-break space, ideographic space, etc.)
+    // as a plain space, so that, e.g., "a\tb" and "a b" tie even at the quaternary level under
+    // shifting. Weight overrides still apply to the original code point
+    pub unify_whitespace: bool,
+    // A niche ordering for catalogs of product names and the like: the case of the first letter
+    // decides at the tertiary level, uppercase first ("IPad" before "iPad"), and any other case
+    // difference is ignored ("iPad" ties with "ipad", apart from the tiebreaker in collate)
+    pub first_letter_case: bool,
+    // Consulted for code points that aren't in the table (after normalization), before falling
+    // back to the usual implicit weights; e.g., to give private-use characters an order of the
+    // app's choosing. Returning None keeps the implicit weights
+    pub implicit_override: Option<ImplicitOverride<'a>>,
+    // Code points to treat as completely ignorable, whatever the table says; e.g., Arabic harakat
+    // or Hebrew niqqud, where other marks should still count. Unlike primary strength, this leaves
+    // the rest of the accents and case significant. The input is always fully decomposed, so a
+    // mark is ignored in precomposed letters too. As with ignore_default_ignorables,
+    // weight_overrides takes precedence
+    pub ignore_marks: Option<&'a BTreeSet<u32>>,
+    // Move one group's primaries up or down by a fixed amount, as a lighter alternative to
+    // reorder; e.g., to put digits after all the letters with a table entry. The offset is
+    // clamped, and ignored if the group would collide with anything else: see
+    // CollationTable::shifted_range. This is applied before reorder
+    pub primary_offset: Option<(ReorderGroup, i32)>,
+    // How collate and the like break a tie between strings that aren't identical
+    pub tiebreak: TiebreakMode,
+    // Words to skip at the start of a string (case-insensitively), as in a library catalog: with
+    // "the", "a" and "an", "The Who" sorts as "Who". An article must be followed by whitespace,
+    // unless it ends with an apostrophe (e.g., French "l'"), and a string that's nothing but an
+    // article is left as it is. This applies to str input; collate_utf16 and sort_key_from_iter
+    // don't strip anything
+    pub ignore_leading_articles: &'a [&'a str],
+    // Advanced: secondary weights to replace with the common one, for looser accent matching
+    // between primary and secondary strength. E.g., with the weights of the acute and grave
+    // accents, "é" ties with "è", but still not with "e", since the mark leaves a secondary weight
+    // of its own (to drop it entirely, use ignore_marks); and "ç" still differs from both. The
+    // weights depend on the table; see collation_elements
+    pub fold_secondaries: Option<&'a BTreeSet<u16>>,
+    // Which elements count as variable, in place of the table's flags; None keeps those. For CLDR
+    // that's the same as Punct (ICU's default), while DUCET also makes most symbols variable
+    pub max_variable: Option<MaxVariable>,
+    // For historical texts: weigh the historic letters and abbreviations that the tables keep
+    // apart (see HISTORIC_FOLDS) as their modern equivalents, at the primary and secondary
+    // levels; e.g., "ẜ" as "s", "ꝫ" as "et". They still differ at the tertiary level, as variant
+    // letters do. The tables already fold long s, r rotunda, insular letters, and ligatures like
+    // "ﬅ" and "ꜳ", so "ſong" ties with "song" at the primary level either way. Yogh and vend are
+    // left alone, having no single modern equivalent. Weight overrides take precedence
+    pub historic_folding: bool,
 }
 
-impl Default for CollationOptions {
+impl Default for CollationOptions<'_> {
     fn default() -> Self {
         Self {
             keys_source: KeysSource::Cldr,
             shifting: true,
+            strength: CollationStrength::Quaternary,
+            case_fold: false,
+            primary_order: &[],
+            variation_selectors: false,
+            normalization: NormalizationForm::Nfd,
+            ignore_above: None,
+            compress_secondaries: false,
+            trim_prefix: true,
+            weight_overrides: None,
+            reorder: &[],
+            ignore_trailing_variable: false,
+            quaternary: QuaternarySource::Shifted,
+            ignore_default_ignorables: false,
+            backwards_secondary: false,
+            backwards_tertiary: false,
+            unify_whitespace: false,
+            first_letter_case: false,
+            implicit_override: None,
+            ignore_marks: None,
+            primary_offset: None,
+            tiebreak: TiebreakMode::Utf8Bytes,
+            ignore_leading_articles: &[],
+            fold_secondaries: None,
+            max_variable: None,
+            historic_folding: false,
         }
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash, Debug)]
-pub enum KeysSource {
-    Cldr,
-    Ducet,
-}
+impl CollationOptions<'_> {
+    // Checks for options that can't do anything together: one that only affects a level that the
+    // strength leaves out (e.g., backwards_secondary at primary strength), or that needs another
+    // that's off. Collation itself doesn't check, and just goes ahead without the useless option
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        let needs_strength = [
+            (
+                "backwards_secondary",
+                self.backwards_secondary,
+                CollationStrength::Secondary,
+            ),
+            (
+                "compress_secondaries",
+                self.compress_secondaries,
+                CollationStrength::Secondary,
+            ),
+            (
+                "fold_secondaries",
+                self.fold_secondaries.is_some(),
+                CollationStrength::Secondary,
+            ),
+            (
+                "backwards_tertiary",
+                self.backwards_tertiary,
+                CollationStrength::Tertiary,
+            ),
+            (
+                "first_letter_case",
+                self.first_letter_case,
+                CollationStrength::Tertiary,
+            ),
+            (
+                "variation_selectors",
+                self.variation_selectors,
+                CollationStrength::Tertiary,
+            ),
+            (
+                "quaternary: CodePoints",
+                self.quaternary == QuaternarySource::CodePoints,
+                CollationStrength::Quaternary,
+            ),
+        ];
 
-//
-// Static/const
-//
+        for (option, set, needs) in needs_strength {
+            if set && self.strength < needs {
+                return Err(OptionsError::StrengthTooLow { option, needs });
+            }
+        }
 
-static FCD: Lazy<HashMap<u32, u16>> = Lazy::new(|| {
-    let data = include_bytes!("bincode/fcd");
-    let decoded: HashMap<u32, u16> = bincode::deserialize(data).unwrap();
-    decoded
-});
+        if self.max_variable.is_some() && !self.shifting {
+            return Err(OptionsError::Conflict("max_variable", "shifting: false"));
+        }
 
-static LOW: Lazy<HashMap<u32, Weights>> = Lazy::new(|| {
-    let data = include_bytes!("bincode/low");
-    let decoded: HashMap<u32, Weights> = bincode::deserialize(data).unwrap();
-    decoded
-});
+        // Every letter is lowercase after folding, so there's no case left to look at
+        if self.case_fold && self.first_letter_case {
+            return Err(OptionsError::Conflict("first_letter_case", "case_fold"));
+        }
 
-static SING: Lazy<HashMap<u32, Vec<Weights>>> = Lazy::new(|| {
-    let data = include_bytes!("bincode/singles");
-    let decoded: HashMap<u32, Vec<Weights>> = bincode::deserialize(data).unwrap();
-    decoded
-});
+        Ok(())
+    }
+}
 
-static MULT: Lazy<HashMap<ArrayVec<[u32; 3]>, Vec<Weights>>> = Lazy::new(|| {
-    let data = include_bytes!("bincode/multis");
-    let decoded: HashMap<ArrayVec<[u32; 3]>, Vec<Weights>> = bincode::deserialize(data).unwrap();
-    decoded
-});
+// The weights that get_cea draws on. The built-in tables (DUCET and CLDR root) are loaded once, on
+// first use; CollationTable::new gives a copy of either, which can then be tailored
+#[derive(Clone, Debug)]
+pub struct CollationTable {
+    // Which table this was built from; CLDR allows a wider range of discontiguous matches
+    source: KeysSource,
+    // Indexed by code point, for the fast path in get_cea (no hashing)
+    low: [Option<Weights>; LOW_END as usize],
+    singles: TableMap<u32, Vec<Weights>>,
+    multis: TableMap<ArrayVec<[u32; 3]>, Vec<Weights>>,
+    // Length of the longest contraction starting with a given code point (if there is one)
+    lookahead: TableMap<u32, usize>,
+    // The same as multis, for finding the longest contraction that the input continues with
+    contractions: ContractionTrie,
+    // Mappings that apply only after a given prefix, by the code point they're for; longest
+    // prefix first. The built-in tables have none
+    contexts: TableMap<u32, Vec<ContextMapping>>,
+    // Lowest and highest primary weights of variable elements
+    variable_range: (u16, u16),
+    // The primaries spanned by each reorder group, worked out on first use
+    reorder_ranges: OnceCell<Vec<Option<RangeInclusive<u16>>>>,
+    // Every primary weight in the table, sorted, for checking a primary offset; also on first use
+    used_primaries: OnceCell<Vec<u16>>,
+}
 
-static LOW_CLDR: Lazy<HashMap<u32, Weights>> = Lazy::new(|| {
-    let data = include_bytes!("bincode/low_cldr");
-    let decoded: HashMap<u32, Weights> = bincode::deserialize(data).unwrap();
-    decoded
-});
+// The hasher for the maps behind the tables, whose keys are code points (or a few of them), or
+// weights. They come from the Unicode data (or a tailoring), not from the input being collated,
+// so there's nothing to gain from a DoS-resistant hasher like the default one, which is much
+// slower for such small keys. With the fast-hash feature (on by default), it's FxHash
+#[cfg(feature = "fast-hash")]
+type TableHasher = rustc_hash::FxBuildHasher;
+#[cfg(not(feature = "fast-hash"))]
+type TableHasher = std::collections::hash_map::RandomState;
+
+type TableMap<K, V> = HashMap<K, V, TableHasher>;
+
+// A prefix, and the weights that apply after it
+type ContextMapping = (Vec<u32>, Vec<Weights>);
+
+// One collation element from each of two strings (see diff_elements)
+pub type ElementPair = (ArrayVec<[u16; 4]>, ArrayVec<[u16; 4]>);
+
+// Contractions, one level per code point, so that a search can go a code point at a time and stop
+// as soon as no longer one could match, instead of hashing every slice from the longest possible
+// down. Each key's weights are copied from multis, so finding them takes no hashing past the first
+// code point
+#[derive(Clone, Default, Debug)]
+struct ContractionTrie {
+    // The node for each starter
+    roots: TableMap<u32, u32>,
+    nodes: Vec<TrieNode>,
+}
 
-static SING_CLDR: Lazy<HashMap<u32, Vec<Weights>>> = Lazy::new(|| {
-    let data = include_bytes!("bincode/singles_cldr");
-    let decoded: HashMap<u32, Vec<Weights>> = bincode::deserialize(data).unwrap();
-    decoded
-});
+#[derive(Clone, Default, Debug)]
+struct TrieNode {
+    // By code point, for a binary search; most nodes have only a few
+    children: Vec<(u32, u32)>,
+    // The weights, if the code points leading here are a key
+    row: Option<Vec<Weights>>,
+}
 
-static MULT_CLDR: Lazy<HashMap<ArrayVec<[u32; 3]>, Vec<Weights>>> = Lazy::new(|| {
-    let data = include_bytes!("bincode/multis_cldr");
-    let decoded: HashMap<ArrayVec<[u32; 3]>, Vec<Weights>> = bincode::deserialize(data).unwrap();
-    decoded
-});
+impl ContractionTrie {
+    #[allow(clippy::cast_possible_truncation)]
+    fn insert(&mut self, key: &[u32], row: &[Weights]) {
+        let Some((first, rest)) = key.split_first() else {
+            return;
+        };
 
-const NEED_THREE: [u32; 4] = [3_270, 3_545, 4_018, 4_019];
+        let next_index = self.nodes.len() as u32;
+        let mut node = *self.roots.entry(*first).or_insert(next_index);
 
-const NEED_TWO: [u32; 59] = [
-    76, 108, 1_048, 1_080, 1_575, 1_608, 1_610, 2_503, 2_887, 2_962, 3_014, 3_015, 3_142, 3_263,
-    3_274, 3_398, 3_399, 3_548, 3_648, 3_649, 3_650, 3_651, 3_652, 3_661, 3_776, 3_777, 3_778,
-    3_779, 3_780, 3_789, 3_953, 4_133, 6_581, 6_582, 6_583, 6_586, 6_917, 6_919, 6_921, 6_923,
-    6_925, 6_929, 6_970, 6_972, 6_974, 6_975, 6_978, 43_701, 43_702, 43_705, 43_707, 43_708,
-    69_937, 69_938, 70_471, 70_841, 71_096, 71_097, 71_989,
-];
+        if node == next_index {
+            self.nodes.push(TrieNode::default());
+        }
 
-const INCLUDED_UNASSIGNED: [u32; 4] = [177_977, 178_206, 183_970, 191_457];
+        for cp in rest {
+            let next_index = self.nodes.len() as u32;
+            let children = &mut self.nodes[node as usize].children;
 
-//
-// Macros
-//
+            node = match children.binary_search_by_key(cp, |(c, _)| *c) {
+                Ok(i) => children[i].1,
+                Err(i) => {
+                    children.insert(i, (*cp, next_index));
+                    self.nodes.push(TrieNode::default());
+                    next_index
+                }
+            };
+        }
 
-macro_rules! regex {
-    ($re:literal $(,)?) => {{
-        static RE: OnceCell<Regex> = OnceCell::new();
-        RE.get_or_init(|| Regex::new($re).unwrap())
-    }};
-}
+        self.nodes[node as usize].row = Some(row.to_vec());
+    }
 
-//
-// Functions, public
-//
+    fn root(&self, code_point: u32) -> Option<u32> {
+        self.roots.get(&code_point).copied()
+    }
 
-pub fn collate(str_a: &str, str_b: &str, opt: CollationOptions) -> Ordering {
-    // Early out
-    if str_a == str_b {
-        return Ordering::Equal;
+    fn child(&self, node: u32, code_point: u32) -> Option<u32> {
+        let children = &self.nodes[node as usize].children;
+
+        children
+            .binary_search_by_key(&code_point, |(c, _)| *c)
+            .ok()
+            .map(|i| children[i].1)
     }
 
-    // Get NFD if necessary (i.e., if not FCD)
-    let mut a_nfd = get_nfd(str_a);
-    let mut b_nfd = get_nfd(str_b);
+    // The weights of the key made of the root's starter and the rest
+    fn get(&self, root: u32, rest: &[u32]) -> Option<&[Weights]> {
+        let mut node = root;
 
-    // Slightly less early out
-    if a_nfd == b_nfd {
-        // Tiebreaker
-        return str_a.cmp(str_b);
+        for cp in rest {
+            node = self.child(node, *cp)?;
+        }
+
+        self.nodes[node as usize].row.as_deref()
     }
 
-    // Trim shared prefix if possible
-    let cldr = opt.keys_source == KeysSource::Cldr;
-    trim_prefix(&mut a_nfd, &mut b_nfd, cldr);
+    // The longest key that starts with the root's starter and continues with the input, as its
+    // length (at least two code points, counting the starter) and weights
+    fn longest_match(&self, root: u32, rest: &[u32]) -> Option<(usize, &[Weights])> {
+        let mut node = root;
+        let mut longest = None;
 
-    // Generate sort keys... this is where things get expensive
-    let a_sk = nfd_to_sk(&mut a_nfd, opt);
-    let b_sk = nfd_to_sk(&mut b_nfd, opt);
+        for (i, cp) in rest.iter().enumerate() {
+            let Some(next) = self.child(node, *cp) else {
+                break;
+            };
 
-    let comparison = a_sk.cmp(&b_sk);
+            node = next;
 
-    if comparison == Ordering::Equal {
-        // Tiebreaker
-        return str_a.cmp(str_b);
-    }
+            if let Some(row) = &self.nodes[node as usize].row {
+                longest = Some((i + 2, row.as_slice()));
+            }
+        }
 
-    comparison
+        longest
+    }
 }
 
-pub fn collate_no_tiebreak(str_a: &str, str_b: &str, opt: CollationOptions) -> Ordering {
-    // Early out
-    if str_a == str_b {
-        return Ordering::Equal;
+impl CollationTable {
+    pub fn new(keys_source: KeysSource) -> Self {
+        builtin_table(keys_source).clone()
     }
 
-    // Get NFD if necessary (i.e., if not FCD)
-    let mut a_nfd = get_nfd(str_a);
-    let mut b_nfd = get_nfd(str_b);
+    // Adds a mapping (or replaces an existing one) from one to three code points to the given
+    // weights. The input is normalized to NFD before lookup, so that's the form to use here.
+    // Contractions are matched as in the built-in tables, including discontiguously
+    pub fn add_mapping(&mut self, key: &str, weights: &[Weights]) {
+        let len = key.chars().count();
+        assert!(
+            (1..=3).contains(&len),
+            "mapping key must be 1-3 code points: {key:?}"
+        );
+
+        let key: ArrayVec<[u32; 3]> = key.chars().map(|c| c as u32).collect();
+        let first = key[0];
+
+        self.reorder_ranges = OnceCell::new();
+        self.used_primaries = OnceCell::new();
+        self.extend_variable_range(weights);
+
+        if len == 1 {
+            self.singles.insert(first, weights.to_vec());
+
+            // The low table only holds code points with a single collation element
+            match weights {
+                [w] if first < LOW_END && !self.lookahead.contains_key(&first) => {
+                    self.low[first as usize] = Some(*w);
+                }
+                _ => {
+                    self.set_low(first, None);
+                }
+            }
 
-    // Slightly less early out (but no tiebreaker)
-    if a_nfd == b_nfd {
-        return Ordering::Equal;
-    }
+            return;
+        }
 
-    // Trim shared prefix if possible
-    let cldr = opt.keys_source == KeysSource::Cldr;
-    trim_prefix(&mut a_nfd, &mut b_nfd, cldr);
+        let lookahead = self.lookahead.entry(first).or_insert(1);
+        *lookahead = (*lookahead).max(key.len());
 
-    // Generate sort keys... this is where things get expensive
-    let a_sk = nfd_to_sk(&mut a_nfd, opt);
-    let b_sk = nfd_to_sk(&mut b_nfd, opt);
+        // A contraction starter has to skip the fast path
+        self.set_low(first, None);
 
-    a_sk.cmp(&b_sk)
-}
+        self.contractions.insert(&key, weights);
+        self.multis.insert(key, weights.to_vec());
+    }
 
-//
-// Functions, private
-//
+    // Adds a mapping for a single code point that applies only when it comes right after the given
+    // prefix, as with "prefix | key" in CLDR tailoring rules (e.g., for the Japanese prolonged
+    // sound mark, which takes the vowel of the kana before it). Both are in NFD. Where prefixes
+    // overlap, the longest that matches wins. Prefix mappings take precedence over contractions
+    // starting with the same code point, but not over weight overrides
+    pub fn add_context_mapping(&mut self, prefix: &str, key: &str, weights: &[Weights]) {
+        let mut chars = key.chars();
+        let (Some(first), None) = (chars.next(), chars.next()) else {
+            panic!("context mapping key must be one code point: {key:?}");
+        };
+        assert!(
+            !prefix.is_empty(),
+            "context mapping prefix must not be empty"
+        );
 
-fn get_nfd(input: &str) -> Vec<u32> {
-    if fcd(input) {
-        input.chars().map(|c| c as u32).collect()
-    } else {
-        UnicodeNormalization::nfd(input).map(|c| c as u32).collect()
-    }
-}
+        let prefix: Vec<u32> = prefix.chars().map(|c| c as u32).collect();
 
-fn fcd(input: &str) -> bool {
-    let mut c_as_u32: u32;
-    let mut curr_lead_cc: u8;
-    let mut curr_trail_cc: u8;
+        self.reorder_ranges = OnceCell::new();
+        self.used_primaries = OnceCell::new();
+        self.extend_variable_range(weights);
 
-    let mut prev_trail_cc: u8 = 0;
+        // The fast path doesn't look behind
+        self.set_low(first as u32, None);
 
-    for c in input.chars() {
-        c_as_u32 = c as u32;
+        let rows = self.contexts.entry(first as u32).or_default();
+        rows.retain(|(p, _)| *p != prefix);
+        rows.push((prefix, weights.to_vec()));
+        rows.sort_by_key(|(p, _)| std::cmp::Reverse(p.len()));
+    }
 
-        if c_as_u32 < 192 {
-            prev_trail_cc = 0;
-            continue;
+    // The range only grows, even if a variable mapping is replaced
+    fn extend_variable_range(&mut self, weights: &[Weights]) {
+        for w in weights.iter().filter(|w| w.variable) {
+            self.variable_range = match self.variable_range {
+                (0, 0) => (w.primary, w.primary),
+                (lo, hi) => (lo.min(w.primary), hi.max(w.primary)),
+            };
         }
+    }
 
-        if c_as_u32 == 3_969 || (44_032..=55_215).contains(&c_as_u32) {
-            return false;
-        }
+    // A copy of a built-in table with only the code points in the given ranges (e.g., Unicode
+    // blocks), to save memory. Anything else gets implicit weights, as if it were unassigned, so
+    // it sorts after everything in the table, by code point. Results for text outside the ranges
+    // will differ from the full table's. Note that input that isn't FCD is fully decomposed, so
+    // the blocks for combining marks (e.g., 0300..=036F) are needed for accented letters
+    pub fn filtered(keys_source: KeysSource, ranges: &[RangeInclusive<u32>]) -> Self {
+        let full = builtin_table(keys_source);
+        let keep = |cp: &u32| ranges.iter().any(|r| r.contains(cp));
+
+        let mut table = Self {
+            source: full.source,
+            low: [None; LOW_END as usize],
+            singles: TableMap::default(),
+            multis: TableMap::default(),
+            lookahead: TableMap::default(),
+            contractions: ContractionTrie::default(),
+            contexts: TableMap::default(),
+            variable_range: full.variable_range,
+            reorder_ranges: OnceCell::new(),
+            used_primaries: OnceCell::new(),
+        };
 
-        if let Some(vals) = FCD.get(&c_as_u32) {
-            [curr_lead_cc, curr_trail_cc] = vals.to_be_bytes();
-        } else {
-            curr_lead_cc = get_ccc(c) as u8;
-            curr_trail_cc = curr_lead_cc;
+        for (cp, weights) in (0..LOW_END).zip(full.low) {
+            if keep(&cp) {
+                table.low[cp as usize] = weights;
+            }
         }
 
-        if curr_lead_cc != 0 && curr_lead_cc < prev_trail_cc {
-            return false;
+        for (cp, row) in &full.singles {
+            if keep(cp) {
+                table.singles.insert(*cp, row.clone());
+            }
         }
 
-        prev_trail_cc = curr_trail_cc;
-    }
+        for (key, row) in &full.multis {
+            if key.iter().all(keep) {
+                let lookahead = table.lookahead.entry(key[0]).or_insert(1);
+                *lookahead = (*lookahead).max(key.len());
 
-    true
-}
+                table.contractions.insert(key, row);
+                table.multis.insert(*key, row.clone());
+            }
+        }
 
-fn trim_prefix(a: &mut Vec<u32>, b: &mut Vec<u32>, cldr: bool) {
-    let prefix_len = find_prefix(a, b);
+        table.variable_range =
+            find_variable_range(table.singles.values().chain(table.multis.values()));
 
-    if prefix_len > 0 {
-        let sing = if cldr { &SING_CLDR } else { &SING };
+        table
+    }
 
-        // Test final code point in prefix; bail if bad
-        if let Some(row) = sing.get(&a[prefix_len - 1]) {
-            for weights in row {
-                if weights.variable || weights.primary == 0 {
-                    return;
-                }
-            }
+    // Anything in the low table is known not to, which saves hashing in the common case
+    fn starts_contraction(&self, code_point: u32) -> bool {
+        match self.low.get(code_point as usize) {
+            Some(Some(_)) => false,
+            _ => self.lookahead.contains_key(&code_point),
         }
-
-        a.drain(0..prefix_len);
-        b.drain(0..prefix_len);
     }
-}
 
-fn find_prefix(a: &[u32], b: &[u32]) -> usize {
-    a.iter()
-        .zip(b)
-        .take_while(|(x, y)| x == y && !NEED_THREE.contains(x) && !NEED_TWO.contains(x))
-        .count()
-}
+    fn set_low(&mut self, code_point: u32, weights: Option<Weights>) {
+        if let Some(slot) = self.low.get_mut(code_point as usize) {
+            *slot = weights;
+        }
+    }
 
-fn nfd_to_sk(nfd: &mut Vec<u32>, opt: CollationOptions) -> Vec<u16> {
-    let collation_element_array = get_cea(nfd, opt);
-    get_sort_key(&collation_element_array, opt.shifting)
-}
+    fn load(source: KeysSource, [low_data, singles, multis]: [&[u8]; 3]) -> Self {
+        #[cfg(test)]
+        TABLE_LOADS[source as usize].fetch_add(1, std::sync::atomic::Ordering::SeqCst);
 
-fn get_sort_key(collation_element_array: &[ArrayVec<[u16; 4]>], shifting: bool) -> Vec<u16> {
-    let max_level = if shifting { 4 } else { 3 };
-    let mut sort_key = Vec::new();
+        let low_map: HashMap<u32, Weights> = deserialize_table(low_data);
 
-    for i in 0..max_level {
-        if i > 0 {
-            sort_key.push(0);
+        let mut low = [None; LOW_END as usize];
+        for (cp, weights) in low_map {
+            low[cp as usize] = Some(weights);
         }
+        let singles: TableMap<u32, Vec<Weights>> = deserialize_table(singles);
+        let multis: TableMap<ArrayVec<[u32; 3]>, Vec<Weights>> = deserialize_table(multis);
 
-        for elem in collation_element_array {
-            if elem[i] != 0 {
-                sort_key.push(elem[i]);
-            }
+        let mut lookahead: TableMap<u32, usize> = TableMap::default();
+        let mut contractions = ContractionTrie::default();
+
+        for (key, row) in &multis {
+            let entry = lookahead.entry(key[0]).or_insert(1);
+            *entry = (*entry).max(key.len());
+
+            contractions.insert(key, row);
         }
-    }
 
-    sort_key
-}
+        let variable_range = find_variable_range(singles.values().chain(multis.values()));
 
-fn get_cea(char_vals: &mut Vec<u32>, opt: CollationOptions) -> Vec<ArrayVec<[u16; 4]>> {
-    let mut cea: Vec<ArrayVec<[u16; 4]>> = Vec::new();
+        Self {
+            source,
+            low,
+            singles,
+            multis,
+            lookahead,
+            contractions,
+            contexts: TableMap::default(),
+            variable_range,
+            reorder_ranges: OnceCell::new(),
+            used_primaries: OnceCell::new(),
+        }
+    }
 
-    let cldr = opt.keys_source == KeysSource::Cldr;
-    let shifting = opt.shifting;
+    // A hash of the table's mappings (FNV-1a, over them in sorted order), the same in every
+    // process and on every platform for the same data, and changed by any tailoring that changes
+    // a mapping. It's meant for spotting which table is in use, e.g., to invalidate stored sort
+    // keys; it isn't cryptographic
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+
+        let mut write = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= u64::from(*byte);
+                hash = hash.wrapping_mul(0x100_0000_01B3);
+            }
+        };
 
-    let low = if cldr { &LOW_CLDR } else { &LOW };
-    let singles = if cldr { &SING_CLDR } else { &SING };
-    let multis = if cldr { &MULT_CLDR } else { &MULT };
+        let mut write_row = |key: &[u32], row: &[Weights]| {
+            // Lengths first, so that the boundaries between rows are unambiguous
+            for len in [key.len(), row.len()] {
+                write(&(len as u64).to_be_bytes());
+            }
 
-    let mut left: usize = 0;
-    let mut last_variable = false;
+            for cp in key {
+                write(&cp.to_be_bytes());
+            }
 
-    'outer: while left < char_vals.len() {
-        let left_val = char_vals[left];
+            for weights in row {
+                write(&weights.pack().to_be_bytes());
+            }
+        };
 
-        if left_val < 183 && left_val != 108 && left_val != 76 {
-            let weights = low.get(&left_val).unwrap();
+        write_row(&[self.source as u32], &[]);
 
-            if shifting {
-                let weight_values = get_weights_shifting(weights, last_variable);
-                cea.push(weight_values);
-                if weights.variable {
-                    last_variable = true;
-                } else if weights.primary != 0 {
-                    last_variable = false;
-                }
-            } else {
-                let weight_values = array_vec!(
-                    [u16; 4] => weights.primary, weights.secondary, weights.tertiary
-                );
-                cea.push(weight_values);
+        for (cp, weights) in (0..LOW_END).zip(&self.low) {
+            if let Some(weights) = weights {
+                write_row(&[cp], &[*weights]);
             }
+        }
 
-            left += 1;
-            continue;
+        let mut singles: Vec<_> = self.singles.iter().collect();
+        singles.sort_unstable_by_key(|(cp, _)| **cp);
+
+        for (cp, row) in singles {
+            write_row(&[*cp], row);
         }
 
-        // Set lookahead depending on left_val. We need 3 in a few cases; 2 in several dozen cases;
-        // and 1 otherwise.
-        let lookahead: usize = match left_val {
-            x if NEED_THREE.contains(&x) => 3,
-            x if NEED_TWO.contains(&x) => 2,
-            _ => 1,
-        };
+        let mut multis: Vec<_> = self.multis.iter().collect();
+        multis.sort_unstable_by_key(|(key, _)| *key);
 
-        let check_multi = lookahead > 1 && char_vals.len() - left > 1;
+        for (key, row) in multis {
+            write_row(key, row);
+        }
 
-        // If lookahead is 1, or if this is the last item in the vec, take an easy path
-        if !check_multi {
-            // Did we find it? Sure hope so
-            if let Some(row) = singles.get(&left_val) {
-                // Push weights to collation element array
-                for weights in row {
-                    if shifting {
-                        let weight_values = get_weights_shifting(weights, last_variable);
-                        cea.push(weight_values);
-                        if weights.variable {
-                            last_variable = true;
-                        } else if weights.primary != 0 {
-                            last_variable = false;
-                        }
-                    } else {
-                        let weight_values = array_vec!(
-                            [u16; 4] => weights.primary, weights.secondary, weights.tertiary
-                        );
-                        cea.push(weight_values);
-                    }
-                }
+        let mut contexts: Vec<_> = self.contexts.iter().collect();
+        contexts.sort_unstable_by_key(|(cp, _)| **cp);
 
-                // Increment and continue outer loop
-                left += 1;
-                continue 'outer;
+        for (cp, rows) in contexts {
+            for (prefix, row) in rows {
+                write_row(prefix, &[]);
+                write_row(&[*cp], row);
             }
         }
 
-        // Next consider multis, if applicable
-        // If we just tried to find a single, and didn't find it, we should skip all the way down
-        // to the implicit weights section
-
-        // But don't look past end of the vec
-        let mut right = if left + lookahead > char_vals.len() {
-            char_vals.len()
-        } else {
-            left + lookahead
-        };
-
-        'middle: while check_multi && right > left {
-            // If right - left == 1 (which cannot have been the case in the first iteration),
-            // attempts to find a slice have failed. So look for one code point, in the singles map
-            if right - left == 1 {
-                // If we found it, we do still need to check for discontiguous matches
-                if let Some(value) = singles.get(&left_val) {
-                    // Determine how much further right to look
-                    let mut max_right = if right + 2 < char_vals.len() {
-                        right + 2
-                    } else if right + 1 < char_vals.len() {
-                        right + 1
-                    } else {
-                        // This should skip the loop below. There will be no discontiguous match
-                        right
-                    };
+        hash
+    }
 
-                    let mut try_two = max_right - right == 2 && cldr;
-
-                    'inner: while max_right > right {
-                        // Make sure the sequence of CCC values is kosher
-                        let interest_cohort = &char_vals[right..=max_right];
-                        let mut max_ccc = 0;
-
-                        for elem in interest_cohort {
-                            let ccc = get_ccc(char::from_u32(*elem).unwrap()) as u8;
-                            if ccc == 0 || ccc <= max_ccc {
-                                // Can also forget about try_two in this case
-                                try_two = false;
-                                max_right -= 1;
-                                continue 'inner;
-                            }
-                            max_ccc = ccc;
-                        }
+    // The lowest and highest primary weights of variable elements in the table. Everything in
+    // between is variable too: spaces and punctuation in CLDR, and most symbols as well in DUCET
+    pub fn variable_range(&self) -> (u16, u16) {
+        self.variable_range
+    }
 
-                        // Having made it this far, we test a new subset, adding the later char(s)
-                        let new_subset = if try_two {
-                            ArrayVec::from([
-                                left_val,
-                                char_vals[max_right - 1],
-                                char_vals[max_right],
-                            ])
-                        } else {
-                            array_vec!([u32; 3] => left_val, char_vals[max_right])
-                        };
-
-                        // If the new subset is found in the table...
-                        if let Some(new_value) = multis.get(&new_subset) {
-                            // Then add these weights instead
-                            for weights in new_value {
-                                if shifting {
-                                    let weight_values =
-                                        get_weights_shifting(weights, last_variable);
-                                    cea.push(weight_values);
-                                    if weights.variable {
-                                        last_variable = true;
-                                    } else if weights.primary != 0 {
-                                        last_variable = false;
-                                    }
-                                } else {
-                                    let weight_values = array_vec!(
-                                        [u16; 4] => weights.primary, weights.secondary, weights.tertiary
-                                    );
-                                    cea.push(weight_values);
-                                }
-                            }
-
-                            // Remove the pulled char(s) (in this order!)
-                            char_vals.remove(max_right);
-                            if try_two {
-                                char_vals.remove(max_right - 1);
-                            }
-
-                            // Increment and continue outer loop
-                            left += right - left;
-                            continue 'outer;
-                        }
+    // Han is the range of implicit weights (lead elements) for ideographs. The special groups
+    // besides Digit are marked out by their first characters (see ReorderGroup). Any other group
+    // spans the primaries of its letters (or digits) with a single collation element, which the
+    // tables keep together. None if the table has no such letters (e.g., a filtered one)
+    fn reorder_range(&self, group: ReorderGroup) -> Option<RangeInclusive<u16>> {
+        if group == ReorderGroup::Han {
+            return Some(0xFB40..=0xFBBF);
+        }
 
-                        // If we tried for two, don't decrement max_right yet
-                        // Inner loop will run again
-                        if try_two {
-                            try_two = false;
-                        } else {
-                            // Otherwise decrement max_right; inner loop may or may not run again
-                            max_right -= 1;
-                        }
-                    }
+        if let Some(i) = SPECIAL_GROUPS.iter().position(|(g, _)| *g == group) {
+            let first_primary = |cp: u32| match self.singles.get(&cp).map(Vec::as_slice) {
+                Some([w]) if w.primary != 0 => Some(w.primary),
+                _ => None,
+            };
 
-                    // At this point, we're not looking for a discontiguous match. We just need to
-                    // push the weights we found above
-
-                    for weights in value {
-                        if shifting {
-                            let weight_values = get_weights_shifting(weights, last_variable);
-                            cea.push(weight_values);
-                            if weights.variable {
-                                last_variable = true;
-                            } else if weights.primary != 0 {
-                                last_variable = false;
-                            }
-                        } else {
-                            let weight_values = array_vec!(
-                                [u16; 4] => weights.primary, weights.secondary, weights.tertiary
-                            );
-                            cea.push(weight_values);
-                        }
-                    }
+            let start = first_primary(SPECIAL_GROUPS[i].1)?;
+            let next = match SPECIAL_GROUPS.get(i + 1) {
+                Some((_, cp)) => first_primary(*cp)?,
+                None => *self.reorder_range(ReorderGroup::Digit)?.start(),
+            };
 
-                    // Increment and continue outer loop
-                    left += right - left;
-                    continue 'outer;
-                }
+            return (start < next).then(|| start..=next - 1);
+        }
 
-                // We failed to find the one code point
-                // This means we need to skip down to deal with implicit weights
-                // If we decrement right and continue middle loop, that should happen
-                right -= 1;
-                continue 'middle;
-            }
+        let ranges = self.reorder_ranges.get_or_init(|| {
+            REORDER_LETTERS
+                .iter()
+                .map(|(_, code_points)| {
+                    let primaries = code_points
+                        .iter()
+                        .flat_map(Clone::clone)
+                        .filter(|cp| {
+                            char::from_u32(*cp)
+                                .is_some_and(|c| c.is_alphabetic() || c.is_ascii_digit())
+                        })
+                        .filter_map(|cp| match self.singles.get(&cp).map(Vec::as_slice) {
+                            Some([w]) if !w.variable && w.primary != 0 => Some(w.primary),
+                            _ => None,
+                        });
+
+                    primaries
+                        .clone()
+                        .min()
+                        .zip(primaries.max())
+                        .map(|(lo, hi)| lo..=hi)
+                })
+                .collect()
+        });
+
+        let index = REORDER_LETTERS.iter().position(|(g, _)| *g == group)?;
+        ranges[index].clone()
+    }
 
-            // If we got here, we're trying to find a slice
-            let subset = &char_vals[left..right];
+    // Where a group's primaries would end up with the primary_offset option, or None if they
+    // can't go there: the offset is clamped to keep them above 0 and below 0x8000 (the top half
+    // is taken by the second elements of implicit weights, and Han's lead elements), and it's
+    // rejected if that lands them on primaries used by anything outside the group. The built-in
+    // tables leave a wide gap after the scripts with a table entry (from 0x5E73), and CLDR leaves
+    // some room below its lowest primaries and between digits and Latin
+    pub fn shifted_range(&self, group: ReorderGroup, offset: i32) -> Option<RangeInclusive<u16>> {
+        let range = self.reorder_range(group)?;
+        let (start, end) = (i32::from(*range.start()), i32::from(*range.end()));
+        let clamped = offset.clamp(1 - start, 0x7FFF - end);
+
+        // Han is above the limit to begin with, so it can only go down
+        if clamped.signum() != offset.signum() {
+            return None;
+        }
 
-            if let Some(row) = multis.get(subset) {
-                // If we found it, we may need to check for discontiguous matches.
-                // But that's only if we matched a set of two code points; and we'll only skip over
-                // one more to find a possible third.
-                let mut try_discont = subset.len() == 2 && right + 1 < char_vals.len();
-
-                'inner: while try_discont {
-                    // Need to make sure the sequence of CCCs is kosher
-                    let ccc_a = get_ccc(char::from_u32(char_vals[right]).unwrap()) as u8;
-                    let ccc_b = get_ccc(char::from_u32(char_vals[right + 1]).unwrap()) as u8;
-
-                    if ccc_a == 0 || ccc_a >= ccc_b {
-                        // Bail -- no discontiguous match
-                        try_discont = false;
-                        continue 'inner;
-                    }
+        let shifted = u16::try_from(start + clamped).ok()?..=u16::try_from(end + clamped).ok()?;
+
+        let used = self.used_primaries.get_or_init(|| {
+            let mut primaries: Vec<u16> = self
+                .low
+                .iter()
+                .flatten()
+                .chain(self.singles.values().flatten())
+                .chain(self.multis.values().flatten())
+                .chain(self.contexts.values().flatten().flat_map(|(_, row)| row))
+                .map(|w| w.primary)
+                .filter(|p| *p != 0)
+                .collect();
+
+            primaries.sort_unstable();
+            primaries.dedup();
+            primaries
+        });
+
+        let first = used.partition_point(|p| p < shifted.start());
+        let collides = used[first..]
+            .iter()
+            .take_while(|p| shifted.contains(p))
+            .any(|p| !range.contains(p));
+
+        (!collides).then_some(shifted)
+    }
+}
 
-                    // Having made it this far, we can test a new subset, adding the later char.
-                    // This only happens when we've found an initial match of two code points and
-                    // want to add a third; so we can be oddly specific.
-                    let new_subset = ArrayVec::from([subset[0], subset[1], char_vals[right + 1]]);
+// Weights level by level, with a 0 separating the levels. No weight within a level is ever 0, so
+// the level structure is kept, and a key can be cut down to a lower strength after the fact. The
+// strength it was generated at goes along with it, so that it isn't mistakenly compared at a
+// higher one. Input containing U+0000 is fine: it's completely ignorable in both tables, and the
+// code point level offsets every code point so that none of its weights is 0. Only the separators
+// are. (The bytes of as_bytes can still include 0x00, though, e.g., in the weight 0x0100; where a
+// NUL byte isn't allowed, as in a C string, use to_hex or to_base32). Keys are reproducible: the
+// same input, options and table version give the same key, and as_bytes the same bytes, on any
+// platform. Nothing depends on hash map iteration order, and the bytes are always big-endian
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub struct SortKey {
+    weights: Vec<u16>,
+    strength: CollationStrength,
+}
 
-                    // If the new subset is found in the table...
-                    if let Some(new_value) = multis.get(&new_subset) {
-                        // Then add these weights instead
-                        for weights in new_value {
-                            if shifting {
-                                let weight_values = get_weights_shifting(weights, last_variable);
-                                cea.push(weight_values);
-                                if weights.variable {
-                                    last_variable = true;
-                                } else if weights.primary != 0 {
-                                    last_variable = false;
-                                }
-                            } else {
-                                let weight_values = array_vec!(
-                                    [u16; 4] => weights.primary, weights.secondary, weights.tertiary
-                                );
-                                cea.push(weight_values);
-                            }
-                        }
+impl SortKey {
+    pub fn as_slice(&self) -> &[u16] {
+        &self.weights
+    }
 
-                        // Remove the pulled char
-                        char_vals.remove(right + 1);
+    pub fn into_vec(self) -> Vec<u16> {
+        self.weights
+    }
 
-                        // Increment and continue outer loop
-                        left += right - left;
-                        continue 'outer;
-                    }
+    // The weights as big-endian bytes, which compare (e.g., with memcmp, or as a database's binary
+    // column) the same way as the key itself
+    pub fn as_bytes(&self) -> Vec<u8> {
+        self.weights.iter().flat_map(|w| w.to_be_bytes()).collect()
+    }
 
-                    // The loop will not run again
-                    try_discont = false;
-                }
+    // The weights as little-endian bytes. Warning: these do NOT compare correctly with memcmp or
+    // any other bytewise comparison, since the low byte of each weight comes first. They're only
+    // for storage where the key is read back into weights before comparing
+    pub fn as_bytes_le(&self) -> Vec<u8> {
+        self.weights.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
 
-                // At this point, we're not looking for a discontiguous match. We just need to push
-                // the weights from the original subset we found
+    pub fn levels(&self) -> usize {
+        self.weights.iter().filter(|w| **w == 0).count() + 1
+    }
 
-                for weights in row {
-                    if shifting {
-                        let weight_values = get_weights_shifting(weights, last_variable);
-                        cea.push(weight_values);
-                        if weights.variable {
-                            last_variable = true;
-                        } else if weights.primary != 0 {
-                            last_variable = false;
-                        }
-                    } else {
-                        let weight_values = array_vec!(
-                            [u16; 4] => weights.primary, weights.secondary, weights.tertiary
-                        );
-                        cea.push(weight_values);
-                    }
-                }
+    pub fn strength(&self) -> CollationStrength {
+        self.strength
+    }
 
-                // Increment and continue outer loop
-                left += right - left;
-                continue 'outer;
-            }
+    // The same key as if it had been generated at the given strength, which can't be higher than
+    // the one it was generated at (in release builds, the key is left at its own strength)
+    pub fn to_strength(&self, strength: CollationStrength) -> SortKey {
+        self.check_strength(strength);
 
-            // Shorten slice to try again
-            right -= 1;
+        Self {
+            weights: self.weights[..self.level_end(strength)].to_vec(),
+            strength: strength.min(self.strength),
         }
+    }
 
-        // By now, we're looking for just one value, and it isn't in the table
-        // Time for implicit weights...
+    // Same as cmp, but only for keys generated at the same strength (checked in debug builds)
+    pub fn compare(&self, other: &SortKey) -> Ordering {
+        debug_assert_eq!(
+            self.strength, other.strength,
+            "comparing sort keys generated at different strengths"
+        );
 
-        let first_weights = get_implicit_a(left_val, shifting);
-        cea.push(first_weights);
+        self.weights.cmp(&other.weights)
+    }
 
-        let second_weights = get_implicit_b(left_val, shifting);
-        cea.push(second_weights);
+    // Compares only the levels up to the given strength, without copying anything. Both keys have
+    // to have been generated at that strength or higher (checked in debug builds)
+    pub fn compare_at(&self, other: &SortKey, strength: CollationStrength) -> Ordering {
+        self.check_strength(strength);
+        other.check_strength(strength);
 
-        // Finally, increment and let outer loop continue
-        left += 1;
+        let a = &self.weights[..self.level_end(strength)];
+        let b = &other.weights[..other.level_end(strength)];
+
+        a.cmp(b)
     }
 
-    cea
+    // The weights as big-endian bytes, in lowercase hex (four digits per weight), for logging and
+    // pasting around
+    pub fn to_hex(&self) -> String {
+        self.weights.iter().map(|w| format!("{w:04x}")).collect()
+    }
+
+    pub fn from_hex(hex: &str) -> Result<SortKey, ParseSortKeyError> {
+        if !hex.len().is_multiple_of(4) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseSortKeyError);
+        }
+
+        let weights = (0..hex.len())
+            .step_by(4)
+            .map(|i| u16::from_str_radix(&hex[i..i + 4], 16).map_err(|_| ParseSortKeyError))
+            .collect::<Result<Vec<u16>, _>>()?;
+
+        Ok(Self::from_weights(weights))
+    }
+
+    // The same bytes in base32 (RFC 4648 alphabet, lowercase, no padding), a fifth shorter than hex
+    pub fn to_base32(&self) -> String {
+        let mut out = String::with_capacity((self.weights.len() * 16).div_ceil(5));
+        let mut buffer = 0_u32;
+        let mut bits = 0;
+
+        for byte in self.weights.iter().flat_map(|w| w.to_be_bytes()) {
+            buffer = (buffer << 8) | u32::from(byte);
+            bits += 8;
+
+            while bits >= 5 {
+                bits -= 5;
+                out.push(BASE32[(buffer >> bits) as usize & 31] as char);
+            }
+        }
+
+        if bits > 0 {
+            out.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+        }
+
+        out
+    }
+
+    pub fn from_base32(base32: &str) -> Result<SortKey, ParseSortKeyError> {
+        let mut bytes = Vec::with_capacity(base32.len() * 5 / 8);
+        let mut buffer = 0_u32;
+        let mut bits = 0;
+
+        for c in base32.bytes() {
+            let val = BASE32
+                .iter()
+                .position(|b| *b == c.to_ascii_lowercase())
+                .ok_or(ParseSortKeyError)?;
+
+            buffer = (buffer << 5) | val as u32;
+            bits += 5;
+
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+            }
+        }
+
+        // Leftover bits are only padding, and must be zero; the bytes must make whole weights
+        if bits >= 5 || buffer & ((1 << bits) - 1) != 0 || !bytes.len().is_multiple_of(2) {
+            return Err(ParseSortKeyError);
+        }
+
+        let weights = bytes
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+
+        Ok(Self::from_weights(weights))
+    }
+
+    fn check_strength(&self, strength: CollationStrength) {
+        debug_assert!(
+            strength <= self.strength,
+            "sort key generated at {:?} used at {strength:?}",
+            self.strength
+        );
+    }
+
+    // A decoded key has no record of its strength, so it's taken from the number of levels
+    fn from_weights(weights: Vec<u16>) -> Self {
+        let strength = match weights.iter().filter(|w| **w == 0).count() {
+            0 => CollationStrength::Primary,
+            1 => CollationStrength::Secondary,
+            2 => CollationStrength::Tertiary,
+            _ => CollationStrength::Quaternary,
+        };
+
+        Self { weights, strength }
+    }
+
+    // Index of the separator that ends the given level (or the length of the key)
+    fn level_end(&self, strength: CollationStrength) -> usize {
+        self.weights
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| **w == 0)
+            .nth(strength as usize - 1)
+            .map_or(self.weights.len(), |(i, _)| i)
+    }
 }
 
-fn get_weights_shifting(weights: &Weights, last_variable: bool) -> ArrayVec<[u16; 4]> {
-    if weights.primary == 0 && weights.secondary == 0 && weights.tertiary == 0 {
-        ArrayVec::from([0, 0, 0, 0])
-    } else if weights.variable {
-        ArrayVec::from([0, 0, 0, weights.primary])
-    } else if last_variable && weights.primary == 0 && weights.tertiary != 0 {
-        ArrayVec::from([0, 0, 0, 0])
-    } else {
-        ArrayVec::from([weights.primary, weights.secondary, weights.tertiary, 65_535])
+// A string's sort keys at every strength, as for an index that stores more than one. Where the
+// options don't go as far as a level (e.g., when not shifting, there's no quaternary level), the
+// keys for that strength and the one below are the same
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct MultiKey {
+    pub primary: SortKey,
+    pub secondary: SortKey,
+    pub tertiary: SortKey,
+    pub quaternary: SortKey,
+}
+
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+// A closure giving the two collation elements for a code point in place of its implicit weights,
+// for CollationOptions::implicit_override. Two overrides are equal only if they're the same closure
+#[derive(Copy, Clone)]
+pub struct ImplicitOverride<'a>(pub &'a dyn Fn(u32) -> Option<[Weights; 2]>);
+
+impl PartialEq for ImplicitOverride<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::addr_eq(self.0, other.0)
     }
 }
 
-fn get_implicit_a(left_val: u32, shifting: bool) -> ArrayVec<[u16; 4]> {
-    #[allow(clippy::manual_range_contains)]
-    let mut aaaa = match left_val {
-        x if x >= 13_312 && x <= 19_903 => 64_384 + (left_val >> 15), //     CJK2
-        x if x >= 19_968 && x <= 40_959 => 64_320 + (left_val >> 15), //     CJK1
-        x if x >= 63_744 && x <= 64_255 => 64_320 + (left_val >> 15), //     CJK1
-        x if x >= 94_208 && x <= 101_119 => 64_256,                   //     Tangut
-        x if x >= 101_120 && x <= 101_631 => 64_258,                  //     Khitan
-        x if x >= 101_632 && x <= 101_775 => 64_256,                  //     Tangut
-        x if x >= 110_960 && x <= 111_359 => 64_257,                  //     Nushu
-        x if x >= 131_072 && x <= 173_791 => 64_384 + (left_val >> 15), //   CJK2
-        x if x >= 173_824 && x <= 191_471 => 64_384 + (left_val >> 15), //   CJK2
-        x if x >= 196_608 && x <= 201_551 => 64_384 + (left_val >> 15), //   CJK2
-        _ => 64_448 + (left_val >> 15),                               //     unass.
+impl Eq for ImplicitOverride<'_> {}
+
+impl PartialOrd for ImplicitOverride<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// By address, which is arbitrary but consistent with eq
+impl Ord for ImplicitOverride<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let addr = |o: &Self| std::ptr::from_ref(o.0).cast::<()>();
+        addr(self).cmp(&addr(other))
+    }
+}
+
+impl std::hash::Hash for ImplicitOverride<'_> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::ptr::from_ref(self.0).cast::<()>().hash(state);
+    }
+}
+
+impl std::fmt::Debug for ImplicitOverride<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("ImplicitOverride(..)")
+    }
+}
+
+// Returned by SortKey::from_hex and SortKey::from_base32 for input that isn't a whole number of
+// weights in the expected encoding
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ParseSortKeyError;
+
+impl std::fmt::Display for ParseSortKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("invalid encoded sort key")
+    }
+}
+
+impl std::error::Error for ParseSortKeyError {}
+
+// Returned by CollationOptions::validate
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OptionsError {
+    // The option only affects a level that the strength doesn't reach
+    StrengthTooLow {
+        option: &'static str,
+        needs: CollationStrength,
+    },
+    // The first option has no effect with the second
+    Conflict(&'static str, &'static str),
+}
+
+impl std::fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::StrengthTooLow { option, needs } => {
+                write!(f, "{option} needs at least {needs:?} strength")
+            }
+            Self::Conflict(option, other) => write!(f, "{option} has no effect with {other}"),
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+// Strings that implement Ord by calling collate, so they can go straight into a BTreeSet, a sorted
+// Vec, etc. Each comparison does the full work of collation, so when the same strings are compared
+// many times, it's cheaper to build a SortKey for each once. The left-hand value's options are
+// used; mixing options in one container makes no sense. Values are equal when collate says they
+// are: with the default tiebreak, only if their strings are identical, but with TiebreakMode::None,
+// whenever they tie (e.g., "a" and "a\u{200B}")
+#[derive(Copy, Clone, Debug)]
+pub struct CollatedStr<'a>(pub &'a str, pub CollationOptions<'a>);
+
+#[derive(Clone, Debug)]
+pub struct CollatedString(pub String, pub CollationOptions<'static>);
+
+impl Ord for CollatedStr<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        collate(self.0, other.0, self.1)
+    }
+}
+
+impl PartialOrd for CollatedStr<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for CollatedStr<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CollatedStr<'_> {}
+
+impl Ord for CollatedString {
+    fn cmp(&self, other: &Self) -> Ordering {
+        collate(&self.0, &other.0, self.1)
+    }
+}
+
+impl PartialOrd for CollatedString {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for CollatedString {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CollatedString {}
+
+// A map with string keys in collation order, rather than byte order. Unlike a BTreeMap with
+// CollatedString keys, each key's sort key is built once, on insertion, so lookups and inserts
+// don't collate over and over. As with collate, keys that tie are ordered by code point
+#[derive(Clone, Debug)]
+pub struct CollationMap<V> {
+    inner: BTreeMap<(SortKey, String), V>,
+    opt: CollationOptions<'static>,
+}
+
+impl<V> CollationMap<V> {
+    pub fn new(opt: CollationOptions<'static>) -> Self {
+        Self {
+            inner: BTreeMap::new(),
+            opt,
+        }
+    }
+
+    // Returns the old value, if the key was already present
+    pub fn insert(&mut self, key: &str, value: V) -> Option<V> {
+        self.inner
+            .insert((sort_key(key, self.opt), key.to_string()), value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.inner.get(&(sort_key(key, self.opt), key.to_string()))
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        self.inner
+            .remove(&(sort_key(key, self.opt), key.to_string()))
+    }
+
+    // Entries in collation order, with the keys as they were inserted
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.inner
+            .iter()
+            .map(|((_, key), value)| (key.as_str(), value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash, Debug)]
+pub enum KeysSource {
+    Cldr,
+    Ducet,
+}
+
+// Groups of scripts that can be moved as a block with the reorder option. Kana is Hiragana and
+// Katakana together, as they're interleaved in the tables. The rest are CLDR's special groups,
+// which come before the scripts, in this order. Each of the first four runs from its first
+// character (tab, overline, grave accent, currency sign) up to the next, so, e.g., a few numeric
+// symbols that CLDR sorts just before the digits count as Currency. Digit is the digits 0-9,
+// along with the digits of other scripts, which share their primaries
+#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash, Debug)]
+pub enum ReorderGroup {
+    Latin,
+    Greek,
+    Cyrillic,
+    Kana,
+    Han,
+    Space,
+    Punctuation,
+    Symbol,
+    Currency,
+    Digit,
+}
+
+impl ReorderGroup {
+    fn is_special(self) -> bool {
+        SPECIAL_GROUPS.iter().any(|(group, _)| *group == self) || self == ReorderGroup::Digit
+    }
+}
+
+// NFKD also applies compatibility decompositions (ligatures, full-width forms, superscripts,
+// etc.), so those tie with their plain equivalents rather than differing at the tertiary level.
+// There's no FCD shortcut for NFKD, so it's always a bit slower
+#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash, Debug)]
+pub enum NormalizationForm {
+    Nfd,
+    Nfkd,
+}
+
+// The number of levels compared. By default, there is only a fourth level when shifting, so
+// Quaternary and Tertiary are the same otherwise (see QuaternarySource)
+#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum CollationStrength {
+    Primary = 1,
+    Secondary = 2,
+    Tertiary = 3,
+    #[default]
+    Quaternary = 4,
+}
+
+// Where the fourth level comes from, at quaternary strength. Shifted is the usual one: the
+// primaries of variable elements when shifting, and nothing otherwise. CodePoints is the NFD code
+// points themselves, with or without shifting, so only canonically equivalent strings tie. None
+// means no fourth level even when shifting, so variable elements are ignored completely
+#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash, Debug)]
+pub enum QuaternarySource {
+    Shifted,
+    CodePoints,
+    None,
+}
+
+// How to order strings that tie at every level. UTF-8 preserves code point order, so Utf8Bytes
+// (comparing the bytes, as str::cmp does) and Scalar (comparing the chars) always agree; unlike,
+// e.g., UTF-16 code units, where a supplementary character comes before U+E000..U+FFFF. Utf8Bytes
+// is the cheaper of the two. Grapheme puts the string with fewer grapheme clusters (extended, as
+// a user would count characters) first, then goes by chars; so, e.g., "a" with an ignorable
+// variation selector stays next to a plain "a", rather than after "a" with a zero-width space,
+// which looks like one character but counts as two. That's a stable order that's easier to make
+// sense of in a UI. (collate_utf16 and collate_bytes don't count clusters, and go by code point
+// or byte.) None leaves the strings Equal, as in collate_no_tiebreak; a stable sort then keeps
+// them in their original order
+#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash, Debug)]
+pub enum TiebreakMode {
+    Utf8Bytes,
+    Scalar,
+    Grapheme,
+    None,
+}
+
+// Which case goes first in collate_caseless_case_tiebreak
+#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash, Debug)]
+pub enum CaseFirst {
+    Lower,
+    Upper,
+}
+
+// How far up the variable elements go when shifting, as with CLDR's maxVariable: spaces only, or
+// up through punctuation, symbols, or currency symbols (see ReorderGroup for where the groups
+// start and end). Punct is what the CLDR table has anyway
+#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash, Debug)]
+pub enum MaxVariable {
+    Space,
+    Punct,
+    Symbol,
+    Currency,
+}
+
+// Why two strings tied (before the tiebreaker), if they did. EqualNfd means they were the same
+// after normalization (and case folding, if enabled). Input that's already FCD isn't fully
+// decomposed, though, so canonically equivalent strings can also end up as EqualKeys
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum TieReason {
+    Identical,
+    EqualNfd,
+    EqualKeys,
+    NotTied,
+}
+
+// How get_cea turns table weights into collation elements
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Weighting {
+    NonIgnorable,
+    Shifted,
+    // Shifted at primary strength: only primaries are ever compared, so variable elements can
+    // just be zeroed, without tracking what follows them or computing a fourth weight. (This
+    // doesn't work at secondary or tertiary strength, where an ignorable after a variable still
+    // has to lose its weights)
+    ShiftedPrimary,
+}
+
+// Used to map collation elements back to byte offsets in the source string
+struct CeaTrace {
+    offsets: Vec<usize>, // Parallel to the NFD code points
+    origins: Vec<usize>, // Parallel to the collation element array
+    current: usize,
+}
+
+impl CeaTrace {
+    fn fill(&mut self, cea_len: usize) {
+        self.origins.resize(cea_len, self.current);
+    }
+}
+
+//
+// Static/const
+//
+
+static FCD: Lazy<TableMap<u32, u16>> = Lazy::new(|| {
+    let data = include_bytes!("bincode/fcd");
+    let decoded: TableMap<u32, u16> = bincode::deserialize(data).unwrap();
+    decoded
+});
+
+// Full canonical decompositions (other than Hangul syllables, which are done arithmetically)
+static DECOMP: Lazy<TableMap<u32, Vec<u32>>> = Lazy::new(|| {
+    let data = include_bytes!("bincode/decomp");
+    let decoded: TableMap<u32, Vec<u32>> = bincode::deserialize(data).unwrap();
+    decoded
+});
+
+// Hangul LV syllables (which decompose to two jamo, rather than three)
+static JAMO: Lazy<HashSet<u32, TableHasher>> = Lazy::new(|| {
+    let data = include_bytes!("bincode/jamo");
+    let decoded: HashSet<u32, TableHasher> = bincode::deserialize(data).unwrap();
+    decoded
+});
+
+// Tibetan vowel signs that decompose to U+0F71 and another sign (U+0F72, U+0F74, U+0F80). They're
+// in the tables, but after another U+0F71, the decomposed form pairs that one with the second sign
+// (a discontiguous match), which the precomposed form can't do. So they're always decomposed
+const TIBETAN_DECOMPOSABLE: [u32; 3] = [0xF73, 0xF75, 0xF81];
+
+// Historic letters and scribal abbreviations that the tables give weights of their own, with the
+// modern letters to weigh them as when folding them (see CollationOptions::historic_folding)
+const HISTORIC_FOLDS: [(u32, &str); 12] = [
+    // Long s with a diagonal stroke, and with a high stroke
+    (0x1E9C, "s"),
+    (0x1E9D, "s"),
+    // R with a long leg
+    (0x27C, "r"),
+    // Wynn
+    (0x1F7, "W"),
+    (0x1BF, "w"),
+    // The abbreviations et, is, con, and us
+    (0xA76A, "ET"),
+    (0xA76B, "et"),
+    (0xA76C, "IS"),
+    (0xA76D, "is"),
+    (0xA76E, "CON"),
+    (0xA76F, "con"),
+    (0xA770, "us"),
+];
+
+// Added to a letter's tertiary weight for a variant of it (e.g., ſ, or ĳ for i and j), in the
+// tables; and for a folded historic letter
+const VARIANT_TERTIARY_OFFSET: u16 = 2;
+
+const S_BASE: u32 = 0xAC00;
+const S_LAST: u32 = 0xD7A3;
+const L_BASE: u32 = 0x1100;
+const V_BASE: u32 = 0x1161;
+const T_BASE: u32 = 0x11A7;
+const T_COUNT: u32 = 28;
+const N_COUNT: u32 = 588;
+
+static DUCET: Lazy<CollationTable> =
+    Lazy::new(|| CollationTable::load(KeysSource::Ducet, DUCET_DATA));
+
+static CLDR: Lazy<CollationTable> = Lazy::new(|| CollationTable::load(KeysSource::Cldr, CLDR_DATA));
+
+// How many times each built-in table has been loaded, by KeysSource
+#[cfg(test)]
+static TABLE_LOADS: [std::sync::atomic::AtomicUsize; 2] = [
+    std::sync::atomic::AtomicUsize::new(0),
+    std::sync::atomic::AtomicUsize::new(0),
+];
+
+// Low, singles, and multis
+#[cfg(not(feature = "compressed-tables"))]
+const DUCET_DATA: [&[u8]; 3] = [
+    include_bytes!("bincode/low"),
+    include_bytes!("bincode/singles"),
+    include_bytes!("bincode/multis"),
+];
+
+#[cfg(not(feature = "compressed-tables"))]
+const CLDR_DATA: [&[u8]; 3] = [
+    include_bytes!("bincode/low_cldr"),
+    include_bytes!("bincode/singles_cldr"),
+    include_bytes!("bincode/multis_cldr"),
+];
+
+// The same, gzipped (with gzip -9 -n)
+#[cfg(feature = "compressed-tables")]
+const DUCET_DATA: [&[u8]; 3] = [
+    include_bytes!("bincode/low.gz"),
+    include_bytes!("bincode/singles.gz"),
+    include_bytes!("bincode/multis.gz"),
+];
+
+#[cfg(feature = "compressed-tables")]
+const CLDR_DATA: [&[u8]; 3] = [
+    include_bytes!("bincode/low_cldr.gz"),
+    include_bytes!("bincode/singles_cldr.gz"),
+    include_bytes!("bincode/multis_cldr.gz"),
+];
+
+const COMMON_SECONDARY: u16 = 0x20;
+
+// Runs of the common secondary weight are encoded in chunks of up to this many
+const SECONDARY_RUN_MAX: u16 = 16;
+
+// Above any tertiary weight in the tables
+const VS_TERTIARY: u16 = 0x20;
+
+// Tertiary weights of lowercase letters (plain, wide, compatibility, font, circled); those of the
+// uppercase counterparts are the same plus CASE_OFFSET
+const LOWER_TERTIARIES: RangeInclusive<u16> = 0x02..=0x06;
+const CASE_OFFSET: u16 = 6;
+
+// Code points below this are in the low table, unless they start a contraction (the table is
+// stored densely, so this also sets its size)
+const LOW_END: u32 = 183;
+
+// The letters that mark out each reorder group's primaries (besides Han). For Kana, not the
+// iteration marks or the prolonged sound mark, which sort with common characters
+const REORDER_LETTERS: [(ReorderGroup, &[RangeInclusive<u32>]); 5] = [
+    (
+        ReorderGroup::Latin,
+        &[0x41..=0x5A, 0x61..=0x7A, 0xC0..=0x24F, 0x1E00..=0x1EFF],
+    ),
+    (
+        ReorderGroup::Greek,
+        &[
+            0x370..=0x373,
+            0x376..=0x377,
+            0x37B..=0x37F,
+            0x386..=0x3FF,
+            0x1F00..=0x1FFF,
+        ],
+    ),
+    (ReorderGroup::Cyrillic, &[0x400..=0x52F]),
+    (
+        ReorderGroup::Kana,
+        &[
+            0x3041..=0x309C,
+            0x309F..=0x30FB,
+            0x30FF..=0x30FF,
+            0x31F0..=0x31FF,
+        ],
+    ),
+    (ReorderGroup::Digit, &[0x30..=0x39]),
+];
+
+// The first character of each special group (besides Digit), in both tables
+const SPECIAL_GROUPS: [(ReorderGroup, u32); 4] = [
+    (ReorderGroup::Space, 0x09),
+    (ReorderGroup::Punctuation, 0x203E),
+    (ReorderGroup::Symbol, 0x60),
+    (ReorderGroup::Currency, 0xA4),
+];
+
+const INCLUDED_UNASSIGNED: [u32; 4] = [177_977, 178_206, 183_970, 191_457];
+
+// Implicit weights are [.AAAA.0020.0002][.BBBB.0000.0000] (UTS #10, Implicit Weights): the first
+// element has the common secondary and tertiary weights of a plain letter, and the second has
+// none, so that the pair acts as one character at the lower levels
+const IMPLICIT_SECONDARY: u16 = 0x20;
+const IMPLICIT_TERTIARY: u16 = 0x02;
+
+// Under shifting, every element that isn't variable or ignorable (implicit weights included) gets
+// the highest fourth weight (UTS #10, Variable Weighting). It's not arbitrary: it puts them after
+// any shifted variable element at that level, and it's the same for all of them, so the primary
+// decides between two implicit-weighted characters, never the quaternary
+const SHIFTED_QUATERNARY: u16 = 0xFFFF;
+
+//
+// Macros
+//
+
+macro_rules! regex {
+    ($re:literal $(,)?) => {{
+        static RE: OnceCell<Regex> = OnceCell::new();
+        RE.get_or_init(|| Regex::new($re).unwrap())
+    }};
+}
+
+//
+// Functions, public
+//
+
+// Loads the built-in table for the given source, along with the normalization data, right away
+// rather than on first use. Call it at startup (e.g., in a server) so that the first requests
+// don't all wait on the loading. Once loaded, everything stays loaded; calling this again does
+// nothing
+pub fn warm_tables(keys_source: KeysSource) {
+    let table = builtin_table(keys_source);
+
+    // This works out the primary ranges of all the reorder groups at once
+    table.reorder_range(ReorderGroup::Latin);
+
+    Lazy::force(&FCD);
+    Lazy::force(&DECOMP);
+    Lazy::force(&JAMO);
+}
+
+pub fn collate(str_a: &str, str_b: &str, opt: CollationOptions) -> Ordering {
+    collate_with_table(str_a, str_b, builtin_table(opt.keys_source), opt)
+}
+
+// Same as collate, but with weights from the given table; opt.keys_source is ignored
+pub fn collate_with_table(
+    str_a: &str,
+    str_b: &str,
+    table: &CollationTable,
+    opt: CollationOptions,
+) -> Ordering {
+    // Early out
+    if str_a == str_b {
+        return Ordering::Equal;
+    }
+
+    // Get NFD if necessary (i.e., if not FCD)
+    let mut a_nfd = get_nfd(str_a, opt);
+    let mut b_nfd = get_nfd(str_b, opt);
+
+    let comparison = collate_nfd(&mut a_nfd, &mut b_nfd, table, opt);
+
+    if comparison == Ordering::Equal {
+        return tiebreak(str_a, str_b, opt.tiebreak);
+    }
+
+    comparison
+}
+
+fn tiebreak(str_a: &str, str_b: &str, mode: TiebreakMode) -> Ordering {
+    match mode {
+        TiebreakMode::Utf8Bytes => str_a.cmp(str_b),
+        TiebreakMode::Scalar => str_a.chars().cmp(str_b.chars()),
+        TiebreakMode::Grapheme => {
+            let a_count = str_a.graphemes(true).count();
+            let b_count = str_b.graphemes(true).count();
+
+            a_count
+                .cmp(&b_count)
+                .then_with(|| str_a.chars().cmp(str_b.chars()))
+        }
+        TiebreakMode::None => Ordering::Equal,
+    }
+}
+
+// A comparison function with the options built in, for sort_by and the like; reversed if
+// descending (so the tiebreaker is reversed too, and the order is exactly the opposite)
+pub fn comparator<'a>(
+    opt: CollationOptions<'a>,
+    descending: bool,
+) -> impl Fn(&str, &str) -> Ordering + 'a {
+    move |a, b| {
+        let comparison = collate(a, b, opt);
+
+        if descending {
+            comparison.reverse()
+        } else {
+            comparison
+        }
+    }
+}
+
+// Compares at secondary strength, so that case doesn't count (e.g., "apple" and "Apple" go
+// together, before "apples"); then, only if that's a tie, by case, letter by letter, with the
+// given case first; and then as collate would, with the strength and tiebreaker in the options.
+// Unlike making case count at the tertiary level (or before it), case never outweighs an accent
+// or a letter
+pub fn collate_caseless_case_tiebreak(
+    str_a: &str,
+    str_b: &str,
+    case_first: CaseFirst,
+    opt: CollationOptions,
+) -> Ordering {
+    let caseless = CollationOptions {
+        strength: CollationStrength::Secondary,
+        ..opt
     };
 
-    if INCLUDED_UNASSIGNED.contains(&left_val) {
-        aaaa = 64_448 + (left_val >> 15);
+    let comparison = collate_no_tiebreak(str_a, str_b, caseless);
+
+    if comparison != Ordering::Equal {
+        return comparison;
     }
 
-    #[allow(clippy::cast_possible_truncation)]
-    let first_weights = if shifting {
-        // Add an arbitrary fourth weight if shifting
-        ArrayVec::from([aaaa as u16, 32, 2, 65_535])
-    } else {
-        array_vec!([u16; 4] => aaaa as u16, 32, 2)
+    // The primaries are the same on both sides, so these line up letter by letter
+    let cases = |s: &str| {
+        collation_elements(s, caseless)
+            .into_iter()
+            .filter(|elem| elem[0] != 0)
+            .map(|elem| {
+                let upper = LOWER_TERTIARIES.contains(&elem[2].wrapping_sub(CASE_OFFSET));
+                upper == (case_first == CaseFirst::Lower)
+            })
+            .collect::<Vec<bool>>()
     };
 
-    first_weights
+    cases(str_a)
+        .cmp(&cases(str_b))
+        .then_with(|| collate(str_a, str_b, opt))
 }
 
-fn get_implicit_b(left_val: u32, shifting: bool) -> ArrayVec<[u16; 4]> {
-    #[allow(clippy::manual_range_contains)]
-    let mut bbbb = match left_val {
-        x if x >= 13_312 && x <= 19_903 => left_val & 32_767, //      CJK2
-        x if x >= 19_968 && x <= 40_959 => left_val & 32_767, //      CJK1
-        x if x >= 63_744 && x <= 64_255 => left_val & 32_767, //      CJK1
-        x if x >= 94_208 && x <= 101_119 => left_val - 94_208, //     Tangut
-        x if x >= 101_120 && x <= 101_631 => left_val - 101_120, //   Khitan
-        x if x >= 101_632 && x <= 101_775 => left_val - 94_208, //    Tangut
-        x if x >= 110_960 && x <= 111_359 => left_val - 110_960, //   Nushu
-        x if x >= 131_072 && x <= 173_791 => left_val & 32_767, //    CJK2
-        x if x >= 173_824 && x <= 191_471 => left_val & 32_767, //    CJK2
-        x if x >= 196_608 && x <= 201_551 => left_val & 32_767, //    CJK2
-        _ => left_val & 32_767,                               //      unass.
+// Same as collate, but also says which branch decided the result
+pub fn collate_detailed(str_a: &str, str_b: &str, opt: CollationOptions) -> (Ordering, TieReason) {
+    if str_a == str_b {
+        return (Ordering::Equal, TieReason::Identical);
+    }
+
+    let mut a_nfd = get_nfd(str_a, opt);
+    let mut b_nfd = get_nfd(str_b, opt);
+
+    if a_nfd == b_nfd {
+        return (tiebreak(str_a, str_b, opt.tiebreak), TieReason::EqualNfd);
+    }
+
+    let comparison = collate_nfd(&mut a_nfd, &mut b_nfd, builtin_table(opt.keys_source), opt);
+
+    if comparison == Ordering::Equal {
+        return (tiebreak(str_a, str_b, opt.tiebreak), TieReason::EqualKeys);
+    }
+
+    (comparison, TieReason::NotTied)
+}
+
+pub fn collate_no_tiebreak(str_a: &str, str_b: &str, opt: CollationOptions) -> Ordering {
+    // Early out
+    if str_a == str_b {
+        return Ordering::Equal;
+    }
+
+    // Get NFD if necessary (i.e., if not FCD)
+    let mut a_nfd = get_nfd(str_a, opt);
+    let mut b_nfd = get_nfd(str_b, opt);
+
+    collate_nfd(&mut a_nfd, &mut b_nfd, builtin_table(opt.keys_source), opt)
+}
+
+// Options for a BCP 47 locale tag, approximating its CLDR tailoring with what's available here: by
+// language, the script order (e.g., Cyrillic first for Russian), the place of a few letters (y
+// after i in Lithuanian; z after s in Estonian), and French backwards secondaries. Anything else,
+// including languages whose tailorings add letters (e.g., Swedish å, ä, ö after z), gets the
+// defaults, which are CLDR root
+pub fn locale_options(locale: &str) -> CollationOptions<'static> {
+    let language = locale.split(['-', '_']).next().unwrap_or_default();
+
+    let mut opt = CollationOptions::default();
+
+    match language.to_ascii_lowercase().as_str() {
+        "fr" => opt.backwards_secondary = true,
+        "ja" => opt.reorder = &[ReorderGroup::Latin, ReorderGroup::Kana, ReorderGroup::Han],
+        "zh" => opt.reorder = &[ReorderGroup::Han],
+        "be" | "bg" | "kk" | "mk" | "ru" | "sr" | "uk" => opt.reorder = &[ReorderGroup::Cyrillic],
+        "el" => opt.reorder = &[ReorderGroup::Greek],
+        "lt" => {
+            opt.primary_order = &[
+                'i', 'y', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x',
+            ]
+        }
+        "et" => opt.primary_order = &['s', 'z', 't', 'u', 'v', 'w', 'x', 'y'],
+        _ => (),
+    }
+
+    opt
+}
+
+pub fn collate_locale(str_a: &str, str_b: &str, locale: &str) -> Ordering {
+    collate(str_a, str_b, locale_options(locale))
+}
+
+// Compares strings by their collation elements read from the end, e.g., for a rhyming dictionary:
+// words with the same ending sort together. Only the order of the elements is reversed, not the
+// weights within them, and the levels are still compared one after another. As in collate, a tie
+// is broken according to opt.tiebreak
+pub fn collate_reversed_elements(str_a: &str, str_b: &str, opt: CollationOptions) -> Ordering {
+    if str_a == str_b {
+        return Ordering::Equal;
+    }
+
+    let mut a_cea = collation_elements(str_a, opt);
+    let mut b_cea = collation_elements(str_b, opt);
+
+    a_cea.reverse();
+    b_cea.reverse();
+
+    compare_ceas(&a_cea, &b_cea, opt).then_with(|| tiebreak(str_a, str_b, opt.tiebreak))
+}
+
+// Unpaired surrogates aren't an error. Like other code points missing from the tables, they get
+// implicit weights, so they sort consistently (after assigned characters). The tiebreaker is by
+// code point (not code unit), as with collate, unless opt.tiebreak is None
+pub fn collate_utf16(a: &[u16], b: &[u16], opt: CollationOptions) -> Ordering {
+    // Early out
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let a_code_points = decode_utf16(a);
+    let b_code_points = decode_utf16(b);
+
+    // Get NFD if necessary (i.e., if not FCD)
+    let mut a_nfd = get_nfd_code_points(&a_code_points, opt);
+    let mut b_nfd = get_nfd_code_points(&b_code_points, opt);
+
+    let comparison = collate_nfd(&mut a_nfd, &mut b_nfd, builtin_table(opt.keys_source), opt);
+
+    if comparison == Ordering::Equal && opt.tiebreak != TiebreakMode::None {
+        return a_code_points.cmp(&b_code_points);
+    }
+
+    comparison
+}
+
+// Compares arbitrary bytes (e.g., Unix file names) as UTF-8 where they're valid. Any byte that
+// isn't part of a valid sequence is escaped as a lone surrogate (see surrogate_escape), so,
+// unlike text from String::from_utf8_lossy, where each invalid sequence becomes U+FFFD, data
+// with different invalid bytes doesn't tie. Valid UTF-8 gives the same result as collate, apart
+// from the leading articles option; the tiebreaker is by byte
+pub fn collate_bytes(a: &[u8], b: &[u8], opt: CollationOptions) -> Ordering {
+    // Early out
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let mut a_nfd = get_nfd_code_points(&surrogate_escape(a), opt);
+    let mut b_nfd = get_nfd_code_points(&surrogate_escape(b), opt);
+
+    let comparison = collate_nfd(&mut a_nfd, &mut b_nfd, builtin_table(opt.keys_source), opt);
+
+    if comparison == Ordering::Equal && opt.tiebreak != TiebreakMode::None {
+        return a.cmp(b);
+    }
+
+    comparison
+}
+
+// Decodes bytes as UTF-8, escaping each byte that isn't part of a valid sequence as a lone
+// surrogate: 0x80..=0xFF become U+DC80..=U+DCFF (Python's "surrogateescape", from PEP 383; ASCII
+// bytes are always valid). Those get implicit weights like other unpaired surrogates: after
+// assigned characters, and in byte order among themselves. An encoded surrogate (as in WTF-8)
+// isn't valid UTF-8, so it's escaped byte by byte too. For a sort key, pass the result to
+// sort_key_from_iter
+pub fn surrogate_escape(bytes: &[u8]) -> Vec<u32> {
+    let mut code_points = Vec::with_capacity(bytes.len());
+
+    for chunk in bytes.utf8_chunks() {
+        code_points.extend(chunk.valid().chars().map(|c| c as u32));
+        code_points.extend(chunk.invalid().iter().map(|b| 0xDC00 + u32::from(*b)));
+    }
+
+    code_points
+}
+
+// The fingerprint of a built-in table (see CollationTable::fingerprint)
+pub fn table_fingerprint(keys_source: KeysSource) -> u64 {
+    builtin_table(keys_source).fingerprint()
+}
+
+// The variable range of a built-in table (see CollationTable::variable_range)
+pub fn variable_range(keys_source: KeysSource) -> (u16, u16) {
+    builtin_table(keys_source).variable_range()
+}
+
+pub fn sort_key(input: &str, opt: CollationOptions) -> SortKey {
+    let mut nfd = get_nfd(input, opt);
+
+    SortKey {
+        weights: nfd_to_sk(&mut nfd, 0, opt),
+        strength: key_strength(opt),
+    }
+}
+
+// Same as sort_key, but the weights go into a buffer that the caller owns, which is cleared
+// first. Reusing one buffer for many keys saves allocating a new one each time
+pub fn sort_key_into(input: &str, opt: CollationOptions, out: &mut Vec<u16>) {
+    let mut nfd = get_nfd(input, opt);
+
+    out.clear();
+    push_nfd_sk(out, &mut nfd, 0, opt);
+}
+
+// The sort keys at all four strengths, whatever the strength in the options, from a single pass
+// over the string: the full key is cut down for the others (see SortKey::to_strength)
+pub fn multi_strength_keys(input: &str, opt: CollationOptions) -> MultiKey {
+    let quaternary = sort_key(
+        input,
+        CollationOptions {
+            strength: CollationStrength::Quaternary,
+            ..opt
+        },
+    );
+
+    MultiKey {
+        primary: quaternary.to_strength(CollationStrength::Primary),
+        secondary: quaternary.to_strength(CollationStrength::Secondary),
+        tertiary: quaternary.to_strength(CollationStrength::Tertiary),
+        quaternary,
+    }
+}
+
+// Same as sort_key, but for a stream of code points (which needn't be valid chars), normalized
+// and turned into collation elements a piece at a time instead of being collected first. The
+// elements are kept until the end, since the key has all of their primaries before any of their
+// secondaries, and so on. A piece ends before a starter, once no contraction, canonical
+// reordering, or shifting could reach across
+pub fn sort_key_from_iter<I: Iterator<Item = u32>>(iter: I, opt: CollationOptions) -> SortKey {
+    let table = builtin_table(opt.keys_source);
+
+    let mut cea: Vec<ArrayVec<[u16; 4]>> = Vec::new();
+    let mut piece: Vec<u32> = Vec::new();
+    let mut code_points: Vec<u32> = Vec::new();
+
+    // Each piece would otherwise be taken for the start of the string, so first_letter_case is
+    // applied once, to all of the elements
+    let piece_opt = CollationOptions {
+        first_letter_case: false,
+        ..opt
+    };
+
+    let mut flush = |piece: &mut Vec<u32>| {
+        canonical_reorder(piece);
+
+        if code_point_level(opt) {
+            code_points.extend_from_slice(piece);
+        }
+
+        cea.extend(get_cea(piece, 0, table, piece_opt, None));
+        piece.clear();
+    };
+
+    let mut push = |d: u32| {
+        // A contraction is at most three code points
+        let can_cut = get_ccc_u32(d) == 0
+            && piece
+                .last()
+                .is_some_and(|last| ends_variable_run(*last, table, opt))
+            && !piece
+                .iter()
+                .rev()
+                .take(2)
+                .any(|cp| table.starts_contraction(*cp));
+
+        if can_cut {
+            flush(&mut piece);
+        }
+
+        piece.push(d);
+    };
+
+    for code_point in iter {
+        let Some(c) = char::from_u32(code_point) else {
+            push(code_point);
+            continue;
+        };
+
+        let folded = opt.case_fold.then(|| fold_case(c)).into_iter().flatten();
+
+        for f in folded.chain((!opt.case_fold).then_some(c)) {
+            match opt.normalization {
+                NormalizationForm::Nfd => decompose_canonical(f, |d| push(d as u32)),
+                NormalizationForm::Nfkd => decompose_compatible(f, |d| push(d as u32)),
+            }
+        }
+    }
+
+    flush(&mut piece);
+
+    if opt.first_letter_case {
+        apply_first_letter_case(&mut cea, true);
+    }
+
+    let mut weights = get_sort_key(&cea, opt);
+
+    if code_point_level(opt) {
+        push_code_point_level(&mut weights, &code_points);
+    }
+
+    SortKey {
+        weights,
+        strength: key_strength(opt),
+    }
+}
+
+// How many leading bytes of the two strings' sort keys (as from SortKey::as_bytes) it takes to
+// tell them apart: one past the first byte where they differ, or past the end of the shorter key
+// if it's a prefix of the longer. If the keys are equal, that's their full length. Useful for
+// sizing truncated keys in an index
+pub fn distinguishing_prefix(str_a: &str, str_b: &str, opt: CollationOptions) -> usize {
+    let a = sort_key(str_a, opt).as_bytes();
+    let b = sort_key(str_b, opt).as_bytes();
+
+    match a.iter().zip(&b).position(|(x, y)| x != y) {
+        Some(i) => i + 1,
+        None if a.len() == b.len() => a.len(),
+        None => a.len().min(b.len()) + 1,
+    }
+}
+
+// Merges runs of sort keys, each already in order, into one ordered sequence (e.g., the last step
+// of an external sort). Only the next key of each run is held at a time. Equal keys come out in
+// the order of their runs
+pub fn merge_sorted_keys<I: Iterator<Item = SortKey>>(
+    mut runs: Vec<I>,
+) -> impl Iterator<Item = SortKey> {
+    // Reversed for a min-heap; the run index breaks ties
+    let mut heap: BinaryHeap<std::cmp::Reverse<(SortKey, usize)>> = runs
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(i, run)| run.next().map(|key| std::cmp::Reverse((key, i))))
+        .collect();
+
+    std::iter::from_fn(move || {
+        let std::cmp::Reverse((key, i)) = heap.pop()?;
+
+        if let Some(next) = runs[i].next() {
+            heap.push(std::cmp::Reverse((next, i)));
+        }
+
+        Some(key)
+    })
+}
+
+// Gives the same result as comparing the sort keys of two collation element arrays, but goes
+// level by level and stops at the first difference, without building the keys. (A code point
+// level, if the options call for one, isn't part of the collation elements, so it's left out)
+pub fn compare_ceas(
+    a: &[ArrayVec<[u16; 4]>],
+    b: &[ArrayVec<[u16; 4]>],
+    opt: CollationOptions,
+) -> Ordering {
+    for i in 0..max_level(opt) {
+        let a_iter = a.iter().map(|elem| elem[i]).filter(|w| *w != 0);
+        let b_iter = b.iter().map(|elem| elem[i]).filter(|w| *w != 0);
+
+        let comparison = if backwards_level(i, opt) {
+            compare_level(a_iter.rev(), b_iter.rev())
+        } else {
+            compare_level(a_iter, b_iter)
+        };
+
+        if comparison != Ordering::Equal {
+            return comparison;
+        }
+    }
+
+    Ordering::Equal
+}
+
+fn compare_level(
+    mut a_iter: impl Iterator<Item = u16>,
+    mut b_iter: impl Iterator<Item = u16>,
+) -> Ordering {
+    loop {
+        match (a_iter.next(), b_iter.next()) {
+            (None, None) => return Ordering::Equal,
+            // Running out first is like hitting the level separator in a sort key
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+            }
+        }
+    }
+}
+
+// Compares two collation element arrays by their sort keys, e.g., to see the effect of editing
+// the output of collation_elements by hand. An element may leave off trailing weights, which
+// count as 0; so elements built with three weights work under shifting too, just without a
+// quaternary
+pub fn compare_elements(
+    a: &[ArrayVec<[u16; 4]>],
+    b: &[ArrayVec<[u16; 4]>],
+    opt: CollationOptions,
+) -> Ordering {
+    let key = |cea: &[ArrayVec<[u16; 4]>]| {
+        let padded: Vec<ArrayVec<[u16; 4]>> = cea
+            .iter()
+            .map(|elem| {
+                let mut elem = *elem;
+                elem.resize(4, 0);
+                elem
+            })
+            .collect();
+
+        get_sort_key(&padded, opt)
+    };
+
+    key(a).cmp(&key(b))
+}
+
+pub fn collation_elements(input: &str, opt: CollationOptions) -> Vec<ArrayVec<[u16; 4]>> {
+    let mut nfd = get_nfd(input, opt);
+    get_cea(&mut nfd, 0, builtin_table(opt.keys_source), opt, None)
+}
+
+// Same as above, but with named fields; the quaternary is 0 when not shifting
+pub fn collation_elements_typed(input: &str, opt: CollationOptions) -> Vec<CollationElement> {
+    collation_elements(input, opt)
+        .into_iter()
+        .map(|elem| CollationElement {
+            primary: elem[0],
+            secondary: elem[1],
+            tertiary: elem[2],
+            quaternary: elem.get(3).copied().unwrap_or(0),
+        })
+        .collect()
+}
+
+// The table weights of a string, one entry per collation element, with the variable flag set as
+// the options say (i.e., with max_variable taken into account); but not yet shifted, so they can
+// be cached, e.g., per token, and given to collate_weight_streams with any weighting
+pub fn weights_for(input: &str, opt: CollationOptions) -> Vec<Weights> {
+    let table = builtin_table(opt.keys_source);
+    let range = variable_override(table, opt).unwrap_or(table.variable_range);
+
+    let unshifted = CollationOptions {
+        shifting: false,
+        ..opt
+    };
+
+    collation_elements(input, unshifted)
+        .iter()
+        .map(|elem| {
+            let weights = Weights {
+                variable: false,
+                primary: elem[0],
+                secondary: elem[1],
+                tertiary: elem[2],
+            };
+
+            with_variable(&weights, range)
+        })
+        .collect()
+}
+
+// Compares two streams of weights (e.g., from weights_for, for a sequence of tokens) as collate
+// would compare the strings they came from, but with no normalization or table lookup: the
+// weights are only shifted, per the options, and compared level by level. There's no code point
+// level or tiebreaker, since there are no code points
+pub fn collate_weight_streams(a: &[Weights], b: &[Weights], opt: CollationOptions) -> Ordering {
+    let cea = |weights: &[Weights]| {
+        let mut cea = Vec::new();
+        let mut variable_run = None;
+
+        for w in weights {
+            push_weights(&mut cea, w, weighting(opt), None, &mut variable_run);
+        }
+
+        cea
+    };
+
+    compare_ceas(&cea(a), &cea(b), opt)
+}
+
+// Byte offsets in each string of the collation element where they first differ (checking level
+// by level, as in sort key comparison; backwards levels from the end); or None if they're equal at
+// every level
+pub fn divergence_index(str_a: &str, str_b: &str, opt: CollationOptions) -> Option<(usize, usize)> {
+    if str_a == str_b {
+        return None;
+    }
+
+    let (a_cea, a_origins) = get_traced_cea(str_a, opt);
+    let (b_cea, b_origins) = get_traced_cea(str_b, opt);
+
+    for i in 0..max_level(opt) {
+        let a_level: Vec<_> = a_cea
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e[i] != 0)
+            .collect();
+        let b_level: Vec<_> = b_cea
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e[i] != 0)
+            .collect();
+
+        let backwards = backwards_level(i, opt);
+
+        // If one side runs out, point to the end of that string (or the start, going backwards)
+        let (a_end, b_end) = if backwards {
+            (0, 0)
+        } else {
+            (str_a.len(), str_b.len())
+        };
+
+        let diff = |a: &mut dyn Iterator<Item = &(usize, &ArrayVec<[u16; 4]>)>,
+                    b: &mut dyn Iterator<Item = &(usize, &ArrayVec<[u16; 4]>)>| loop {
+            match (a.next(), b.next()) {
+                (None, None) => return None,
+                (Some((_, x)), Some((_, y))) if x[i] == y[i] => continue,
+                (x, y) => {
+                    let a_offset = x.map_or(a_end, |(j, _)| a_origins[*j]);
+                    let b_offset = y.map_or(b_end, |(j, _)| b_origins[*j]);
+                    return Some((a_offset, b_offset));
+                }
+            }
+        };
+
+        let found = if backwards {
+            diff(&mut a_level.iter().rev(), &mut b_level.iter().rev())
+        } else {
+            diff(&mut a_level.iter(), &mut b_level.iter())
+        };
+
+        if found.is_some() {
+            return found;
+        }
+    }
+
+    None
+}
+
+// The collation elements where the two strings first differ (checking level by level, as in sort
+// key comparison; backwards levels from the end); or None if they're equal at every level. If one
+// string runs out of weights at that level first, its side is an empty element. See also
+// divergence_index, for where the elements came from
+pub fn diff_elements(str_a: &str, str_b: &str, opt: CollationOptions) -> Option<ElementPair> {
+    if str_a == str_b {
+        return None;
+    }
+
+    let a_cea = collation_elements(str_a, opt);
+    let b_cea = collation_elements(str_b, opt);
+
+    for i in 0..max_level(opt) {
+        let a_level: Vec<_> = a_cea.iter().filter(|elem| elem[i] != 0).collect();
+        let b_level: Vec<_> = b_cea.iter().filter(|elem| elem[i] != 0).collect();
+
+        let diff = |a: &mut dyn Iterator<Item = &&ArrayVec<[u16; 4]>>,
+                    b: &mut dyn Iterator<Item = &&ArrayVec<[u16; 4]>>| loop {
+            match (a.next(), b.next()) {
+                (None, None) => return None,
+                (Some(x), Some(y)) if x[i] == y[i] => continue,
+                (x, y) => {
+                    return Some((
+                        x.map_or_else(ArrayVec::new, |x| **x),
+                        y.map_or_else(ArrayVec::new, |y| **y),
+                    ));
+                }
+            }
+        };
+
+        let found = if backwards_level(i, opt) {
+            diff(&mut a_level.iter().rev(), &mut b_level.iter().rev())
+        } else {
+            diff(&mut a_level.iter(), &mut b_level.iter())
+        };
+
+        if found.is_some() {
+            return found;
+        }
+    }
+
+    None
+}
+
+// Each char with its (first non-zero) primary weight, in collation order. This makes it easy to
+// see or diff how an alphabet is ordered under different options. A char with no primary weight
+// (e.g., punctuation when shifting) is reported with 0
+pub fn primary_order_report(chars: &[char], opt: CollationOptions) -> Vec<(char, u16)> {
+    let mut report: Vec<(char, u16)> = chars
+        .iter()
+        .map(|c| {
+            let primary = collation_elements(c.encode_utf8(&mut [0; 4]), opt)
+                .iter()
+                .map(|elem| elem[0])
+                .find(|p| *p != 0)
+                .unwrap_or(0);
+
+            (*c, primary)
+        })
+        .collect();
+
+    report.sort_by(|(a, _), (b, _)| {
+        collate(a.encode_utf8(&mut [0; 4]), b.encode_utf8(&mut [0; 4]), opt)
+    });
+
+    report
+}
+
+// The pairs of chars in the sample (in sample order) that the tailored table orders differently
+// from the base table, with default options otherwise: one goes before the other in one table,
+// and after it, or ties with it, in the other. A tailoring that only moves letters relative to
+// things outside the sample doesn't show up, so the sample should cover what it's meant to touch
+pub fn tailoring_diff(
+    table: &CollationTable,
+    base: KeysSource,
+    sample: &[char],
+) -> Vec<(char, char)> {
+    let opt = CollationOptions {
+        keys_source: base,
+        ..Default::default()
+    };
+
+    let nfd: Vec<Vec<u32>> = sample
+        .iter()
+        .map(|c| get_nfd(c.encode_utf8(&mut [0; 4]), opt))
+        .collect();
+
+    let compare = |i: usize, j: usize, table: &CollationTable| {
+        collate_nfd(&mut nfd[i].clone(), &mut nfd[j].clone(), table, opt)
+    };
+
+    let mut pairs = Vec::new();
+
+    for i in 0..sample.len() {
+        for j in i + 1..sample.len() {
+            if compare(i, j, table) != compare(i, j, builtin_table(base)) {
+                pairs.push((sample[i], sample[j]));
+            }
+        }
+    }
+
+    pairs
+}
+
+// Just the non-zero primary weights, in order; i.e., the first level of the sort key, without the
+// separator
+pub fn primary_weights(input: &str, opt: CollationOptions) -> Vec<u16> {
+    let mut nfd = get_nfd(input, opt);
+    let cea = get_cea(&mut nfd, 0, builtin_table(opt.keys_source), opt, None);
+
+    cea.iter().map(|elem| elem[0]).filter(|&p| p != 0).collect()
+}
+
+// Byte bounds [lo, hi) for a range scan over keys from SortKey::as_bytes (at any strength, with
+// the same options), matching the strings that start with the prefix at the primary level: "caf"
+// matches "café", "Cafe" and "cafeteria". Both are just the prefix's primaries, with the last one
+// incremented for hi. If the prefix has no primaries, every key matches, and hi is empty, meaning
+// no upper bound. The match is by collation elements, so a prefix that ends partway through a
+// contraction (e.g., "c" for Czech "ch") won't match strings that continue it
+pub fn prefix_range(prefix: &str, opt: CollationOptions) -> (Vec<u8>, Vec<u8>) {
+    let primaries = primary_weights(prefix, opt);
+    let lo: Vec<u8> = primaries.iter().flat_map(|w| w.to_be_bytes()).collect();
+
+    let mut upper = primaries;
+    while let Some(last) = upper.pop() {
+        if last < u16::MAX {
+            upper.push(last + 1);
+            break;
+        }
+    }
+
+    let hi = upper.iter().flat_map(|w| w.to_be_bytes()).collect();
+
+    (lo, hi)
+}
+
+// The letter under which the string goes in an A-Z style index: its first primary weight, mapped
+// back to a representative char, usually the uppercase base letter (e.g., "Ångström" goes under
+// 'A'). If the table gives the whole accented letter a primary of its own, as a Swedish tailoring
+// would for å, that letter is the representative instead ('Å'). Only the first combining sequence
+// with a primary is considered, so contractions spanning two starters (e.g., Czech "ch") go under
+// their first letter. None if nothing in the string has a primary (e.g., only punctuation when
+// shifting)
+pub fn index_bucket(input: &str, opt: CollationOptions) -> Option<char> {
+    index_bucket_with_table(input, builtin_table(opt.keys_source), opt)
+}
+
+// Same as above, but with weights from the given table; opt.keys_source is ignored
+pub fn index_bucket_with_table(
+    input: &str,
+    table: &CollationTable,
+    opt: CollationOptions,
+) -> Option<char> {
+    let input = strip_leading_article(input, opt.ignore_leading_articles);
+
+    let first_primary = |s: &str| {
+        let mut nfd = get_nfd(s, opt);
+        get_cea(&mut nfd, 0, table, opt, None)
+            .iter()
+            .map(|elem| elem[0])
+            .find(|p| *p != 0)
+    };
+
+    // Where each combining sequence (a starter, and any non-starters after it) begins
+    let mut starts: Vec<usize> = input
+        .char_indices()
+        .filter(|(i, c)| *i == 0 || get_ccc(*c) as u8 == 0)
+        .map(|(i, _)| i)
+        .collect();
+    starts.push(input.len());
+
+    for bounds in starts.windows(2) {
+        let sequence = &input[bounds[0]..bounds[1]];
+
+        let Some(primary) = first_primary(sequence) else {
+            continue;
+        };
+
+        let base = UnicodeNormalization::nfd(sequence).next()?;
+
+        let mut composed = UnicodeNormalization::nfc(sequence);
+        let composed = match (composed.next(), composed.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        };
+
+        // The base letter if it has the same primary; otherwise the whole (composed) sequence
+        for c in [Some(base), composed].into_iter().flatten() {
+            // Where uppercasing gives more than one char (e.g., ß to SS), the first may still do
+            let upper = c.to_uppercase().next().unwrap_or(c);
+
+            for candidate in [upper, c] {
+                if first_primary(candidate.encode_utf8(&mut [0; 4])) == Some(primary) {
+                    return Some(candidate);
+                }
+            }
+        }
+
+        return Some(base);
+    }
+
+    None
+}
+
+// Every match of the needle in the haystack at the primary level (so ignoring case and accents),
+// as byte ranges, left to right and not overlapping. Matches start and end between combining
+// sequences, so an accent goes with its base letter; and each is as short as possible, so it
+// doesn't pick up ignorables on either side
+pub fn find_all(haystack: &str, needle: &str, opt: CollationOptions) -> Vec<Range<usize>> {
+    let opt = CollationOptions {
+        strength: CollationStrength::Primary,
+        ..opt
     };
 
-    if INCLUDED_UNASSIGNED.contains(&left_val) {
-        bbbb = left_val & 32_767;
+    let target = primary_weights(needle, opt);
+    if target.is_empty() {
+        return Vec::new();
+    }
+
+    let boundaries: Vec<usize> = haystack
+        .char_indices()
+        .filter(|(_, c)| get_ccc_u32(*c as u32) == 0)
+        .map(|(i, _)| i)
+        .chain([haystack.len()])
+        .collect();
+
+    let mut matches = Vec::new();
+    let mut s = 0;
+
+    'starts: while s + 1 < boundaries.len() {
+        let start = boundaries[s];
+
+        for (e, &end) in boundaries.iter().enumerate().skip(s + 1) {
+            let weights = primary_weights(&haystack[start..end], opt);
+
+            // Don't start on something ignorable
+            if weights.is_empty() {
+                break;
+            }
+
+            if weights == target {
+                matches.push(start..end);
+                s = e;
+                continue 'starts;
+            }
+
+            if !target.starts_with(&weights) {
+                break;
+            }
+        }
+
+        s += 1;
+    }
+
+    matches
+}
+
+// Whether collation orders two strings differently than their code points would
+pub fn disagrees_with_codepoint_order(str_a: &str, str_b: &str, opt: CollationOptions) -> bool {
+    collate(str_a, str_b, opt) != str_a.chars().cmp(str_b.chars())
+}
+
+// A rough measure of how alike two strings are, for ranking: the number of leading collation
+// elements they share, over the element count of the longer one. 1.0 means equal (at the given
+// strength), and 0.0 means they differ from the first element. This is a heuristic, not a metric;
+// e.g., it knows nothing of edit distance, so "xcafe" scores 0.0 against "cafe"
+pub fn collation_similarity(str_a: &str, str_b: &str, opt: CollationOptions) -> f64 {
+    let max_level = max_level(opt);
+
+    // Only what's compared at this strength, and without ignorables
+    let elements = |s: &str| -> Vec<ArrayVec<[u16; 4]>> {
+        collation_elements(s, opt)
+            .into_iter()
+            .map(|mut elem| {
+                elem.truncate(max_level);
+                elem
+            })
+            .filter(|elem| elem.iter().any(|w| *w != 0))
+            .collect()
+    };
+
+    let a = elements(str_a);
+    let b = elements(str_b);
+
+    let longer = a.len().max(b.len());
+    if longer == 0 {
+        return 1.0;
+    }
+
+    let common = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+
+    common as f64 / longer as f64
+}
+
+// Removes adjacent strings that are equal at the given strength, keeping the first of each run.
+// As with Vec::dedup, this only catches all duplicates if the vec is already sorted (with the
+// same options)
+pub fn dedup_by_collation(v: &mut Vec<String>, strength: CollationStrength, opt: CollationOptions) {
+    let opt = CollationOptions { strength, ..opt };
+
+    v.dedup_by(|b, a| collate_no_tiebreak(a, b, opt) == Ordering::Equal);
+}
+
+// Indices of the strings that are equal at the given strength, grouped; in order of first
+// appearance, both the groups and the indices within them. Each string's sort key is computed
+// once, and nothing needs to be sorted first
+pub fn group_by_collation(
+    items: &[String],
+    strength: CollationStrength,
+    opt: CollationOptions,
+) -> Vec<Vec<usize>> {
+    let opt = CollationOptions { strength, ..opt };
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut by_key: HashMap<SortKey, usize> = HashMap::new();
+
+    for (i, item) in items.iter().enumerate() {
+        let group = *by_key.entry(sort_key(item, opt)).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+
+        groups[group].push(i);
+    }
+
+    groups
+}
+
+// A map's entries in collation order of their keys, as collate would sort them (with the same
+// tiebreaker), computing each key's sort key only once. With TiebreakMode::None, the order of
+// keys that tie is arbitrary, as the map's is
+pub fn sorted_entries<'a, V>(
+    map: &'a HashMap<String, V>,
+    opt: CollationOptions,
+) -> Vec<(&'a String, &'a V)> {
+    let mut keyed: Vec<(SortKey, (&String, &V))> = map
+        .iter()
+        .map(|(k, v)| (sort_key(k, opt), (k, v)))
+        .collect();
+
+    keyed.sort_unstable_by(|(a_key, (a, _)), (b_key, (b, _))| {
+        a_key.cmp(b_key).then_with(|| tiebreak(a, b, opt.tiebreak))
+    });
+
+    keyed.into_iter().map(|(_, entry)| entry).collect()
+}
+
+// Reads lines, sorts them as collate would (with the same tiebreaker), and writes them out, each
+// ending in \n (a \r\n ending is read as a line break too). The sort happens in memory: every
+// line is held at once, along with its sort key, which takes about 8 bytes per char of the line
+// (more for chars that expand). For more than fits, sort chunks of lines separately, and combine
+// the chunks' keys with merge_sorted_keys. Lines that tie with TiebreakMode::None keep their
+// input order. Input that isn't UTF-8 is an InvalidData error
+pub fn sort_lines<R: BufRead, W: Write>(
+    reader: R,
+    mut writer: W,
+    opt: CollationOptions,
+) -> io::Result<()> {
+    let mut keyed: Vec<(SortKey, String)> = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        keyed.push((sort_key(&line, opt), line));
+    }
+
+    keyed.sort_by(|(a_key, a), (b_key, b)| {
+        a_key.cmp(b_key).then_with(|| tiebreak(a, b, opt.tiebreak))
+    });
+
+    for (_, line) in keyed {
+        writeln!(writer, "{line}")?;
+    }
+
+    writer.flush()
+}
+
+// Replaces each code point with its full canonical decomposition. Together with
+// canonical_reorder, this gives NFD. Code points that aren't valid chars are left alone
+pub fn decompose(input: &mut Vec<u32>) {
+    let mut output: Vec<u32> = Vec::with_capacity(input.len());
+
+    for code_point in input.drain(..) {
+        if (S_BASE..=S_LAST).contains(&code_point) {
+            output.extend(decompose_jamo(code_point));
+        } else if let Some(rep) = DECOMP.get(&code_point) {
+            output.extend(rep);
+        } else {
+            output.push(code_point);
+        }
+    }
+
+    *input = output;
+}
+
+// Sorts each run of non-starters by combining class (stably, so marks of the same class keep
+// their order). Applied after decompose, this gives NFD
+pub fn canonical_reorder(input: &mut [u32]) {
+    for run in input.split_mut(|cp| get_ccc_u32(*cp) == 0) {
+        if run.len() > 1 {
+            run.sort_by_key(|cp| get_ccc_u32(*cp));
+        }
+    }
+}
+
+// Checks that a string is already in NFD, e.g., in a debug assertion on input that's meant to be.
+// Err has the byte offset of the first char that decomposes, or that's out of canonical order
+// with the one before it
+pub fn assert_nfd(s: &str) -> Result<(), usize> {
+    let mut prev_ccc = 0;
+
+    for (i, c) in s.char_indices() {
+        let mut decomposed = vec![c as u32];
+        decompose(&mut decomposed);
+
+        let ccc = get_ccc(c) as u8;
+
+        if decomposed != [c as u32] || (ccc != 0 && prev_ccc > ccc) {
+            return Err(i);
+        }
+
+        prev_ccc = ccc;
+    }
+
+    Ok(())
+}
+
+//
+// Functions, private
+//
+
+// Hangul syllables aren't in the tables: their weights are those of the jamo they decompose to.
+// That's just arithmetic, and since the syllables and jamo are all starters, it doesn't take full
+// normalization; FCD input stays FCD
+fn decompose_hangul(input: impl Iterator<Item = u32>) -> Vec<u32> {
+    let mut output: Vec<u32> = Vec::with_capacity(input.size_hint().0);
+
+    for code_point in input {
+        if (S_BASE..=S_LAST).contains(&code_point) {
+            output.extend(decompose_jamo(code_point));
+        } else {
+            output.push(code_point);
+        }
+    }
+
+    output
+}
+
+fn decompose_jamo(s: u32) -> Vec<u32> {
+    let s_index = s - S_BASE;
+
+    let lv = JAMO.get(&s).is_some();
+
+    if lv {
+        let l_index = s_index / N_COUNT;
+        let v_index = (s_index % N_COUNT) / T_COUNT;
+
+        let l_part = L_BASE + l_index;
+        let v_part = V_BASE + v_index;
+
+        vec![l_part, v_part]
+    } else {
+        let l_index = s_index / N_COUNT;
+        let v_index = (s_index % N_COUNT) / T_COUNT;
+        let t_index = s_index % T_COUNT;
+
+        let l_part = L_BASE + l_index;
+        let v_part = V_BASE + v_index;
+        let t_part = T_BASE + t_index;
+
+        vec![l_part, v_part, t_part]
+    }
+}
+
+fn collate_nfd(
+    a_nfd: &mut [u32],
+    b_nfd: &mut [u32],
+    table: &CollationTable,
+    opt: CollationOptions,
+) -> Ordering {
+    // Slightly less early out (but no tiebreaker)
+    if a_nfd == b_nfd {
+        return Ordering::Equal;
+    }
+
+    // Skip shared prefix if possible
+    // Backwards secondaries (or tertiaries) end with those of the prefix, so it can't be skipped
+    let offset = if opt.trim_prefix && !opt.backwards_secondary && !opt.backwards_tertiary {
+        trim_prefix(a_nfd, b_nfd, table, opt)
+    } else {
+        0
+    };
+
+    // Discontiguous matches move code points around, so this has to come first
+    let code_points =
+        code_point_level(opt).then(|| (full_nfd(&a_nfd[offset..]), full_nfd(&b_nfd[offset..])));
+
+    // Generate collation element arrays... this is where things get expensive
+    let a_cea = get_cea(a_nfd, offset, table, opt, None);
+    let b_cea = get_cea(b_nfd, offset, table, opt, None);
+
+    // Compare level by level, without building sort keys
+    compare_ceas(&a_cea, &b_cea, opt)
+        .then_with(|| code_points.map_or(Ordering::Equal, |(a, b)| a.cmp(&b)))
+}
+
+fn decode_utf16(input: &[u16]) -> Vec<u32> {
+    char::decode_utf16(input.iter().copied())
+        .map(|r| r.map_or_else(|e| u32::from(e.unpaired_surrogate()), |c| c as u32))
+        .collect()
+}
+
+// Code points that aren't valid chars (i.e., surrogates) are treated as starters
+fn get_ccc_u32(code_point: u32) -> u8 {
+    char::from_u32(code_point).map_or(0, |c| get_ccc(c) as u8)
+}
+
+fn get_nfd(input: &str, opt: CollationOptions) -> Vec<u32> {
+    let input = strip_leading_article(input, opt.ignore_leading_articles);
+    let folded: String;
+
+    let input = if opt.case_fold {
+        folded = input.chars().flat_map(fold_case).collect();
+        &folded
+    } else {
+        input
+    };
+
+    let nfd: Vec<u32> = if opt.normalization == NormalizationForm::Nfkd {
+        UnicodeNormalization::nfkd(input)
+            .map(|c| c as u32)
+            .collect()
+    } else if opt.ignore_marks.is_none()
+        && opt.weight_overrides.is_none()
+        && fcd(input.chars().map(|c| c as u32))
+    {
+        decompose_hangul(input.chars().map(|c| c as u32))
+    } else if cfg!(feature = "native-nfd") {
+        let mut nfd: Vec<u32> = input.chars().map(|c| c as u32).collect();
+        decompose(&mut nfd);
+        canonical_reorder(&mut nfd);
+        nfd
+    } else {
+        UnicodeNormalization::nfd(input).map(|c| c as u32).collect()
+    };
+
+    debug_assert!(
+        canonically_ordered(&nfd),
+        "not in canonical order: {nfd:X?}"
+    );
+
+    nfd
+}
+
+// The first of the articles that the input starts with, and anything after it up to the next word,
+// is dropped (see CollationOptions::ignore_leading_articles)
+fn strip_leading_article<'s>(input: &'s str, articles: &[&str]) -> &'s str {
+    for article in articles {
+        let mut chars = input.chars();
+
+        let matched = article.chars().all(|a| {
+            chars
+                .next()
+                .is_some_and(|c| c.to_lowercase().eq(a.to_lowercase()))
+        });
+
+        if !matched {
+            continue;
+        }
+
+        let rest = chars.as_str();
+        let elided = article.ends_with(['\'', '\u{2019}']);
+
+        if elided || rest.starts_with(char::is_whitespace) {
+            let rest = rest.trim_start();
+
+            if !rest.is_empty() {
+                return rest;
+            }
+        }
+    }
+
+    input
+}
+
+// Always decomposes, and keeps track of where each code point came from in the input (including
+// any leading article that's been stripped)
+fn get_nfd_with_offsets(input: &str, opt: CollationOptions) -> (Vec<u32>, Vec<usize>) {
+    let stripped = strip_leading_article(input, opt.ignore_leading_articles);
+    let start = input.len() - stripped.len();
+
+    let mut nfd: Vec<u32> = Vec::new();
+    let mut offsets: Vec<usize> = Vec::new();
+
+    let decompose = |c: char, push: &mut dyn FnMut(char)| match opt.normalization {
+        NormalizationForm::Nfd => decompose_canonical(c, push),
+        NormalizationForm::Nfkd => decompose_compatible(c, push),
+    };
+
+    for (i, c) in stripped.char_indices() {
+        let mut push = |d: char| {
+            nfd.push(d as u32);
+            offsets.push(start + i);
+        };
+
+        if opt.case_fold {
+            for f in fold_case(c) {
+                decompose(f, &mut push);
+            }
+        } else {
+            decompose(c, &mut push);
+        }
+    }
+
+    // Canonical reordering (a stable insertion sort of each run of non-starters)
+    for i in 1..nfd.len() {
+        let mut j = i;
+
+        while j > 0 {
+            let ccc_a = get_ccc_u32(nfd[j - 1]);
+            let ccc_b = get_ccc_u32(nfd[j]);
+
+            if ccc_b == 0 || ccc_a <= ccc_b {
+                break;
+            }
+
+            nfd.swap(j - 1, j);
+            offsets.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+
+    (nfd, offsets)
+}
+
+// Same as above, but the input may contain surrogates. Those have no decomposition and act as
+// starters, so the runs of valid chars around them can be normalized separately
+fn get_nfd_code_points(input: &[u32], opt: CollationOptions) -> Vec<u32> {
+    let folded: Vec<u32>;
+
+    let input = if opt.case_fold {
+        folded = input
+            .iter()
+            .flat_map(|cp| match char::from_u32(*cp) {
+                Some(c) => fold_case(c).map(|f| f as u32).collect(),
+                None => vec![*cp],
+            })
+            .collect();
+        &folded
+    } else {
+        input
+    };
+
+    let nfkd = opt.normalization == NormalizationForm::Nfkd;
+
+    if !nfkd
+        && opt.ignore_marks.is_none()
+        && opt.weight_overrides.is_none()
+        && fcd(input.iter().copied())
+    {
+        debug_assert!(
+            canonically_ordered(input),
+            "not in canonical order: {input:X?}"
+        );
+        return decompose_hangul(input.iter().copied());
+    }
+
+    // Surrogates are just starters with no decomposition here
+    if !nfkd && cfg!(feature = "native-nfd") {
+        let mut nfd = input.to_vec();
+        decompose(&mut nfd);
+        canonical_reorder(&mut nfd);
+        return nfd;
+    }
+
+    let mut nfd: Vec<u32> = Vec::with_capacity(input.len());
+    let mut run: Vec<char> = Vec::new();
+
+    let normalize = |run: &mut Vec<char>, nfd: &mut Vec<u32>| {
+        if nfkd {
+            nfd.extend(run.drain(..).nfkd().map(|c| c as u32));
+        } else {
+            nfd.extend(run.drain(..).nfd().map(|c| c as u32));
+        }
+    };
+
+    for code_point in input {
+        if let Some(c) = char::from_u32(*code_point) {
+            run.push(c);
+        } else {
+            normalize(&mut run, &mut nfd);
+            nfd.push(*code_point);
+        }
+    }
+
+    normalize(&mut run, &mut nfd);
+
+    debug_assert!(
+        canonically_ordered(&nfd),
+        "not in canonical order: {nfd:X?}"
+    );
+
+    nfd
+}
+
+// No non-starter is preceded by one with a higher combining class. This holds for NFD, and for
+// the FCD input that we pass through as-is
+fn canonically_ordered(code_points: &[u32]) -> bool {
+    code_points.windows(2).all(|w| {
+        let ccc_b = get_ccc_u32(w[1]);
+        ccc_b == 0 || get_ccc_u32(w[0]) <= ccc_b
+    })
+}
+
+// Full case folding, from the standard library's case mappings: lowercase, then uppercase, then
+// lowercase again. This gets, e.g., ß and ẞ to "ss"; İ to "i̇"; and ς to σ. Dotless ı has no
+// folding, so it's left alone. (The one known gap is Cherokee, which folds to uppercase; here it
+// ends up lowercase, but consistently so)
+fn fold_case(c: char) -> impl Iterator<Item = char> {
+    let keep = c == 'ı';
+
+    c.to_lowercase()
+        .flat_map(char::to_uppercase)
+        .flat_map(char::to_lowercase)
+        .filter(move |_| !keep)
+        .chain(keep.then_some(c))
+}
+
+// Whether the input is FCD, so that it collates the same as its NFD (once any Hangul syllables are
+// decomposed; see decompose_hangul). The Tibetan vowel signs above are the exception: strictly,
+// they're FCD, but they still need decomposing
+fn fcd(input: impl Iterator<Item = u32>) -> bool {
+    let mut c_as_u32: u32;
+    let mut curr_lead_cc: u8;
+    let mut curr_trail_cc: u8;
+
+    let mut prev_trail_cc: u8 = 0;
+
+    for c in input {
+        c_as_u32 = c;
+
+        if c_as_u32 < 192 {
+            prev_trail_cc = 0;
+            continue;
+        }
+
+        if TIBETAN_DECOMPOSABLE.contains(&c_as_u32) {
+            return false;
+        }
+
+        if let Some(vals) = FCD.get(&c_as_u32) {
+            [curr_lead_cc, curr_trail_cc] = vals.to_be_bytes();
+        } else {
+            curr_lead_cc = get_ccc_u32(c);
+            curr_trail_cc = curr_lead_cc;
+        }
+
+        if curr_lead_cc != 0 && curr_lead_cc < prev_trail_cc {
+            return false;
+        }
+
+        prev_trail_cc = curr_trail_cc;
+    }
+
+    true
+}
+
+// Returns the length of the shared prefix that can be skipped when generating sort keys. Rather
+// than draining it from the vecs (which shifts everything after it), we pass it along as an offset
+fn trim_prefix(a: &[u32], b: &[u32], table: &CollationTable, opt: CollationOptions) -> usize {
+    let prefix_len = find_prefix(a, b, table);
+
+    // Test final code point in prefix; bail if bad
+    if prefix_len > 0 && !ends_variable_run(a[prefix_len - 1], table, opt) {
+        return 0;
+    }
+
+    prefix_len
+}
+
+// The primaries that count as variable with the max_variable option, from the lowest (a space) up
+// to the end of the chosen group. None to go by the table's flags
+fn variable_override(table: &CollationTable, opt: CollationOptions) -> Option<(u16, u16)> {
+    let group = match opt.max_variable? {
+        MaxVariable::Space => ReorderGroup::Space,
+        MaxVariable::Punct => ReorderGroup::Punctuation,
+        MaxVariable::Symbol => ReorderGroup::Symbol,
+        MaxVariable::Currency => ReorderGroup::Currency,
+    };
+
+    let lo = *table.reorder_range(ReorderGroup::Space)?.start();
+    let hi = *table.reorder_range(group)?.end();
+
+    Some((lo, hi))
+}
+
+fn weighting(opt: CollationOptions) -> Weighting {
+    match (opt.shifting, opt.strength) {
+        (false, _) => Weighting::NonIgnorable,
+        (true, CollationStrength::Primary) => Weighting::ShiftedPrimary,
+        (true, _) => Weighting::Shifted,
+    }
+}
+
+fn with_variable(weights: &Weights, (lo, hi): (u16, u16)) -> Weights {
+    Weights {
+        variable: (lo..=hi).contains(&weights.primary),
+        ..*weights
+    }
+}
+
+// Whether a code point's collation elements all have a primary weight and aren't variable, so
+// that whatever follows it is weighted the same as at the start of a string (under shifting, an
+// ignorable after a variable loses its weights)
+fn ends_variable_run(code_point: u32, table: &CollationTable, opt: CollationOptions) -> bool {
+    // An ignored code point has no primary, whatever the table says
+    if opt.ignore_above.is_some_and(|max| code_point > max)
+        || (opt.ignore_default_ignorables && default_ignorable(code_point))
+        || opt
+            .ignore_marks
+            .is_some_and(|marks| marks.contains(&code_point))
+    {
+        return false;
+    }
+
+    let row = opt
+        .weight_overrides
+        .and_then(|overrides| overrides.get(&code_point))
+        .or_else(|| table.singles.get(&code_point));
+
+    let variable = variable_override(table, opt);
+
+    let fine = |row: &[Weights]| {
+        row.iter().all(|weights| {
+            let weights = variable.map_or(*weights, |range| with_variable(weights, range));
+            !weights.variable && weights.primary != 0
+        })
+    };
+
+    match row {
+        Some(row) => fine(row),
+        // Anything not in the table gets implicit weights, which are fine, unless overridden
+        None => opt
+            .implicit_override
+            .and_then(|o| (o.0)(code_point))
+            .is_none_or(|row| fine(&row)),
+    }
+}
+
+fn find_prefix(a: &[u32], b: &[u32], table: &CollationTable) -> usize {
+    a.iter()
+        .zip(b)
+        .take_while(|(x, y)| x == y && !table.starts_contraction(**x))
+        .count()
+}
+
+#[cfg(not(feature = "compressed-tables"))]
+fn deserialize_table<T: DeserializeOwned>(data: &[u8]) -> T {
+    bincode::deserialize(data).unwrap()
+}
+
+#[cfg(feature = "compressed-tables")]
+fn deserialize_table<T: DeserializeOwned>(data: &[u8]) -> T {
+    use std::io::Read;
+
+    let mut bytes = Vec::new();
+    flate2::read::GzDecoder::new(data)
+        .read_to_end(&mut bytes)
+        .unwrap();
+
+    bincode::deserialize(&bytes).unwrap()
+}
+
+// (0, 0) if there are no variable elements at all
+fn find_variable_range<'a>(rows: impl Iterator<Item = &'a Vec<Weights>>) -> (u16, u16) {
+    rows.flatten()
+        .filter(|w| w.variable)
+        .fold(None, |range, w| match range {
+            None => Some((w.primary, w.primary)),
+            Some((lo, hi)) => Some((w.primary.min(lo), w.primary.max(hi))),
+        })
+        .unwrap_or((0, 0))
+}
+
+fn builtin_table(keys_source: KeysSource) -> &'static CollationTable {
+    match keys_source {
+        KeysSource::Cldr => &CLDR,
+        KeysSource::Ducet => &DUCET,
+    }
+}
+
+fn nfd_to_sk(nfd: &mut [u32], offset: usize, opt: CollationOptions) -> Vec<u16> {
+    let mut sort_key = Vec::new();
+    push_nfd_sk(&mut sort_key, nfd, offset, opt);
+    sort_key
+}
+
+fn push_nfd_sk(sort_key: &mut Vec<u16>, nfd: &mut [u32], offset: usize, opt: CollationOptions) {
+    let code_points = code_point_level(opt).then(|| full_nfd(&nfd[offset..]));
+
+    let collation_element_array = get_cea(nfd, offset, builtin_table(opt.keys_source), opt, None);
+    push_sort_key(sort_key, &collation_element_array, opt);
+
+    if let Some(code_points) = code_points {
+        push_code_point_level(sort_key, &code_points);
+    }
+}
+
+// Each code point as two weights, neither of them 0, so that the keys still order by code point
+fn push_code_point_level(sort_key: &mut Vec<u16>, code_points: &[u32]) {
+    sort_key.push(0);
+
+    #[allow(clippy::cast_possible_truncation)]
+    sort_key.extend(
+        code_points
+            .iter()
+            .flat_map(|cp| [(cp >> 15) as u16 + 1, (cp & 0x7FFF) as u16 + 1]),
+    );
+}
+
+// For the code point level. The input may only be FCD (and after a shared prefix, which is left
+// out, there's nothing to reorder across)
+fn full_nfd(code_points: &[u32]) -> Vec<u32> {
+    let mut nfd = code_points.to_vec();
+
+    decompose(&mut nfd);
+    canonical_reorder(&mut nfd);
+
+    nfd
+}
+
+// The number of levels taken from the collation elements
+fn max_level(opt: CollationOptions) -> usize {
+    let levels = if opt.shifting && opt.quaternary == QuaternarySource::Shifted {
+        4
+    } else {
+        3
+    };
+
+    levels.min(opt.strength as usize)
+}
+
+// Whether the weights of a level (counting from 0) go from the end of the string
+fn backwards_level(level: usize, opt: CollationOptions) -> bool {
+    match level {
+        1 => opt.backwards_secondary,
+        2 => opt.backwards_tertiary,
+        _ => false,
+    }
+}
+
+// Whether there's a fourth level of code points after those
+fn code_point_level(opt: CollationOptions) -> bool {
+    opt.quaternary == QuaternarySource::CodePoints && opt.strength == CollationStrength::Quaternary
+}
+
+// The strength of the levels that a sort key actually has: quaternary strength without a fourth
+// level (e.g., when not shifting) gives only three, the same as tertiary. A key decoded from hex or
+// base32 gets its strength from the number of levels, so it has to be recorded this way
+fn key_strength(opt: CollationOptions) -> CollationStrength {
+    if max_level(opt) == 3 && !code_point_level(opt) {
+        CollationStrength::Tertiary
+    } else {
+        opt.strength
+    }
+}
+
+fn get_sort_key(collation_element_array: &[ArrayVec<[u16; 4]>], opt: CollationOptions) -> Vec<u16> {
+    let mut sort_key = Vec::new();
+    push_sort_key(&mut sort_key, collation_element_array, opt);
+    sort_key
+}
+
+fn push_sort_key(
+    sort_key: &mut Vec<u16>,
+    collation_element_array: &[ArrayVec<[u16; 4]>],
+    opt: CollationOptions,
+) {
+    for i in 0..max_level(opt) {
+        if i > 0 {
+            sort_key.push(0);
+        }
+
+        let weights = collation_element_array
+            .iter()
+            .map(|elem| elem[i])
+            .filter(|w| *w != 0);
+
+        if backwards_level(i, opt) {
+            push_level(sort_key, weights.rev(), i, opt);
+        } else {
+            push_level(sort_key, weights, i, opt);
+        }
+    }
+}
+
+fn push_level(
+    sort_key: &mut Vec<u16>,
+    weights: impl Iterator<Item = u16>,
+    level: usize,
+    opt: CollationOptions,
+) {
+    if level == 1 && opt.compress_secondaries {
+        push_compressed_secondaries(sort_key, weights);
+    } else {
+        sort_key.extend(weights);
+    }
+}
+
+// Every secondary weight in the tables is at least the common weight, which leaves 1..=0x20 free
+// to encode runs of it. A run followed by a higher weight becomes 32 - n (for a run of n), so that
+// longer runs sort lower, as they would have; a run at the end of the level becomes n, so that
+// longer runs sort higher. Either way, each full 16 of a long run becomes 16. All of these codes
+// sort below any real weight, and above the level separator
+fn push_compressed_secondaries(sort_key: &mut Vec<u16>, weights: impl Iterator<Item = u16>) {
+    let push_run = |sort_key: &mut Vec<u16>, mut run: u16, at_end: bool| {
+        while run >= SECONDARY_RUN_MAX {
+            sort_key.push(SECONDARY_RUN_MAX);
+            run -= SECONDARY_RUN_MAX;
+        }
+
+        if run > 0 {
+            sort_key.push(if at_end {
+                run
+            } else {
+                2 * SECONDARY_RUN_MAX - run
+            });
+        }
+    };
+
+    let mut run = 0;
+
+    for weight in weights {
+        debug_assert!(
+            weight >= COMMON_SECONDARY,
+            "secondary below common: {weight:X}"
+        );
+
+        if weight == COMMON_SECONDARY {
+            run += 1;
+            continue;
+        }
+
+        push_run(sort_key, run, false);
+        run = 0;
+
+        sort_key.push(weight);
+    }
+
+    push_run(sort_key, run, true);
+}
+
+fn get_traced_cea(input: &str, opt: CollationOptions) -> (Vec<ArrayVec<[u16; 4]>>, Vec<usize>) {
+    let (mut nfd, offsets) = get_nfd_with_offsets(input, opt);
+
+    let mut trace = CeaTrace {
+        offsets,
+        origins: Vec::new(),
+        current: 0,
+    };
+
+    let cea = get_cea(
+        &mut nfd,
+        0,
+        builtin_table(opt.keys_source),
+        opt,
+        Some(&mut trace),
+    );
+
+    (cea, trace.origins)
+}
+
+// The input has already been normalized (or found to be FCD) before any table lookup, so
+// contraction matching always happens after decomposition. FCD input can still contain
+// precomposed characters, but the tables include the canonical closure of every contraction,
+// so a precomposed starter and its decomposition get the same weights
+fn get_cea(
+    char_vals: &mut [u32],
+    offset: usize,
+    table: &CollationTable,
+    opt: CollationOptions,
+    mut trace: Option<&mut CeaTrace>,
+) -> Vec<ArrayVec<[u16; 4]>> {
+    let mut cea: Vec<ArrayVec<[u16; 4]>> = Vec::new();
+
+    let cldr = table.source == KeysSource::Cldr;
+
+    let weighting = weighting(opt);
+
+    // Only full shifting needs a fourth weight
+    let shifting = weighting == Weighting::Shifted;
+
+    let variable = variable_override(table, opt);
+
+    let singles = &table.singles;
+
+    let mut left: usize = offset;
+
+    // Where the current run of variable elements (and ignorables after them) started, if the
+    // array ends in one
+    let mut variable_run: Option<usize> = None;
+
+    let mut last_left: Option<usize> = None;
+
+    'outer: while left < char_vals.len() {
+        let left_val = char_vals[left];
+
+        // Every iteration has to consume at least one code point
+        debug_assert!(last_left < Some(left), "get_cea stalled at {left}");
+        last_left = Some(left);
+
+        // Everything pushed in the previous iteration came from the previous position
+        if let Some(t) = &mut trace {
+            t.fill(cea.len());
+            t.current = t.offsets[left];
+        }
+
+        if let Some(row) = opt.weight_overrides.and_then(|o| o.get(&left_val)) {
+            for weights in row {
+                push_weights(&mut cea, weights, weighting, variable, &mut variable_run);
+            }
+
+            left += 1;
+            continue;
+        }
+
+        if (opt.ignore_default_ignorables && default_ignorable(left_val))
+            || opt
+                .ignore_marks
+                .is_some_and(|marks| marks.contains(&left_val))
+        {
+            left += 1;
+            continue;
+        }
+
+        // None of these starts a contraction, or is in the low table
+        if opt.historic_folding {
+            if let Some((_, modern)) = HISTORIC_FOLDS.iter().find(|(cp, _)| *cp == left_val) {
+                let row = modern
+                    .chars()
+                    .filter_map(|c| table.singles.get(&(c as u32)))
+                    .flatten();
+
+                for weights in row {
+                    let weights = Weights {
+                        tertiary: weights.tertiary + VARIANT_TERTIARY_OFFSET,
+                        ..*weights
+                    };
+
+                    push_weights(&mut cea, &weights, weighting, variable, &mut variable_run);
+                }
+
+                left += 1;
+                continue;
+            }
+        }
+
+        // No whitespace starts a contraction, so this is all it takes
+        let left_val = if opt.unify_whitespace && whitespace(left_val) {
+            0x20
+        } else {
+            left_val
+        };
+
+        // Context mappings look at what's already been processed, including any shared prefix that
+        // was skipped
+        if !table.contexts.is_empty() {
+            let context_row = table.contexts.get(&left_val).and_then(|rows| {
+                rows.iter()
+                    .find(|(prefix, _)| char_vals[..left].ends_with(prefix))
+            });
+
+            if let Some((_, row)) = context_row {
+                for weights in row {
+                    push_weights(&mut cea, weights, weighting, variable, &mut variable_run);
+                }
+
+                left += 1;
+                continue;
+            }
+        }
+
+        // Fast path for code points that can't start a contraction
+        if let Some(Some(weights)) = table.low.get(left_val as usize) {
+            push_weights(&mut cea, weights, weighting, variable, &mut variable_run);
+
+            left += 1;
+            continue;
+        }
+
+        if opt.ignore_above.is_some_and(|max| left_val > max) {
+            left += 1;
+            continue;
+        }
+
+        if opt.variation_selectors {
+            if let Some(index) = variation_selector_index(left_val) {
+                let weights = Weights {
+                    tertiary: VS_TERTIARY + index,
+                    ..Weights::new()
+                };
+
+                push_weights(&mut cea, &weights, weighting, variable, &mut variable_run);
+
+                left += 1;
+                continue;
+            }
+        }
+
+        // The trie node for left_val, if it starts a contraction. A contiguous match goes no further
+        // than the longest one; beyond that, discontiguous matching looks at most two code points
+        // further (and only within a run of non-starters), so the work per step is bounded no
+        // matter how long a run of combining marks follows
+        let root = if char_vals.len() - left > 1 {
+            table.contractions.root(left_val)
+        } else {
+            None
+        };
+
+        // If left_val starts no contraction, or if this is the last item in the vec, take an easy
+        // path
+        if root.is_none() {
+            // Did we find it? Sure hope so
+            if let Some(row) = singles.get(&left_val) {
+                // Push weights to collation element array
+                for weights in row {
+                    push_weights(&mut cea, weights, weighting, variable, &mut variable_run);
+                }
+
+                // Increment and continue outer loop
+                left += 1;
+                continue 'outer;
+            }
+        }
+
+        // Next consider multis, if applicable
+        // If we just tried to find a single, and didn't find it, we should skip all the way down
+        // to the implicit weights section
+
+        // The trie gives the longest contraction that the input continues with, walking one code
+        // point at a time and stopping as soon as no longer one could match
+        let contiguous = root.and_then(|root| {
+            table
+                .contractions
+                .longest_match(root, &char_vals[left + 1..])
+                .map(|(len, row)| (root, len, row))
+        });
+
+        if let Some((root, len, row)) = contiguous {
+            let right = left + len;
+            let subset = &char_vals[left..right];
+
+            // We may need to check for discontiguous matches. But that's only if we matched a set
+            // of two code points; and we'll only skip over one more to find a possible third.
+            let mut try_discont = subset.len() == 2 && right + 1 < char_vals.len();
+
+            'inner: while try_discont {
+                // Need to make sure the sequence of CCCs is kosher
+                let ccc_a = get_ccc_u32(char_vals[right]);
+                let ccc_b = get_ccc_u32(char_vals[right + 1]);
+
+                if ccc_a == 0 || ccc_a >= ccc_b {
+                    // Bail -- no discontiguous match
+                    try_discont = false;
+                    continue 'inner;
+                }
+
+                // Having made it this far, we can test a new subset, adding the later char. This
+                // only happens when we've found an initial match of two code points and want to
+                // add a third; so we can be oddly specific.
+                let new_subset = [subset[1], char_vals[right + 1]];
+
+                // If the new subset is found in the table...
+                if let Some(new_value) = table.contractions.get(root, &new_subset) {
+                    // Then add these weights instead
+                    for weights in new_value {
+                        push_weights(&mut cea, weights, weighting, variable, &mut variable_run);
+                    }
+
+                    // Set aside the pulled char
+                    set_aside(char_vals, trace.as_deref_mut(), left, right + 1);
+
+                    // Increment (past what was set aside) and continue outer loop
+                    left = right + 1;
+                    continue 'outer;
+                }
+
+                // The loop will not run again
+                try_discont = false;
+            }
+
+            // At this point, we're not looking for a discontiguous match. We just need to push
+            // the weights from the original subset we found
+
+            for weights in row {
+                push_weights(&mut cea, weights, weighting, variable, &mut variable_run);
+            }
+
+            // Increment and continue outer loop
+            left = right;
+            continue 'outer;
+        }
+
+        // No contiguous contraction, so look for one code point, in the singles map
+        if let Some(root) = root {
+            if let Some(value) = singles.get(&left_val) {
+                let right = left + 1;
+
+                // If we found it, we do still need to check for discontiguous matches. Determine
+                // how much further right to look
+                let mut max_right = if right + 2 < char_vals.len() {
+                    right + 2
+                } else if right + 1 < char_vals.len() {
+                    right + 1
+                } else {
+                    // This should skip the loop below. There will be no discontiguous match
+                    right
+                };
+
+                let mut try_two = max_right - right == 2 && cldr;
+
+                'inner: while max_right > right {
+                    // Make sure the sequence of CCC values is kosher
+                    let interest_cohort = &char_vals[right..=max_right];
+                    let mut max_ccc = 0;
+
+                    for elem in interest_cohort {
+                        let ccc = get_ccc_u32(*elem);
+                        if ccc == 0 || ccc <= max_ccc {
+                            // Can also forget about try_two in this case
+                            try_two = false;
+                            max_right -= 1;
+                            continue 'inner;
+                        }
+                        max_ccc = ccc;
+                    }
+
+                    // Having made it this far, we test a new subset, adding the later char(s)
+                    let new_subset = if try_two {
+                        &char_vals[max_right - 1..=max_right]
+                    } else {
+                        &char_vals[max_right..=max_right]
+                    };
+
+                    // If the new subset (after left_val) is found in the table...
+                    if let Some(new_value) = table.contractions.get(root, new_subset) {
+                        // Then add these weights instead
+                        for weights in new_value {
+                            push_weights(&mut cea, weights, weighting, variable, &mut variable_run);
+                        }
+
+                        // Set aside the pulled char(s) (in this order!)
+                        set_aside(char_vals, trace.as_deref_mut(), left, max_right);
+                        if try_two {
+                            set_aside(char_vals, trace.as_deref_mut(), left + 1, max_right);
+                        }
+
+                        // Increment (past what was set aside) and continue outer loop
+                        left = right + if try_two { 2 } else { 1 };
+                        continue 'outer;
+                    }
+
+                    // If we tried for two, don't decrement max_right yet
+                    // Inner loop will run again
+                    if try_two {
+                        try_two = false;
+                    } else {
+                        // Otherwise decrement max_right; inner loop may or may not run again
+                        max_right -= 1;
+                    }
+                }
+
+                // At this point, we're not looking for a discontiguous match. We just need to push
+                // the weights we found above
+
+                for weights in value {
+                    push_weights(&mut cea, weights, weighting, variable, &mut variable_run);
+                }
+
+                // Increment and continue outer loop
+                left = right;
+                continue 'outer;
+            }
+        }
+
+        // By now, we're looking for just one value, and it isn't in the table
+        if let Some(row) = opt.implicit_override.and_then(|o| (o.0)(left_val)) {
+            for weights in &row {
+                push_weights(&mut cea, weights, weighting, variable, &mut variable_run);
+            }
+
+            left += 1;
+            continue;
+        }
+
+        // Time for implicit weights...
+
+        let first_weights = get_implicit_a(left_val, shifting);
+        cea.push(first_weights);
+
+        let second_weights = get_implicit_b(left_val, shifting);
+        cea.push(second_weights);
+
+        variable_run = None;
+
+        // Finally, increment and let outer loop continue
+        left += 1;
+    }
+
+    if opt.ignore_trailing_variable {
+        if let Some(start) = variable_run {
+            cea.truncate(start);
+        }
+    }
+
+    if let Some(t) = &mut trace {
+        t.fill(cea.len());
+    }
+
+    if !opt.primary_order.is_empty() {
+        apply_primary_order(&mut cea, opt.primary_order, singles);
+    }
+
+    if let Some((group, offset)) = opt.primary_offset {
+        apply_primary_offset(&mut cea, group, offset, table);
+    }
+
+    if !opt.reorder.is_empty() {
+        apply_reorder(&mut cea, opt.reorder, table);
+    }
+
+    // A skipped prefix always includes the first letter, and is the same on both sides
+    if opt.first_letter_case {
+        apply_first_letter_case(&mut cea, offset == 0);
+    }
+
+    if let Some(fold) = opt.fold_secondaries {
+        for elem in &mut cea {
+            if fold.contains(&elem[1]) {
+                elem[1] = COMMON_SECONDARY;
+            }
+        }
+    }
+
+    debug_assert!(
+        cea.iter().all(|elem| valid_element(elem, weighting)),
+        "malformed collation element array: {cea:X?}"
+    );
+
+    cea
+}
+
+// Takes a code point used in a discontiguous match out of play. Removing it from the vec would
+// shift everything after it, which makes a long string with many such matches quadratic. Instead
+// it's rotated back to the start of the match (which is behind us once we move on), so the cost
+// is bounded by the width of the match
+fn set_aside(char_vals: &mut [u32], trace: Option<&mut CeaTrace>, start: usize, index: usize) {
+    char_vals[start..=index].rotate_right(1);
+
+    if let Some(t) = trace {
+        t.offsets[start..=index].rotate_right(1);
+    }
+}
+
+// Default_Ignorable_Code_Point, from DerivedCoreProperties.txt
+fn default_ignorable(code_point: u32) -> bool {
+    matches!(
+        code_point,
+        0xAD | 0x34F
+            | 0x61C
+            | 0x115F..=0x1160
+            | 0x17B4..=0x17B5
+            | 0x180B..=0x180F
+            | 0x200B..=0x200F
+            | 0x202A..=0x202E
+            | 0x2060..=0x206F
+            | 0x3164
+            | 0xFE00..=0xFE0F
+            | 0xFEFF
+            | 0xFFA0
+            | 0xFFF0..=0xFFF8
+            | 0x1BCA0..=0x1BCA3
+            | 0x1D173..=0x1D17A
+            | 0xE0000..=0xE0FFF
+    )
+}
+
+// The White_Space property
+fn whitespace(code_point: u32) -> bool {
+    char::from_u32(code_point).is_some_and(char::is_whitespace)
+}
+
+// 0 for VS1, through 255 for VS256
+fn variation_selector_index(code_point: u32) -> Option<u16> {
+    match code_point {
+        0xFE00..=0xFE0F => Some((code_point - 0xFE00) as u16),
+        0xE0100..=0xE01EF => Some((code_point - 0xE0100) as u16 + 16),
+        _ => None,
+    }
+}
+
+fn valid_element(elem: &ArrayVec<[u16; 4]>, weighting: Weighting) -> bool {
+    if weighting != Weighting::Shifted {
+        return elem.len() == 3;
+    }
+
+    match elem[..] {
+        // Completely ignorable; or ignorable after a variable; or variable
+        [0, 0, 0, q] => q != SHIFTED_QUATERNARY,
+        // Anything else has the maximal fourth weight
+        [_, _, _, q] => q == SHIFTED_QUATERNARY,
+        _ => false,
+    }
+}
+
+fn apply_primary_order(
+    cea: &mut [ArrayVec<[u16; 4]>],
+    order: &[char],
+    singles: &TableMap<u32, Vec<Weights>>,
+) {
+    // Find the primary of each listed letter (skipping duplicates and anything that isn't a
+    // single non-variable collation element)
+    let mut listed: Vec<u16> = Vec::new();
+
+    for c in order {
+        if let Some([weights]) = singles.get(&(*c as u32)).map(Vec::as_slice) {
+            if !weights.variable && weights.primary != 0 && !listed.contains(&weights.primary) {
+                listed.push(weights.primary);
+            }
+        }
+    }
+
+    let mut available = listed.clone();
+    available.sort_unstable();
+
+    // The nth listed letter takes the nth lowest primary among them
+    let remap: HashMap<u16, u16> = listed.into_iter().zip(available).collect();
+
+    for elem in cea {
+        if let Some(new_primary) = remap.get(&elem[0]) {
+            elem[0] = *new_primary;
+        }
+    }
+}
+
+// Swaps lowercase and uppercase tertiaries in the first element with a primary (unless the array
+// doesn't start at the beginning of the string), and folds uppercase to lowercase everywhere else
+fn apply_first_letter_case(cea: &mut [ArrayVec<[u16; 4]>], at_start: bool) {
+    let mut first = at_start;
+
+    for elem in cea.iter_mut() {
+        let tertiary = elem[2];
+        let lower = LOWER_TERTIARIES.contains(&tertiary);
+        let upper = LOWER_TERTIARIES.contains(&tertiary.wrapping_sub(CASE_OFFSET));
+
+        if first && elem[0] != 0 {
+            first = false;
+
+            if lower {
+                elem[2] = tertiary + CASE_OFFSET;
+            } else if upper {
+                elem[2] = tertiary - CASE_OFFSET;
+            }
+        } else if upper {
+            elem[2] = tertiary - CASE_OFFSET;
+        }
+    }
+}
+
+// The range from the lowest listed special group (or else the first script) to the top is cut
+// into the listed groups and the stretches between them, which are then laid out again: first
+// the stretches below the scripts (i.e., special groups that weren't listed), then the listed
+// groups, in order, then the rest as before. Everything below that stays put. Under shifting,
+// variable elements are reordered at the fourth level, where their primaries went
+fn apply_reorder(cea: &mut [ArrayVec<[u16; 4]>], reorder: &[ReorderGroup], table: &CollationTable) {
+    let scripts = REORDER_LETTERS
+        .iter()
+        .map(|(group, _)| *group)
+        .chain([ReorderGroup::Han])
+        .filter(|group| !group.is_special());
+
+    let Some(script_start) = scripts
+        .filter_map(|group| table.reorder_range(group))
+        .map(|range| *range.start())
+        .min()
+    else {
+        return;
+    };
+
+    let start = reorder
+        .iter()
+        .filter(|group| group.is_special())
+        .filter_map(|group| table.reorder_range(*group))
+        .map(|range| *range.start())
+        .fold(script_start, u16::min);
+
+    let mut listed: Vec<RangeInclusive<u16>> = Vec::new();
+
+    for group in reorder {
+        if let Some(range) = table.reorder_range(*group) {
+            if !listed.contains(&range) {
+                listed.push(range);
+            }
+        }
+    }
+
+    let mut sorted = listed.clone();
+    sorted.sort_unstable_by_key(|range| *range.start());
+
+    let mut rest: Vec<RangeInclusive<u16>> = Vec::new();
+    let mut next = start;
+
+    for range in sorted {
+        if next < *range.start() {
+            rest.push(next..=range.start() - 1);
+        }
+        next = range.end() + 1;
+    }
+
+    rest.push(next..=u16::MAX);
+
+    let (specials, scripts): (Vec<_>, Vec<_>) = rest
+        .into_iter()
+        .partition(|range| *range.end() < script_start);
+    let segments = specials.into_iter().chain(listed).chain(scripts);
+
+    // Where each segment starts after reordering
+    let mut new_start = start;
+    let moves: Vec<(RangeInclusive<u16>, u16)> = segments
+        .into_iter()
+        .map(|range| {
+            let moved = (range.clone(), new_start);
+            new_start = new_start.wrapping_add(range.end() - range.start() + 1);
+            moved
+        })
+        .collect();
+
+    for elem in cea {
+        // The second element of implicit weights (which has no secondary or tertiary) isn't a
+        // primary in the same sense, and stays as it is
+        let level = match elem[..] {
+            [0, 0, 0, q] if q != 0 => 3,
+            [_, 0, 0, ..] => continue,
+            _ => 0,
+        };
+
+        if elem[level] < start {
+            continue;
+        }
+
+        if let Some((range, new_start)) =
+            moves.iter().find(|(range, _)| range.contains(&elem[level]))
+        {
+            elem[level] = new_start + (elem[level] - range.start());
+        }
+    }
+}
+
+fn apply_primary_offset(
+    cea: &mut [ArrayVec<[u16; 4]>],
+    group: ReorderGroup,
+    offset: i32,
+    table: &CollationTable,
+) {
+    let (Some(range), Some(shifted)) = (
+        table.reorder_range(group),
+        table.shifted_range(group, offset),
+    ) else {
+        return;
+    };
+
+    for elem in cea {
+        // As in apply_reorder, the second element of implicit weights stays as it is
+        if range.contains(&elem[0]) && (elem[1] != 0 || elem[2] != 0) {
+            elem[0] = shifted.start() + (elem[0] - range.start());
+        }
+    }
+}
+
+fn push_weights(
+    cea: &mut Vec<ArrayVec<[u16; 4]>>,
+    weights: &Weights,
+    weighting: Weighting,
+    variable: Option<(u16, u16)>,
+    variable_run: &mut Option<usize>,
+) {
+    let overridden: Weights;
+
+    let weights = match variable {
+        Some(range) => {
+            overridden = with_variable(weights, range);
+            &overridden
+        }
+        None => weights,
+    };
+
+    // A variable element without a primary would vanish entirely under shifting
+    debug_assert!(
+        !weights.variable || weights.primary != 0,
+        "variable weights without a primary: {weights:?}"
+    );
+
+    let start = cea.len();
+
+    match weighting {
+        Weighting::Shifted => {
+            cea.push(get_weights_shifting(weights, variable_run.is_some()));
+        }
+        Weighting::ShiftedPrimary if weights.variable => {
+            cea.push(array_vec!([u16; 4] => 0, 0, 0));
+        }
+        _ => {
+            cea.push(array_vec!(
+                [u16; 4] => weights.primary, weights.secondary, weights.tertiary
+            ));
+        }
+    }
+
+    if weights.variable {
+        variable_run.get_or_insert(start);
+    } else if weights.primary != 0 {
+        *variable_run = None;
+    }
+}
+
+// Ordered by how common each case is: the usual one (a non-variable primary) is settled after
+// checking the variable flag and the primary. Variable weights always have a primary, and anything
+// else without one is either completely ignorable or loses its weights after a variable
+fn get_weights_shifting(weights: &Weights, last_variable: bool) -> ArrayVec<[u16; 4]> {
+    match *weights {
+        Weights {
+            variable: true,
+            primary,
+            ..
+        } => ArrayVec::from([0, 0, 0, primary]),
+        Weights { primary: 1.., .. } => ArrayVec::from([
+            weights.primary,
+            weights.secondary,
+            weights.tertiary,
+            SHIFTED_QUATERNARY,
+        ]),
+        Weights {
+            secondary: 0,
+            tertiary: 0,
+            ..
+        } => ArrayVec::from([0, 0, 0, 0]),
+        Weights { tertiary: 1.., .. } if last_variable => ArrayVec::from([0, 0, 0, 0]),
+        _ => ArrayVec::from([0, weights.secondary, weights.tertiary, SHIFTED_QUATERNARY]),
+    }
+}
+
+fn get_implicit_a(left_val: u32, shifting: bool) -> ArrayVec<[u16; 4]> {
+    #[allow(clippy::manual_range_contains)]
+    let mut aaaa = match left_val {
+        x if x >= 13_312 && x <= 19_903 => 64_384 + (left_val >> 15), //     CJK2
+        x if x >= 19_968 && x <= 40_959 => 64_320 + (left_val >> 15), //     CJK1
+        x if x >= 63_744 && x <= 64_255 => 64_320 + (left_val >> 15), //     CJK1
+        x if x >= 94_208 && x <= 101_119 => 64_256,                   //     Tangut
+        x if x >= 101_120 && x <= 101_631 => 64_258,                  //     Khitan
+        x if x >= 101_632 && x <= 101_775 => 64_256,                  //     Tangut
+        x if x >= 110_960 && x <= 111_359 => 64_257,                  //     Nushu
+        x if x >= 131_072 && x <= 173_791 => 64_384 + (left_val >> 15), //   CJK2
+        x if x >= 173_824 && x <= 191_471 => 64_384 + (left_val >> 15), //   CJK2
+        x if x >= 196_608 && x <= 201_551 => 64_384 + (left_val >> 15), //   CJK2
+        _ => 64_448 + (left_val >> 15),                               //     unass.
+    };
+
+    if INCLUDED_UNASSIGNED.contains(&left_val) {
+        aaaa = 64_448 + (left_val >> 15);
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    let first_weights = if shifting {
+        ArrayVec::from([
+            aaaa as u16,
+            IMPLICIT_SECONDARY,
+            IMPLICIT_TERTIARY,
+            SHIFTED_QUATERNARY,
+        ])
+    } else {
+        array_vec!([u16; 4] => aaaa as u16, IMPLICIT_SECONDARY, IMPLICIT_TERTIARY)
+    };
+
+    first_weights
+}
+
+fn get_implicit_b(left_val: u32, shifting: bool) -> ArrayVec<[u16; 4]> {
+    #[allow(clippy::manual_range_contains)]
+    let mut bbbb = match left_val {
+        x if x >= 13_312 && x <= 19_903 => left_val & 32_767, //      CJK2
+        x if x >= 19_968 && x <= 40_959 => left_val & 32_767, //      CJK1
+        x if x >= 63_744 && x <= 64_255 => left_val & 32_767, //      CJK1
+        x if x >= 94_208 && x <= 101_119 => left_val - 94_208, //     Tangut
+        x if x >= 101_120 && x <= 101_631 => left_val - 101_120, //   Khitan
+        x if x >= 101_632 && x <= 101_775 => left_val - 94_208, //    Tangut
+        x if x >= 110_960 && x <= 111_359 => left_val - 110_960, //   Nushu
+        x if x >= 131_072 && x <= 173_791 => left_val & 32_767, //    CJK2
+        x if x >= 173_824 && x <= 191_471 => left_val & 32_767, //    CJK2
+        x if x >= 196_608 && x <= 201_551 => left_val & 32_767, //    CJK2
+        _ => left_val & 32_767,                               //      unass.
+    };
+
+    if INCLUDED_UNASSIGNED.contains(&left_val) {
+        bbbb = left_val & 32_767;
+    }
+
+    // BBBB always gets bitwise ORed with this value
+    bbbb |= 32_768;
+
+    #[allow(clippy::cast_possible_truncation)]
+    let second_weights = if shifting {
+        ArrayVec::from([bbbb as u16, 0, 0, SHIFTED_QUATERNARY])
+    } else {
+        array_vec!([u16; 4] => bbbb as u16, 0, 0)
+    };
+
+    second_weights
+}
+
+//
+// Parsing Unicode data (not usually run)
+//
+
+pub fn parse_keys_sing() {
+    let keys = std::fs::read_to_string("test-data/allkeys.txt").unwrap();
+    let mut map: HashMap<u32, Vec<Weights>> = HashMap::new();
+
+    let re_key = regex!(r"[\dA-F]{4,5}");
+    let re_weights = regex!(r"[*.\dA-F]{15}");
+    let re_value = regex!(r"[\dA-F]{4}");
+
+    for line in keys.lines() {
+        if line.is_empty() || line.starts_with('@') || line.starts_with('#') {
+            continue;
+        }
+
+        let mut split_at_semicolon = line.split(';');
+        let left_of_semicolon = split_at_semicolon.next().unwrap();
+        let right_of_semicolon = split_at_semicolon.next().unwrap();
+        let left_of_hash = right_of_semicolon.split('#').next().unwrap();
+
+        let mut points = ArrayVec::<[u32; 3]>::new();
+        for m in re_key.find_iter(left_of_semicolon) {
+            let as_u32 = u32::from_str_radix(m.as_str(), 16).unwrap();
+            points.push(as_u32);
+        }
+
+        if points.len() > 1 {
+            continue;
+        }
+
+        let k = points[0];
+
+        let mut v: Vec<Weights> = Vec::new();
+
+        for m in re_weights.find_iter(left_of_hash) {
+            let weights_str = m.as_str();
+            let mut weights = Weights::new();
+
+            if weights_str.starts_with('*') {
+                weights.variable = true;
+            }
+
+            let mut vals = re_value.find_iter(weights_str);
+            weights.primary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
+            weights.secondary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
+            weights.tertiary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
+
+            v.push(weights);
+        }
+
+        map.insert(k, v);
+    }
+
+    let bytes = bincode::serialize(&map).unwrap();
+    std::fs::write("byte_dump", bytes).unwrap();
+}
+
+pub fn parse_keys_multi() {
+    let keys = std::fs::read_to_string("test-data/allkeys.txt").unwrap();
+    let mut map: HashMap<ArrayVec<[u32; 3]>, Vec<Weights>> = HashMap::new();
+
+    let re_key = regex!(r"[\dA-F]{4,5}");
+    let re_weights = regex!(r"[*.\dA-F]{15}");
+    let re_value = regex!(r"[\dA-F]{4}");
+
+    for line in keys.lines() {
+        if line.is_empty() || line.starts_with('@') || line.starts_with('#') {
+            continue;
+        }
+
+        let mut split_at_semicolon = line.split(';');
+        let left_of_semicolon = split_at_semicolon.next().unwrap();
+        let right_of_semicolon = split_at_semicolon.next().unwrap();
+        let left_of_hash = right_of_semicolon.split('#').next().unwrap();
+
+        let mut k = ArrayVec::<[u32; 3]>::new();
+        for m in re_key.find_iter(left_of_semicolon) {
+            let as_u32 = u32::from_str_radix(m.as_str(), 16).unwrap();
+            k.push(as_u32);
+        }
+
+        if k.len() < 2 {
+            continue;
+        }
+
+        let mut v: Vec<Weights> = Vec::new();
+
+        for m in re_weights.find_iter(left_of_hash) {
+            let weights_str = m.as_str();
+            let mut weights = Weights::new();
+
+            if weights_str.starts_with('*') {
+                weights.variable = true;
+            }
+
+            let mut vals = re_value.find_iter(weights_str);
+            weights.primary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
+            weights.secondary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
+            weights.tertiary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
+
+            v.push(weights);
+        }
+
+        map.insert(k, v);
+    }
+
+    let bytes = bincode::serialize(&map).unwrap();
+    std::fs::write("byte_dump", bytes).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering as AtomicOrdering;
+
+    // Lines from a conformance test file, as strings. Lines with surrogates are skipped, since
+    // they can't be represented safely
+    fn conformance_strings(path: &str) -> Vec<String> {
+        let test_data = std::fs::read_to_string(path).unwrap();
+
+        test_data
+            .lines()
+            // The full files have a comment after the code points
+            .map(|line| line.split(';').next().unwrap().trim())
+            .filter(|code_points| !code_points.is_empty() && !code_points.starts_with('#'))
+            .filter_map(|code_points| {
+                code_points
+                    .split(' ')
+                    .map(|s| char::from_u32(u32::from_str_radix(s, 16).unwrap()))
+                    .collect::<Option<String>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deluge_shifted() {
+        let mut scrambled = [
+            "demark", "de-luge", "deluge", "de-Luge", "de luge", "de-luge", "deLuge", "de Luge",
+            "de-Luge", "death",
+        ];
+
+        let options = CollationOptions {
+            keys_source: KeysSource::Ducet,
+            shifting: true,
+            ..Default::default()
+        };
+
+        scrambled.sort_by(|a, b| collate(a, b, options));
+
+        let sorted = [
+            "death", "de luge", "de-luge", "de-luge", "deluge", "de Luge", "de-Luge", "de-Luge",
+            "deLuge", "demark",
+        ];
+
+        assert_eq!(scrambled, sorted);
+    }
+
+    #[test]
+    fn multi_script() {
+        let mut scrambled = [
+            "ｶ",
+            "ヵ",
+            "abc",
+            "abç",
+            "ab©",
+            "𝒶bc",
+            "abC",
+            "𝕒bc",
+            "File-3",
+            "ガ",
+            "が",
+            "äbc",
+            "カ",
+            "か",
+            "Abc",
+            "file-12",
+            "filé-110",
+        ];
+
+        let options = CollationOptions {
+            keys_source: KeysSource::Ducet,
+            shifting: true,
+            ..Default::default()
+        };
+
+        scrambled.sort_by(|a, b| collate(a, b, options));
+
+        let sorted = [
+            "ab©",
+            "abc",
+            "abC",
+            "𝒶bc",
+            "𝕒bc",
+            "Abc",
+            "abç",
+            "äbc",
+            "filé-110",
+            "file-12",
+            "File-3",
+            "か",
+            "ヵ",
+            "カ",
+            "ｶ",
+            "が",
+            "ガ",
+        ];
+
+        assert_eq!(scrambled, sorted);
+    }
+
+    #[test]
+    fn typed_elements() {
+        for shifting in [false, true] {
+            let options = CollationOptions {
+                keys_source: KeysSource::Ducet,
+                shifting,
+                ..Default::default()
+            };
+
+            let raw = collation_elements("é", options);
+            let typed = collation_elements_typed("é", options);
+
+            assert_eq!(raw.len(), typed.len());
+
+            for (elem, ce) in raw.iter().zip(&typed) {
+                assert_eq!(ce.primary, elem[0]);
+                assert_eq!(ce.secondary, elem[1]);
+                assert_eq!(ce.tertiary, elem[2]);
+                assert_eq!(ce.quaternary, if shifting { elem[3] } else { 0 });
+            }
+        }
+    }
+
+    #[test]
+    fn shifted_boundaries() {
+        // These follow the Shifted column of the variable weighting table in UTS #10, section 4.1
+        let options = CollationOptions {
+            keys_source: KeysSource::Ducet,
+            shifting: true,
+            ..Default::default()
+        };
+
+        let cea = |s: &str| -> Vec<[u16; 4]> {
+            collation_elements(s, options)
+                .iter()
+                .map(|elem| [elem[0], elem[1], elem[2], elem[3]])
+                .collect()
+        };
+
+        // Variable, then completely ignorable: the ignorable stays all zero
+        assert_eq!(cea("-\u{0}"), [[0, 0, 0, 0x020D], [0, 0, 0, 0]]);
+
+        // Variable, then ignorable with non-zero lower weights: also all zero
+        assert_eq!(cea("-\u{301}"), [[0, 0, 0, 0x020D], [0, 0, 0, 0]]);
+
+        // Variable, then non-ignorable: the latter keeps its weights and gets FFFF
+        assert_eq!(cea("-a"), [[0, 0, 0, 0x020D], [0x2075, 0x20, 0x02, 0xFFFF]]);
+
+        // Ignorable not preceded by a variable: keeps its weights and gets FFFF
+        assert_eq!(
+            cea("a\u{301}"),
+            [[0x2075, 0x20, 0x02, 0xFFFF], [0, 0x24, 0x02, 0xFFFF]]
+        );
+
+        // A completely ignorable element between a variable and a mark doesn't reset the state
+        assert_eq!(
+            cea("-\u{0}\u{301}"),
+            [[0, 0, 0, 0x020D], [0, 0, 0, 0], [0, 0, 0, 0]]
+        );
+    }
+
+    #[test]
+    fn divergence() {
+        let options = CollationOptions::default();
+
+        // Letter difference
+        assert_eq!(divergence_index("cart", "card", options), Some((3, 3)));
+        assert_eq!(divergence_index("car", "cart", options), Some((3, 3)));
+
+        // Accent difference: primaries agree, so the first differing secondary decides. In
+        // "résumé" that's the acute on the é (byte 1); in "resume" it's the s (byte 2)
+        assert_eq!(divergence_index("resume", "résumé", options), Some((2, 1)));
+
+        // Equal at every level
+        assert_eq!(divergence_index("café", "cafe\u{301}", options), None);
+        assert_eq!(divergence_index("abc", "abc", options), None);
+
+        // With backwards secondaries, the last accent is looked at first: the acute on the final
+        // é of "coté" (byte 3), against the plain e of "côte" (byte 4)
+        let french = locale_options("fr");
+        assert_eq!(divergence_index("côte", "coté", french), Some((4, 3)));
+
+        // Likewise for tertiaries: the case of the last letter comes first
+        let backwards_tertiary = CollationOptions {
+            backwards_tertiary: true,
+            ..options
+        };
+        assert_eq!(divergence_index("Ab", "aB", options), Some((0, 0)));
+        assert_eq!(
+            divergence_index("Ab", "aB", backwards_tertiary),
+            Some((1, 1))
+        );
+
+        // A stripped article is skipped, and the offsets still point into the original string
+        let catalog = CollationOptions {
+            ignore_leading_articles: &["the"],
+            ..options
+        };
+        assert_eq!(divergence_index("The Beatles", "Beatles", catalog), None);
+        assert_eq!(
+            divergence_index("The Beatles", "Beetles", catalog),
+            Some((6, 2))
+        );
+    }
+
+    #[test]
+    fn differing_elements() {
+        let options = CollationOptions::default();
+
+        let cafe = collation_elements("café", options);
+        let acute = cafe[4];
+        assert_eq!((acute[0], acute[1]), (0, 0x24));
+
+        // Primaries agree, and "cafe" runs out of secondaries before the acute
+        assert_eq!(
+            diff_elements("cafe", "café", options),
+            Some((ArrayVec::new(), acute))
+        );
+        assert_eq!(
+            diff_elements("café", "cafe", options),
+            Some((acute, ArrayVec::new()))
+        );
+
+        // A letter difference comes out as the two letters' elements
+        assert_eq!(
+            diff_elements("cafe", "cafa", options),
+            Some((cafe[3], collation_elements("a", options)[0]))
+        );
+
+        // With backwards secondaries, the last accent is looked at first
+        let french = locale_options("fr");
+        let (a, b) = diff_elements("côté", "côte", french).unwrap();
+        assert_eq!((a[1], b[1]), (0x24, COMMON_SECONDARY));
+
+        assert_eq!(diff_elements("café", "cafe\u{301}", options), None);
+        assert_eq!(diff_elements("abc", "abc", options), None);
+    }
+
+    #[test]
+    fn weight_streams() {
+        let words = [
+            "the",
+            "The",
+            "thé",
+            "the-end",
+            "theend",
+            "the end",
+            "co-op",
+            "coop",
+            "Coop",
+            "",
+            "résumé",
+            "resume",
+            "a\u{301}",
+            "x\u{301}-",
+            "12",
+            "1-2",
+        ];
+
+        let ducet = CollationOptions {
+            keys_source: KeysSource::Ducet,
+            ..Default::default()
+        };
+        let symbols = CollationOptions {
+            max_variable: Some(MaxVariable::Symbol),
+            ..Default::default()
+        };
+        let unshifted = CollationOptions {
+            shifting: false,
+            ..Default::default()
+        };
+        let primary = CollationOptions {
+            strength: CollationStrength::Primary,
+            ..Default::default()
+        };
+
+        for opt in [
+            CollationOptions::default(),
+            ducet,
+            symbols,
+            unshifted,
+            primary,
+        ] {
+            for a in words {
+                for b in words {
+                    let expected = collate_no_tiebreak(a, b, opt);
+                    let actual =
+                        collate_weight_streams(&weights_for(a, opt), &weights_for(b, opt), opt);
+
+                    assert_eq!(actual, expected, "{a} / {b}");
+                }
+            }
+        }
+
+        // Weights cached per token can be joined into a stream for the whole phrase
+        let opt = CollationOptions::default();
+        let phrase = |tokens: &[&str]| {
+            tokens
+                .iter()
+                .flat_map(|t| weights_for(t, opt))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            collate_weight_streams(&phrase(&["co", "-", "op"]), &phrase(&["coop"]), opt),
+            collate_no_tiebreak("co-op", "coop", opt)
+        );
+    }
+
+    #[test]
+    fn custom_primary_order() {
+        let order: Vec<char> = "iyjklmnopqrstuvwx".chars().collect();
+
+        let options = CollationOptions {
+            primary_order: &order,
+            ..Default::default()
+        };
+
+        let mut scrambled = ["z", "j", "Ý", "y", "i", "k", "Yak", "x"];
+        scrambled.sort_by(|a, b| collate(a, b, options));
+
+        assert_eq!(scrambled, ["i", "y", "Ý", "Yak", "j", "k", "x", "z"]);
+
+        // Without the custom order, y is back between x and z
+        let mut scrambled = ["z", "j", "y", "i", "x"];
+        scrambled.sort_by(|a, b| collate(a, b, CollationOptions::default()));
+
+        assert_eq!(scrambled, ["i", "j", "x", "y", "z"]);
+    }
+
+    #[test]
+    fn direct_comparison_agrees_with_sort_keys() {
+        // The same check as the compare_paths fuzz target, over a sample of conformance pairs
+        let strings = conformance_strings("test-data/CollationTest_CLDR_SHIFTED_SHORT.txt");
+
+        for pair in strings.windows(2).step_by(41) {
+            let (a, b) = (&pair[0], &pair[1]);
+
+            for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+                for shifting in [false, true] {
+                    let opt = CollationOptions {
+                        keys_source,
+                        shifting,
+                        ..Default::default()
+                    };
+
+                    let via_keys = sort_key(a, opt).cmp(&sort_key(b, opt));
+
+                    let direct = compare_ceas(
+                        &collation_elements(a, opt),
+                        &collation_elements(b, opt),
+                        opt,
+                    );
+
+                    assert_eq!(direct, via_keys, "{a:?} vs. {b:?} with {opt:?}");
+                    assert_eq!(collate_no_tiebreak(a, b, opt), via_keys);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn utf16() {
+        let options = CollationOptions::default();
+        let utf16 = |s: &str| -> Vec<u16> { s.encode_utf16().collect() };
+
+        // Surrogate pairs are decoded, giving the same result as the str path
+        for (a, b) in [
+            ("a😀", "a😁"),
+            ("😀", "a"),
+            ("résumé", "resume"),
+            ("é", "e\u{301}"),
+        ] {
+            assert_eq!(
+                collate_utf16(&utf16(a), &utf16(b), options),
+                collate(a, b, options)
+            );
+        }
+
+        // Unpaired surrogates get implicit weights, so they sort after assigned characters (and
+        // in code point order among themselves)
+        let lone_a = [0x61, 0xD800];
+        let lone_b = [0x61, 0xD801];
+
+        assert_eq!(collate_utf16(&lone_a, &lone_b, options), Ordering::Less);
+        assert_eq!(
+            collate_utf16(&lone_a, &utf16("a"), options),
+            Ordering::Greater
+        );
+        assert_eq!(
+            collate_utf16(&lone_a, &utf16("ab"), options),
+            Ordering::Greater
+        );
+
+        // A lone surrogate acts as a starter: the marks on either side are reordered separately,
+        // but never across it
+        let marks = [0x61, 0x301, 0x316, 0xDC00, 0x301, 0x316];
+        assert_eq!(
+            get_nfd_code_points(&decode_utf16(&marks), options),
+            [0x61, 0x316, 0x301, 0xDC00, 0x316, 0x301]
+        );
+    }
+
+    #[test]
+    fn shifted_primary_strength() {
+        let full = CollationOptions::default();
+
+        let primary = CollationOptions {
+            strength: CollationStrength::Primary,
+            ..Default::default()
+        };
+
+        let primary_ni = CollationOptions {
+            shifting: false,
+            ..primary
+        };
+
+        // Skipping the shifted bookkeeping gives the same result as computing it in full and
+        // then looking only at the first level of the sort key
+        let level_one = |s: &str| -> Vec<u16> {
+            sort_key(s, full)
+                .into_vec()
+                .into_iter()
+                .take_while(|w| *w != 0)
+                .collect()
+        };
+
+        let strings = conformance_strings("test-data/CollationTest_CLDR_SHIFTED_SHORT.txt");
+
+        for pair in strings.windows(2).step_by(97) {
+            let (a, b) = (&pair[0], &pair[1]);
+
+            assert_eq!(
+                collate_no_tiebreak(a, b, primary),
+                level_one(a).cmp(&level_one(b)),
+                "{a:?} vs. {b:?}"
+            );
+        }
+
+        // With the same punctuation on both sides, shifting makes no difference at primary
+        for (a, b) in [("de-luge", "de-Luge"), ("a-b", "a-c"), ("co-op", "co-öp")] {
+            assert_eq!(
+                collate_no_tiebreak(a, b, primary),
+                collate_no_tiebreak(a, b, primary_ni)
+            );
+        }
+
+        // Whereas differing punctuation only matters when it isn't shifted
+        assert_eq!(
+            collate_no_tiebreak("de-luge", "de luge", primary),
+            Ordering::Equal
+        );
+        assert_ne!(
+            collate_no_tiebreak("de-luge", "de luge", primary_ni),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn primary_report() {
+        let alphabet = ['d', 'b', 'é', 'a', 'C', 'c', 'e'];
+
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let options = CollationOptions {
+                keys_source,
+                ..Default::default()
+            };
+
+            let report = primary_order_report(&alphabet, options);
+
+            let mut sorted = alphabet;
+            sorted.sort_by(|a, b| collate(&a.to_string(), &b.to_string(), options));
+
+            let reported: Vec<char> = report.iter().map(|(c, _)| *c).collect();
+            assert_eq!(reported, sorted);
+
+            // Primaries never decrease, and case/accent variants share them
+            assert!(report.windows(2).all(|w| w[0].1 <= w[1].1));
+            assert_eq!(report[2].1, report[3].1); // c, C
+            assert_eq!(report[5].1, report[6].1); // e, é
+        }
+    }
+
+    #[test]
+    fn case_folding() {
+        let folding = CollationOptions {
+            case_fold: true,
+            ..Default::default()
+        };
+
+        let pairs = [
+            ("STRASSE", "straße"),
+            ("STRASSE", "STRAẞE"),
+            ("İstanbul", "i\u{307}stanbul"),
+            ("ΟΔΟΣ", "οδος"),
+            ("ΟΔΟΣ", "οδοσ"),
+            ("ﬁle", "FILE"),
+        ];
+
+        for (a, b) in pairs {
+            // Equal at every level with folding; not without it
+            assert_eq!(collate_no_tiebreak(a, b, folding), Ordering::Equal);
+            assert_ne!(
+                collate_no_tiebreak(a, b, CollationOptions::default()),
+                Ordering::Equal
+            );
+
+            // The UTF-16 and traced paths fold too
+            let a_utf16: Vec<u16> = a.encode_utf16().collect();
+            let b_utf16: Vec<u16> = b.encode_utf16().collect();
+            assert_eq!(
+                collate_utf16(&a_utf16, &b_utf16, folding),
+                collate(a, b, folding)
+            );
+            assert_eq!(divergence_index(a, b, folding), None);
+        }
+
+        // Dotless ı is not folded to i
+        assert_ne!(collate_no_tiebreak("ı", "i", folding), Ordering::Equal);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "variable weights without a primary")]
+    fn invariant_bad_table_entry() {
+        // A malformed table entry: variable, but with no primary weight
+        let bad = Weights {
+            variable: true,
+            primary: 0,
+            secondary: 0x20,
+            tertiary: 0x02,
+        };
+
+        let mut cea = Vec::new();
+        let mut variable_run = None;
+
+        push_weights(&mut cea, &bad, Weighting::Shifted, None, &mut variable_run);
+    }
+
+    #[test]
+    fn invariant_element_layout() {
+        assert!(valid_element(
+            &ArrayVec::from([0, 0, 0, 0x0209]),
+            Weighting::Shifted
+        ));
+        assert!(valid_element(
+            &ArrayVec::from([0x2075, 0x20, 0x02, 65_535]),
+            Weighting::Shifted
+        ));
+
+        // A fourth weight that belongs to a variable, on an element that isn't shifted away
+        assert!(!valid_element(
+            &ArrayVec::from([0x2075, 0x20, 0x02, 0x0209]),
+            Weighting::Shifted
+        ));
+        assert!(!valid_element(
+            &ArrayVec::from([0, 0, 0, 65_535]),
+            Weighting::Shifted
+        ));
+
+        assert!(!canonically_ordered(&[0x61, 0x301, 0x316]));
+        assert!(canonically_ordered(&[0x61, 0x316, 0x301]));
+    }
+
+    #[test]
+    fn dedup_primary() {
+        let mut words: Vec<String> = ["cafe", "café", "CAFE", "zeta"]
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+
+        dedup_by_collation(
+            &mut words,
+            CollationStrength::Primary,
+            CollationOptions::default(),
+        );
+        assert_eq!(words, ["cafe", "zeta"]);
+
+        // At full strength, nothing here is a duplicate
+        let mut words: Vec<String> = ["cafe", "café", "CAFE"]
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+
+        dedup_by_collation(
+            &mut words,
+            CollationStrength::Quaternary,
+            CollationOptions::default(),
+        );
+        assert_eq!(words.len(), 3);
+    }
+
+    #[test]
+    fn primary_weights_match_sort_key() {
+        for opt in [
+            CollationOptions::default(),
+            CollationOptions {
+                keys_source: KeysSource::Ducet,
+                shifting: false,
+                ..Default::default()
+            },
+        ] {
+            for s in [
+                "",
+                "Hello, world!",
+                "naïve café",
+                "ſtraße",
+                "日本語 テキスト",
+                "𝒳-ray",
+            ] {
+                let mut nfd = get_nfd(s, opt);
+                let cea = get_cea(&mut nfd, 0, builtin_table(opt.keys_source), opt, None);
+                let key = get_sort_key(&cea, opt);
+
+                let separator = key.iter().position(|&w| w == 0).unwrap();
+                assert_eq!(primary_weights(s, opt), key[..separator], "{s}");
+            }
+        }
+    }
+
+    #[test]
+    fn precomposed_and_decomposed_contractions() {
+        use unicode_normalization::UnicodeNormalization;
+
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let multis = &builtin_table(keys_source).multis;
+            let singles = &builtin_table(keys_source).singles;
+
+            for shifting in [true, false] {
+                let opt = CollationOptions {
+                    keys_source,
+                    shifting,
+                    ..Default::default()
+                };
+
+                let contractions = multis.keys().map(|k| {
+                    k.iter()
+                        .map(|&c| char::from_u32(c).unwrap())
+                        .collect::<String>()
+                });
+                let precomposed = singles
+                    .keys()
+                    .filter_map(|&c| char::from_u32(c))
+                    .map(String::from)
+                    .filter(|s| s.nfd().ne(s.chars()));
+
+                for s in contractions.chain(precomposed) {
+                    let composed: String = s.nfc().collect();
+                    let decomposed: String = s.nfd().collect();
+
+                    assert_eq!(
+                        collation_elements(&composed, opt),
+                        collation_elements(&decomposed, opt),
+                        "{:X?}",
+                        s.chars().map(|c| c as u32).collect::<Vec<_>>()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fcd_but_not_nfd() {
+        use unicode_normalization::UnicodeNormalization;
+
+        // Precomposed letters followed by marks that don't need reordering: FCD, so they're used
+        // as they are, but not NFD
+        for s in [
+            "é\u{301}",
+            "ǖ\u{301}",
+            "ḍ\u{307}",
+            "Й\u{301}",
+            "ệ\u{301}",
+            "\u{1D15E}\u{301}",
+        ] {
+            assert!(fcd(s.chars().map(|c| c as u32)), "{s:?}");
+
+            let nfd: String = s.nfd().collect();
+            assert_ne!(nfd, s);
+
+            for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+                let opt = CollationOptions {
+                    keys_source,
+                    ..Default::default()
+                };
+
+                assert_eq!(
+                    get_nfd(s, opt),
+                    s.chars().map(|c| c as u32).collect::<Vec<_>>()
+                );
+                assert_eq!(sort_key(s, opt), sort_key(&nfd, opt), "{s:?}");
+            }
+        }
+
+        // Every precomposed char is in both tables, and followed by any of these marks (those
+        // that continue contractions, and some common ones), gets the same sort key as its NFD
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let table = builtin_table(keys_source);
+
+            let mut marks: Vec<char> = table
+                .multis
+                .keys()
+                .flat_map(|k| k.iter().skip(1).copied())
+                .filter(|cp| get_ccc_u32(*cp) != 0)
+                .chain([0x300, 0x301, 0x316, 0x323, 0x327, 0x34F, 0x5B0, 0x93C])
+                .filter_map(char::from_u32)
+                .collect();
+            marks.sort_unstable();
+            marks.dedup();
+
+            let precomposed = (0..=0x10_FFFF)
+                .filter(|cp| !(S_BASE..=S_LAST).contains(cp))
+                .filter_map(char::from_u32)
+                .filter(|c| c.to_string().nfd().ne([*c]));
+
+            for c in precomposed {
+                assert!(table.singles.contains_key(&(c as u32)), "{:X}", c as u32);
+
+                for shifting in [false, true] {
+                    let opt = CollationOptions {
+                        keys_source,
+                        shifting,
+                        ..Default::default()
+                    };
+
+                    for m in &marks {
+                        let s = String::from_iter([c, *m]);
+
+                        if fcd(s.chars().map(|c| c as u32)) {
+                            let nfd: String = s.nfd().collect();
+                            assert_eq!(sort_key(&s, opt), sort_key(&nfd, opt), "{s:?}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sort_key_to_strength() {
+        let strengths = [
+            CollationStrength::Primary,
+            CollationStrength::Secondary,
+            CollationStrength::Tertiary,
+            CollationStrength::Quaternary,
+        ];
+
+        for shifting in [true, false] {
+            let full = CollationOptions {
+                shifting,
+                ..Default::default()
+            };
+
+            for s in ["", "a", "Café-au-lait", "ſtraße", "日本語", "..."] {
+                let stored = sort_key(s, full);
+                assert_eq!(stored.levels(), if shifting { 4 } else { 3 });
+                assert_eq!(stored.levels(), stored.strength() as usize);
+
+                // Without shifting, there's no quaternary level to cut the key down from
+                for strength in strengths.into_iter().filter(|s| *s <= stored.strength()) {
+                    let fresh = sort_key(s, CollationOptions { strength, ..full });
+                    assert_eq!(stored.to_strength(strength), fresh, "{s} at {strength:?}");
+                }
+            }
+
+            let (a, b) = (sort_key("cafe", full), sort_key("CAFÉ", full));
+
+            assert_eq!(
+                a.compare_at(&b, CollationStrength::Primary),
+                Ordering::Equal
+            );
+            assert_eq!(
+                a.compare_at(&b, CollationStrength::Secondary),
+                Ordering::Less
+            );
+            assert_eq!(a.compare_at(&b, a.strength()), a.cmp(&b));
+        }
+    }
+
+    #[test]
+    fn multi_strength() {
+        let french = locale_options("fr");
+        let code_points = CollationOptions {
+            quaternary: QuaternarySource::CodePoints,
+            ..Default::default()
+        };
+        let unshifted = CollationOptions {
+            shifting: false,
+            ..Default::default()
+        };
+        let compressed = CollationOptions {
+            compress_secondaries: true,
+            strength: CollationStrength::Primary,
+            ..Default::default()
+        };
+
+        for opt in [
+            CollationOptions::default(),
+            french,
+            code_points,
+            unshifted,
+            compressed,
+        ] {
+            for s in [
+                "",
+                "a",
+                "Café-au-lait",
+                "côté",
+                "ſtraße",
+                "日本語",
+                "...",
+                "a\u{FE00}",
+            ] {
+                let keys = multi_strength_keys(s, opt);
+
+                let at = |strength| sort_key(s, CollationOptions { strength, ..opt });
+
+                assert_eq!(keys.primary, at(CollationStrength::Primary), "{s}");
+                assert_eq!(keys.secondary, at(CollationStrength::Secondary), "{s}");
+                assert_eq!(keys.tertiary, at(CollationStrength::Tertiary), "{s}");
+                assert_eq!(keys.quaternary, at(CollationStrength::Quaternary), "{s}");
+            }
+        }
+    }
+
+    #[test]
+    fn sort_key_strength() {
+        let opt = CollationOptions::default();
+        let primary = CollationOptions {
+            strength: CollationStrength::Primary,
+            ..opt
+        };
+
+        let (a, b) = (sort_key("cafe", primary), sort_key("CAFÉ", primary));
+        assert_eq!(a.strength(), CollationStrength::Primary);
+        assert_eq!(a.compare(&b), Ordering::Equal);
+        assert_eq!(
+            a.compare_at(&b, CollationStrength::Primary),
+            Ordering::Equal
+        );
+
+        // Cutting a key down records the new strength
+        let full = sort_key("cafe", opt);
+        assert_eq!(full.strength(), CollationStrength::Quaternary);
+        assert_eq!(full.to_strength(CollationStrength::Primary), a);
+
+        // Decoded keys get their strength from the number of levels
+        assert_eq!(SortKey::from_hex(&a.to_hex()), Ok(a));
+        assert_eq!(SortKey::from_base32(&full.to_base32()), Ok(full));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "sort key generated at Primary used at Tertiary")]
+    fn sort_key_strength_too_high() {
+        let primary = CollationOptions {
+            strength: CollationStrength::Primary,
+            ..Default::default()
+        };
+
+        // The primary key has nothing to say about case, so this would wrongly report a tie
+        let stored = sort_key("cafe", primary);
+        let query = sort_key("CAFE", CollationOptions::default());
+
+        stored.compare_at(&query, CollationStrength::Tertiary);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "comparing sort keys generated at different strengths")]
+    fn sort_key_strength_mismatch() {
+        let primary = CollationOptions {
+            strength: CollationStrength::Primary,
+            ..Default::default()
+        };
+
+        sort_key("cafe", primary).compare(&sort_key("CAFE", CollationOptions::default()));
+    }
+
+    #[test]
+    fn sort_key_byte_order() {
+        let strings = conformance_strings("test-data/CollationTest_CLDR_SHIFTED_SHORT.txt");
+
+        let keys: Vec<SortKey> = strings
+            .iter()
+            .step_by(499)
+            .map(|s| sort_key(s, CollationOptions::default()))
+            .collect();
+
+        let mut le_disagreements = 0;
+
+        for a in &keys {
+            for b in &keys {
+                assert_eq!(a.as_bytes().cmp(&b.as_bytes()), a.cmp(b));
+
+                if a.as_bytes_le().cmp(&b.as_bytes_le()) != a.cmp(b) {
+                    le_disagreements += 1;
+                }
+            }
+        }
+
+        assert!(le_disagreements > 0);
+
+        // E.g., 0x0100 is above 0x00FF, but its low byte comes first
+        let a = SortKey::from_hex("00ff").unwrap();
+        let b = SortKey::from_hex("0100").unwrap();
+
+        assert_eq!(a.as_bytes(), [0x00, 0xFF]);
+        assert_eq!(a.as_bytes_le(), [0xFF, 0x00]);
+        assert!(a < b && a.as_bytes() < b.as_bytes() && a.as_bytes_le() > b.as_bytes_le());
+    }
+
+    #[test]
+    fn detected_variable_range() {
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let (lo, hi) = variable_range(keys_source);
+            assert!(lo > 0 && lo < hi);
+
+            let table = builtin_table(keys_source);
+            let primary = |c: char| table.singles[&(c as u32)][0].primary;
+
+            for c in [
+                ' ', '\t', '\u{3000}', '.', ',', '!', '?', '-', '(', '"', '\u{2014}',
+            ] {
+                assert!((lo..=hi).contains(&primary(c)), "{c:?}");
+            }
+
+            for c in ['a', 'Z', 'é', 'ß', 'α', 'ж', 'か', '0', '9'] {
+                let weights = &table.singles[&(c.to_string().nfd().next().unwrap() as u32)][0];
+                assert!(!(lo..=hi).contains(&weights.primary), "{c:?}");
+            }
+
+            // Nothing in the range is non-variable
+            for row in table.singles.values().chain(table.multis.values()) {
+                for w in row.iter().filter(|w| w.primary != 0) {
+                    assert_eq!((lo..=hi).contains(&w.primary), w.variable, "{w:?}");
+                }
+            }
+        }
+
+        // A tailoring can extend the range
+        let mut table = CollationTable::new(KeysSource::Cldr);
+        let (lo, hi) = table.variable_range();
+
+        let variable = Weights {
+            variable: true,
+            primary: hi + 1,
+            secondary: 0x20,
+            tertiary: 0x02,
+        };
+
+        table.add_mapping("~", &[variable]);
+        assert_eq!(table.variable_range(), (lo, hi + 1));
+
+        // A filtered table only covers what it keeps
+        let latin = CollationTable::filtered(KeysSource::Cldr, &[0x41..=0x5A]);
+        assert_eq!(latin.variable_range(), (0, 0));
+    }
+
+    #[test]
+    fn tailored_ascii_contraction() {
+        let opt = CollationOptions::default();
+
+        let mut table = CollationTable::new(KeysSource::Cldr);
+        assert!(table.low['c' as usize].is_some());
+
+        // Czech-style "ch", as a letter between h and i
+        let h = table.singles[&('h' as u32)][0];
+        let ch = Weights {
+            primary: h.primary + 1,
+            ..h
+        };
+
+        table.add_mapping("ch", &[ch]);
+
+        // Now c has to go the long way, in case it's followed by h
+        assert!(table.low['c' as usize].is_none());
+        assert_eq!(table.lookahead[&('c' as u32)], 2);
+
+        let key = |s: &str, table: &CollationTable| {
+            let mut nfd = get_nfd(s, opt);
+            get_sort_key(&get_cea(&mut nfd, 0, table, opt, None), opt)
+        };
+
+        let builtin = builtin_table(KeysSource::Cldr);
+
+        assert!(key("chata", builtin) < key("hrad", builtin));
+        assert!(key("chata", &table) > key("hrad", &table));
+        assert!(key("chata", &table) < key("ihned", &table));
+
+        // A lone c is unaffected, as is c followed by anything else
+        assert_eq!(key("cena", &table), key("cena", builtin));
+        assert_eq!(key("c", &table), key("c", builtin));
+    }
+
+    #[test]
+    fn tailoring_diff_swapped() {
+        let sample: Vec<char> = ('a'..='z').chain('A'..='Z').collect();
+
+        let mut table = CollationTable::new(KeysSource::Cldr);
+        assert!(tailoring_diff(&table, KeysSource::Cldr, &sample).is_empty());
+
+        // Swap the weights of v and w
+        let v_weights = table.singles[&('v' as u32)].clone();
+        let w_weights = table.singles[&('w' as u32)].clone();
+        table.add_mapping("v", &w_weights);
+        table.add_mapping("w", &v_weights);
+
+        // V and W keep their weights, so v now follows V, and w precedes it
+        assert_eq!(
+            tailoring_diff(&table, KeysSource::Cldr, &sample),
+            [('v', 'w'), ('v', 'V'), ('w', 'V')]
+        );
+
+        // DUCET and CLDR root agree on the basic Latin letters
+        assert!(
+            tailoring_diff(builtin_table(KeysSource::Cldr), KeysSource::Ducet, &sample).is_empty()
+        );
+    }
+
+    #[test]
+    fn prefix_ranges() {
+        let opt = CollationOptions::default();
+        let (lo, hi) = prefix_range("caf", opt);
+
+        let in_range = |s: &str| {
+            let key = sort_key(s, opt).as_bytes();
+            lo <= key && key < hi
+        };
+
+        for s in ["café", "cafeteria", "Cafe", "CAF", "caf", "ca\u{301}f"] {
+            assert!(in_range(s), "{s:?}");
+        }
+
+        for s in ["ca", "cab", "cag", "cbf", "", "cæf"] {
+            assert!(!in_range(s), "{s:?}");
+        }
+
+        // Keys cut down to primary strength fall in the same range
+        let key = sort_key("Café", opt).to_strength(CollationStrength::Primary);
+        assert!(lo <= key.as_bytes() && key.as_bytes() < hi);
+
+        // With no primaries, there's no upper bound
+        assert_eq!(prefix_range("", opt), (Vec::new(), Vec::new()));
+        assert_eq!(prefix_range("-", opt), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn reproducible_keys() {
+        let expected = [
+            ("Ab", "2075208f0000002000200000000800020000ffffffff"),
+            (
+                "café",
+                "20a92075211620db0000002000200020002000240000000200020002000200020000ffffffffffffffffffff",
+            ),
+            (
+                "漢字",
+                "fb40ef22fb40db570000002000200000000200020000ffffffffffffffff",
+            ),
+            ("ﬃ", "211621162167000000200020002000000004000400040000ffffffffffff"),
+        ];
+
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let opt = CollationOptions {
+                keys_source,
+                ..Default::default()
+            };
+
+            for (s, hex) in expected {
+                assert_eq!(sort_key(s, opt).to_hex(), hex, "{s:?}");
+            }
+
+            // The same table in maps with different hash seeds (and so iteration orders)
+            let mut table = CollationTable::new(keys_source);
+            table.singles = table.singles.into_iter().collect();
+            table.multis = table.multis.into_iter().collect();
+            table.lookahead = table.lookahead.into_iter().collect();
+
+            for s in ["-x", "\u{1D15E}", "Ḍ̇", "ᄀ각", "\u{FFFE}\u{10FFFF}"] {
+                let mut nfd = get_nfd(s, opt);
+                let cea = get_cea(&mut nfd, 0, &table, opt, None);
+
+                assert_eq!(
+                    get_sort_key(&cea, opt),
+                    sort_key(s, opt).into_vec(),
+                    "{s:?}"
+                );
+            }
+        }
+
+        let opt = CollationOptions::default();
+        assert_eq!(
+            sort_key("-x", opt).to_hex(),
+            "234d00000020000000020000010cffff"
+        );
+        assert_eq!(
+            sort_key("-x", opt).as_bytes(),
+            [0x23, 0x4d, 0, 0, 0, 0x20, 0, 0, 0, 0x02, 0, 0, 0x01, 0x0c, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn tiebreak_modes() {
+        // A variation selector (BMP, but above the surrogates) and a tag character (supplementary),
+        // both ignorable
+        let (a, b) = ("a\u{FE00}", "a\u{E0001}");
+
+        let opt = CollationOptions::default();
+        assert_eq!(collate_no_tiebreak(a, b, opt), Ordering::Equal);
+
+        // UTF-8 bytes and chars agree, and UTF-16 code units don't
+        assert_eq!(a.cmp(b), Ordering::Less);
+        assert_eq!(a.encode_utf16().cmp(b.encode_utf16()), Ordering::Greater);
+
+        for tiebreak in [TiebreakMode::Utf8Bytes, TiebreakMode::Scalar] {
+            let opt = CollationOptions {
+                tiebreak,
+                ..Default::default()
+            };
+
+            assert_eq!(collate(a, b, opt), Ordering::Less);
+            assert_eq!(collate(b, a, opt), Ordering::Greater);
+            assert_eq!(
+                collate_detailed(a, b, opt),
+                (Ordering::Less, TieReason::EqualKeys)
+            );
+
+            let a_utf16: Vec<u16> = a.encode_utf16().collect();
+            let b_utf16: Vec<u16> = b.encode_utf16().collect();
+            assert_eq!(collate_utf16(&a_utf16, &b_utf16, opt), Ordering::Less);
+        }
+
+        let opt = CollationOptions {
+            tiebreak: TiebreakMode::None,
+            ..Default::default()
+        };
+
+        assert_eq!(collate(a, b, opt), Ordering::Equal);
+        assert_eq!(collate_reversed_elements(a, b, opt), Ordering::Equal);
+        assert_eq!(
+            collate_detailed(a, b, opt),
+            (Ordering::Equal, TieReason::EqualKeys)
+        );
+
+        // So a stable sort keeps the original order
+        let mut v = [b, "b", a];
+        v.sort_by(|x, y| collate(x, y, opt));
+        assert_eq!(v, [b, a, "b"]);
+    }
+
+    #[test]
+    fn caseless_with_case_tiebreak() {
+        let opt = CollationOptions::default();
+
+        for case_first in [CaseFirst::Lower, CaseFirst::Upper] {
+            let compare = |a, b| collate_caseless_case_tiebreak(a, b, case_first, opt);
+
+            // Case alone doesn't separate them from anything else...
+            assert_eq!(compare("Apple", "apples"), Ordering::Less);
+            assert_eq!(compare("apple", "Äpple"), Ordering::Less);
+            assert_eq!(compare("APPLE", "äpple"), Ordering::Less);
+
+            // ...but it still decides between them, the same way every time
+            let first = if case_first == CaseFirst::Lower {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            };
+            assert_eq!(compare("apple", "Apple"), first);
+            assert_eq!(compare("Apple", "apple"), first.reverse());
+            assert_eq!(compare("Apple", "Apple"), Ordering::Equal);
+
+            // The first case difference decides
+            assert_eq!(compare("aPple", "apPle"), first.reverse());
+
+            // After that, the usual tiebreaker
+            assert_eq!(compare("apple", "apple\u{FE00}"), Ordering::Less);
+        }
+
+        let mut words = ["apples", "Apple", "äpple", "apple", "APPLE", "Äpple"];
+
+        words.sort_by(|a, b| collate_caseless_case_tiebreak(a, b, CaseFirst::Lower, opt));
+        assert_eq!(
+            words,
+            ["apple", "Apple", "APPLE", "äpple", "Äpple", "apples"]
+        );
+
+        words.sort_by(|a, b| collate_caseless_case_tiebreak(a, b, CaseFirst::Upper, opt));
+        assert_eq!(
+            words,
+            ["APPLE", "Apple", "apple", "Äpple", "äpple", "apples"]
+        );
+
+        // At the tertiary level, case is one difference among others, e.g., width, and the first
+        // of any of them decides. Here, only case does
+        assert_eq!(collate("applE", "\u{FF41}pple", opt), Ordering::Less);
+        assert_eq!(
+            collate_caseless_case_tiebreak("applE", "\u{FF41}pple", CaseFirst::Lower, opt),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn historic_folding() {
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let table = builtin_table(keys_source);
+
+            let plain = CollationOptions {
+                keys_source,
+                ..Default::default()
+            };
+            let folding = CollationOptions {
+                historic_folding: true,
+                ..plain
+            };
+            let plain_primary = CollationOptions {
+                strength: CollationStrength::Primary,
+                ..plain
+            };
+            let primary = CollationOptions {
+                strength: CollationStrength::Primary,
+                ..folding
+            };
+            let secondary = CollationOptions {
+                strength: CollationStrength::Secondary,
+                ..folding
+            };
+
+            // The tables already take care of long s, with or without the option
+            for opt in [plain_primary, primary] {
+                assert_eq!(collate_no_tiebreak("ſong", "song", opt), Ordering::Equal);
+                assert_eq!(
+                    collate_no_tiebreak("ﬅrange", "strange", opt),
+                    Ordering::Equal
+                );
+            }
+
+            for (historic, modern) in [
+                ("ẜong", "song"),
+                ("ẝong", "song"),
+                ("aɼe", "are"),
+                ("ƿord", "word"),
+                ("Ƿord", "Word"),
+                ("ꝫc", "etc"),
+                ("Ꝫc", "ETc"),
+                ("ꝭ", "is"),
+                ("ꝯtra", "contra"),
+                ("ꝰ", "us"),
+            ] {
+                assert_ne!(
+                    collate_no_tiebreak(historic, modern, plain_primary),
+                    Ordering::Equal,
+                    "{historic}"
+                );
+
+                assert_eq!(
+                    collate_no_tiebreak(historic, modern, primary),
+                    Ordering::Equal
+                );
+                assert_eq!(
+                    collate_no_tiebreak(historic, modern, secondary),
+                    Ordering::Equal
+                );
+
+                // A variant, as in the tables, right after the modern letters
+                assert_eq!(collate(historic, modern, folding), Ordering::Greater);
+            }
+
+            assert_eq!(collate("ẜb", "sc", folding), Ordering::Less);
+            assert_eq!(collate("ẜb", "sc", plain), Ordering::Greater);
+
+            // The fold works as a single code point would
+            for (cp, _) in HISTORIC_FOLDS {
+                assert!(table.singles.contains_key(&cp));
+                assert!(!table.starts_contraction(cp));
+                assert!(cp >= LOW_END);
+            }
+
+            // Weight overrides still come first
+            let singles = &table.singles;
+            let overrides = BTreeMap::from([(0x1BF, singles[&('v' as u32)].clone())]);
+            let overriding = CollationOptions {
+                weight_overrides: Some(&overrides),
+                ..primary
+            };
+            assert_eq!(collate_no_tiebreak("ƿ", "v", overriding), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn grapheme_tiebreak() {
+        // Both look like a single "a": the variation selector extends it, but the zero-width space
+        // is a cluster of its own. Either way, they're ignorable
+        let (a, b) = ("a\u{FE00}", "a\u{200B}");
+
+        let opt = CollationOptions {
+            tiebreak: TiebreakMode::Grapheme,
+            ..Default::default()
+        };
+
+        assert_eq!(collate_no_tiebreak(a, b, opt), Ordering::Equal);
+
+        // By bytes (or chars), U+200B comes first
+        assert_eq!(
+            collate(a, b, CollationOptions::default()),
+            Ordering::Greater
+        );
+
+        // By cluster count, the one that's really a single character does
+        assert_eq!(collate(a, b, opt), Ordering::Less);
+        assert_eq!(collate(b, a, opt), Ordering::Greater);
+        assert_eq!(
+            collate_detailed(a, b, opt),
+            (Ordering::Less, TieReason::EqualKeys)
+        );
+
+        let mut map = HashMap::new();
+        map.insert(b.to_string(), 1);
+        map.insert(a.to_string(), 0);
+        map.insert("a".to_string(), 2);
+
+        let sorted: Vec<_> = sorted_entries(&map, opt)
+            .into_iter()
+            .map(|(_, v)| *v)
+            .collect();
+        assert_eq!(sorted, [2, 0, 1]);
+
+        // The same number of clusters falls back to chars
+        assert_eq!(collate("a\u{FE00}", "a\u{FE01}", opt), Ordering::Less);
+    }
+
+    #[test]
+    fn leading_articles() {
+        let opt = CollationOptions {
+            ignore_leading_articles: &["the", "a", "an"],
+            ..Default::default()
+        };
+
+        assert_eq!(collate_no_tiebreak("The Who", "Who", opt), Ordering::Equal);
+        assert_eq!(sort_key("the  who", opt), sort_key("who", opt));
+        assert_eq!(collate("The Beatles", "Aerosmith", opt), Ordering::Greater);
+        assert_eq!(collate("The Beatles", "Cream", opt), Ordering::Less);
+        assert_eq!(collate("An Apple", "Banana", opt), Ordering::Less);
+        assert_eq!(index_bucket("The Who", opt), Some('W'));
+
+        // Only a whole word, and only one
+        assert_eq!(collate("Theremin", "Sting", opt), Ordering::Greater);
+        assert_eq!(collate("Another", "Bob", opt), Ordering::Less);
+        assert_eq!(collate_no_tiebreak("The The", "The", opt), Ordering::Equal);
+
+        // Nothing but an article stays as it is
+        assert_eq!(collate("The", "Sting", opt), Ordering::Greater);
+        assert_eq!(collate("A", "B", opt), Ordering::Less);
+        assert_eq!(collate("A ", "B", opt), Ordering::Less);
+
+        let mut titles = [
+            "The Who",
+            "Abba",
+            "A Tribe Called Quest",
+            "The Beatles",
+            "Blondie",
+        ];
+        titles.sort_by(|a, b| collate(a, b, opt));
+        assert_eq!(
+            titles,
+            [
+                "Abba",
+                "The Beatles",
+                "Blondie",
+                "A Tribe Called Quest",
+                "The Who"
+            ]
+        );
+
+        let french = CollationOptions {
+            ignore_leading_articles: &["le", "la", "les", "l'", "l\u{2019}"],
+            ..Default::default()
+        };
+
+        let mut titles = [
+            "Les Misérables",
+            "L'Étranger",
+            "La Peste",
+            "Le Petit Prince",
+            "L\u{2019}Écume des jours",
+            "Madame Bovary",
+        ];
+        titles.sort_by(|a, b| collate(a, b, french));
+        assert_eq!(
+            titles,
+            [
+                "L\u{2019}Écume des jours",
+                "L'Étranger",
+                "Madame Bovary",
+                "Les Misérables",
+                "La Peste",
+                "Le Petit Prince",
+            ]
+        );
+
+        // Without the option, nothing changes
+        let plain = CollationOptions::default();
+        assert_eq!(collate("The Who", "Abba", plain), Ordering::Greater);
+        assert_eq!(collate("The Who", "Zappa", plain), Ordering::Less);
+    }
+
+    #[test]
+    fn weights_collation_cmp() {
+        let table = builtin_table(KeysSource::Cldr);
+        let single = |c: char| table.singles[&(c as u32)][0];
+
+        let (hyphen, a, upper_a, acute) =
+            (single('-'), single('a'), single('A'), single('\u{301}'));
+
+        // The derived order puts anything variable last
+        assert!(hyphen.variable);
+        assert_eq!(hyphen.cmp(&a), Ordering::Greater);
+
+        for shifting in [false, true] {
+            assert_eq!(hyphen.collation_cmp(&a, shifting), Ordering::Less);
+            assert_eq!(a.collation_cmp(&upper_a, shifting), Ordering::Less);
+            assert_eq!(a.collation_cmp(&a, shifting), Ordering::Equal);
+
+            // A mark alone has only a secondary, which is less than any primary
+            assert_eq!(acute.collation_cmp(&a, shifting), Ordering::Less);
+            assert_eq!(a.collation_cmp(&acute, shifting), Ordering::Greater);
+        }
+
+        // Shifted, the hyphen is only at the fourth level, below a mark
+        assert_eq!(hyphen.collation_cmp(&acute, false), Ordering::Greater);
+        assert_eq!(hyphen.collation_cmp(&acute, true), Ordering::Less);
+
+        // It matches sort keys for one-char strings
+        for shifting in [false, true] {
+            let opt = CollationOptions {
+                shifting,
+                ..Default::default()
+            };
+
+            for (x, y) in [('-', 'a'), ('a', 'A'), ('\u{301}', '-'), ('b', 'a')] {
+                let key = |c: char| sort_key(c.encode_utf8(&mut [0; 4]), opt);
+
+                assert_eq!(
+                    single(x).collation_cmp(&single(y), shifting),
+                    key(x).cmp(&key(y)),
+                    "{x:?} {y:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fold_secondaries() {
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let secondary = |mark: &str| {
+                let opt = CollationOptions {
+                    keys_source,
+                    ..Default::default()
+                };
+                collation_elements(mark, opt)[0][1]
+            };
+
+            let fold: BTreeSet<u16> = [secondary("\u{301}"), secondary("\u{300}")].into();
+
+            let opt = CollationOptions {
+                keys_source,
+                strength: CollationStrength::Secondary,
+                fold_secondaries: Some(&fold),
+                ..Default::default()
+            };
+
+            assert_eq!(collate_no_tiebreak("é", "è", opt), Ordering::Equal);
+            assert_eq!(
+                collate_no_tiebreak("résumé", "rèsumè", opt),
+                Ordering::Equal
+            );
+            assert_eq!(sort_key("é", opt), sort_key("e\u{300}", opt));
+
+            // The folded marks still count as accents, and the cedilla is as before
+            assert_eq!(collate("e", "é", opt), Ordering::Less);
+            assert_ne!(collate_no_tiebreak("ç", "c\u{301}", opt), Ordering::Equal);
+            assert_ne!(collate_no_tiebreak("ç", "c", opt), Ordering::Equal);
+
+            for (a, b) in [("é", "è"), ("ç", "c\u{301}"), ("ça", "ca"), ("e", "è")] {
+                assert_eq!(
+                    sort_key(a, opt).cmp(&sort_key(b, opt)),
+                    collate_no_tiebreak(a, b, opt),
+                    "{a:?} {b:?}"
+                );
+            }
+
+            let plain = CollationOptions {
+                keys_source,
+                strength: CollationStrength::Secondary,
+                ..Default::default()
+            };
+            assert_ne!(collate_no_tiebreak("é", "è", plain), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn contraction_trie() {
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let table = builtin_table(keys_source);
+            let trie = &table.contractions;
+
+            for (key, row) in &table.multis {
+                let root = trie.root(key[0]).unwrap();
+                assert_eq!(trie.get(root, &key[1..]), Some(row.as_slice()));
+
+                // A longer match wins, and anything after the key is left alone
+                let mut input = key.to_vec();
+                input.push(0x61);
+                let (len, found) = trie.longest_match(root, &input[1..]).unwrap();
+                assert!(len >= key.len());
+                assert_eq!(found, table.multis[&input[..len]].as_slice());
+            }
+
+            // Every starter has a root, and nothing else does
+            assert_eq!(trie.roots.len(), table.lookahead.len());
+            assert!(trie.root('a' as u32).is_none());
+        }
+
+        // Replacing a contraction updates the trie too
+        let mut table = CollationTable::new(KeysSource::Cldr);
+        let breve = table.multis[&array_vec!([u32; 3] => 0x438, 0x306)].clone();
+        let w = table.singles[&('w' as u32)].clone();
+
+        table.add_mapping("\u{438}\u{306}", &w);
+        table.add_mapping("wx", &breve);
+
+        let root = table.contractions.root(0x438).unwrap();
+        assert_eq!(table.contractions.get(root, &[0x306]), Some(w.as_slice()));
+        let root = table.contractions.root('w' as u32).unwrap();
+        assert_eq!(
+            table
+                .contractions
+                .longest_match(root, &['x' as u32, 'y' as u32]),
+            Some((2, breve.as_slice()))
+        );
+    }
+
+    #[test]
+    fn table_fingerprints() {
+        // These change only with the table data
+        assert_eq!(table_fingerprint(KeysSource::Cldr), 0xFD18_FA5D_EC95_C19A);
+        assert_eq!(table_fingerprint(KeysSource::Ducet), 0xB8F4_6BA1_2E80_7674);
+
+        // A copy (with its maps in another iteration order) is the same
+        let mut table = CollationTable::new(KeysSource::Cldr);
+        table.singles = table.singles.into_iter().collect();
+        table.multis = table.multis.into_iter().collect();
+        assert_eq!(table.fingerprint(), table_fingerprint(KeysSource::Cldr));
+
+        // Any tailoring changes it
+        let w_weights = table.singles[&('w' as u32)].clone();
+        table.add_mapping("v", &w_weights);
+        let tailored = table.fingerprint();
+        assert_ne!(tailored, table_fingerprint(KeysSource::Cldr));
+
+        table.add_context_mapping("a", "b", &w_weights);
+        assert_ne!(table.fingerprint(), tailored);
+
+        let filtered = CollationTable::filtered(KeysSource::Cldr, &[0..=0x7F]);
+        assert_ne!(filtered.fingerprint(), table_fingerprint(KeysSource::Cldr));
+    }
+
+    #[test]
+    fn bytes_with_invalid_utf8() {
+        let opt = CollationOptions::default();
+
+        // Each of these is a single U+FFFD when decoded lossily
+        let a = b"file\x80.txt";
+        let b = b"file\xFF.txt";
+
+        assert_eq!(String::from_utf8_lossy(a), String::from_utf8_lossy(b));
+        assert_eq!(
+            collate_no_tiebreak(
+                &String::from_utf8_lossy(a),
+                &String::from_utf8_lossy(b),
+                opt
+            ),
+            Ordering::Equal
+        );
+
+        let no_tiebreak = CollationOptions {
+            tiebreak: TiebreakMode::None,
+            ..Default::default()
+        };
+
+        assert_eq!(collate_bytes(a, b, no_tiebreak), Ordering::Less);
+        assert_eq!(collate_bytes(b, a, no_tiebreak), Ordering::Greater);
+        assert_ne!(
+            sort_key_from_iter(surrogate_escape(a).into_iter(), opt),
+            sort_key_from_iter(surrogate_escape(b).into_iter(), opt)
+        );
+
+        assert_eq!(surrogate_escape(b"a\xC3\xA9\xC3"), [0x61, 0xE9, 0xDCC3]);
+        assert_eq!(surrogate_escape(b"\xED\xA0\x80"), [0xDCED, 0xDCA0, 0xDC80]);
+
+        // Escaped bytes come after assigned characters; valid UTF-8 is as in collate
+        assert_eq!(collate_bytes(b"a\x80", b"ab", opt), Ordering::Greater);
+        for (x, y) in [("résumé", "resume"), ("é", "e\u{301}"), ("a😀", "a😁")] {
+            assert_eq!(
+                collate_bytes(x.as_bytes(), y.as_bytes(), opt),
+                collate(x, y, opt)
+            );
+        }
+
+        // The tiebreaker is by byte
+        assert_eq!(
+            collate_bytes(b"\xC3\xA9", b"e\xCC\x81", opt),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn sorted_map_entries() {
+        let opt = CollationOptions::default();
+
+        let map: HashMap<String, usize> = [
+            "peach", "Péché", "péché", "pêche", "PÉCHÉ", "Peach", "e\u{301}", "é", "-x", "x", "",
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, s)| (s.to_string(), i))
+        .collect();
+
+        let entries = sorted_entries(&map, opt);
+        assert_eq!(entries.len(), map.len());
+
+        for (k, v) in &entries {
+            assert_eq!(map[*k], **v);
+        }
+
+        let mut expected: Vec<&String> = map.keys().collect();
+        expected.sort_by(|a, b| collate(a, b, opt));
+
+        let keys: Vec<&String> = entries.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, expected);
+
+        // Canonically equivalent keys tie, apart from the tiebreaker
+        let pos = |s: &str| keys.iter().position(|k| *k == s).unwrap();
+        assert_eq!(pos("é"), pos("e\u{301}") + 1);
+    }
+
+    #[test]
+    fn sorting_lines() {
+        let input = "peach\nPéché\r\npéché\n\npêche\n-x\nx\ne\u{301}\né\nPeach";
+
+        let mut output = Vec::new();
+        sort_lines(input.as_bytes(), &mut output, CollationOptions::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        let mut expected: Vec<&str> = input.lines().collect();
+        expected.sort_by(|a, b| collate(a, b, CollationOptions::default()));
+
+        assert!(output.ends_with('\n'));
+        assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+        assert_eq!(&expected[..3], ["", "e\u{301}", "é"]);
+
+        // Without a tiebreaker, the input order is kept
+        let opt = CollationOptions {
+            tiebreak: TiebreakMode::None,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        sort_lines("b\né\na\ne\u{301}\n".as_bytes(), &mut output, opt).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "a\nb\né\ne\u{301}\n");
+
+        // Nothing in, nothing out
+        let mut output = Vec::new();
+        sort_lines(&b""[..], &mut output, opt).unwrap();
+        assert!(output.is_empty());
+
+        let err = sort_lines(&b"a\n\xFF\n"[..], Vec::new(), opt).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn validate_options() {
+        let fold = BTreeSet::new();
+
+        let rejected = [
+            (
+                CollationOptions {
+                    strength: CollationStrength::Primary,
+                    backwards_secondary: true,
+                    ..Default::default()
+                },
+                OptionsError::StrengthTooLow {
+                    option: "backwards_secondary",
+                    needs: CollationStrength::Secondary,
+                },
+            ),
+            (
+                CollationOptions {
+                    strength: CollationStrength::Primary,
+                    compress_secondaries: true,
+                    ..Default::default()
+                },
+                OptionsError::StrengthTooLow {
+                    option: "compress_secondaries",
+                    needs: CollationStrength::Secondary,
+                },
+            ),
+            (
+                CollationOptions {
+                    strength: CollationStrength::Primary,
+                    fold_secondaries: Some(&fold),
+                    ..Default::default()
+                },
+                OptionsError::StrengthTooLow {
+                    option: "fold_secondaries",
+                    needs: CollationStrength::Secondary,
+                },
+            ),
+            (
+                CollationOptions {
+                    strength: CollationStrength::Secondary,
+                    first_letter_case: true,
+                    ..Default::default()
+                },
+                OptionsError::StrengthTooLow {
+                    option: "first_letter_case",
+                    needs: CollationStrength::Tertiary,
+                },
+            ),
+            (
+                CollationOptions {
+                    strength: CollationStrength::Secondary,
+                    variation_selectors: true,
+                    ..Default::default()
+                },
+                OptionsError::StrengthTooLow {
+                    option: "variation_selectors",
+                    needs: CollationStrength::Tertiary,
+                },
+            ),
+            (
+                CollationOptions {
+                    strength: CollationStrength::Tertiary,
+                    quaternary: QuaternarySource::CodePoints,
+                    ..Default::default()
+                },
+                OptionsError::StrengthTooLow {
+                    option: "quaternary: CodePoints",
+                    needs: CollationStrength::Quaternary,
+                },
+            ),
+            (
+                CollationOptions {
+                    shifting: false,
+                    max_variable: Some(MaxVariable::Symbol),
+                    ..Default::default()
+                },
+                OptionsError::Conflict("max_variable", "shifting: false"),
+            ),
+            (
+                CollationOptions {
+                    case_fold: true,
+                    first_letter_case: true,
+                    ..Default::default()
+                },
+                OptionsError::Conflict("first_letter_case", "case_fold"),
+            ),
+        ];
+
+        for (opt, err) in rejected {
+            assert_eq!(opt.validate(), Err(err));
+        }
+
+        assert_eq!(
+            rejected[0].1.to_string(),
+            "backwards_secondary needs at least Secondary strength"
+        );
+        assert_eq!(
+            rejected[7].1.to_string(),
+            "first_letter_case has no effect with case_fold"
+        );
+
+        // The defaults, the locale presets, and anything at a high enough strength are fine
+        assert_eq!(CollationOptions::default().validate(), Ok(()));
+
+        for locale in ["fr", "ja", "ru", "lt", "en"] {
+            assert_eq!(locale_options(locale).validate(), Ok(()), "{locale}");
+        }
+
+        for strength in [CollationStrength::Secondary, CollationStrength::Quaternary] {
+            let opt = CollationOptions {
+                strength,
+                backwards_secondary: true,
+                compress_secondaries: true,
+                fold_secondaries: Some(&fold),
+                max_variable: Some(MaxVariable::Space),
+                ..Default::default()
+            };
+            assert_eq!(opt.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn backwards_tertiary() {
+        let backwards = CollationOptions {
+            backwards_tertiary: true,
+            ..Default::default()
+        };
+
+        let levels = |key: SortKey| {
+            key.as_slice()
+                .split(|w| *w == 0)
+                .map(<[u16]>::to_vec)
+                .collect::<Vec<_>>()
+        };
+
+        for s in ["aB", "Ab", "côTé", "x-Y z"] {
+            let forward = levels(sort_key(s, CollationOptions::default()));
+            let reversed = levels(sort_key(s, backwards));
+
+            assert_eq!(forward.len(), reversed.len());
+            assert_eq!(forward[0], reversed[0]);
+            assert_eq!(forward[1], reversed[1]);
+            assert_eq!(forward[3], reversed[3]);
+
+            let mut tertiary = forward[2].clone();
+            tertiary.reverse();
+            assert_eq!(tertiary, reversed[2], "{s}");
+        }
+
+        // The last case difference counts the most
+        assert_eq!(
+            collate("aB", "Ab", CollationOptions::default()),
+            Ordering::Less
+        );
+        assert_eq!(collate("aB", "Ab", backwards), Ordering::Greater);
+
+        // Which doesn't touch the secondary level, or reverse it along with backwards_secondary
+        let both = CollationOptions {
+            backwards_secondary: true,
+            ..backwards
+        };
+        assert_eq!(collate("côte", "coté", backwards), Ordering::Greater);
+        assert_eq!(collate("côte", "coté", both), Ordering::Less);
+        assert_eq!(collate("cOtE", "coTe", both), Ordering::Greater);
+    }
+
+    #[test]
+    fn collate_with_swapped_letters() {
+        let opt = CollationOptions::default();
+
+        // Swap the weights of v and w (and their capitals)
+        let mut table = CollationTable::new(KeysSource::Cldr);
+        for (x, y) in [("v", "w"), ("V", "W")] {
+            let x_weights = table.singles[&(x.chars().next().unwrap() as u32)].clone();
+            let y_weights = table.singles[&(y.chars().next().unwrap() as u32)].clone();
+
+            table.add_mapping(x, &y_weights);
+            table.add_mapping(y, &x_weights);
+        }
+
+        assert_eq!(collate("vase", "wasp", opt), Ordering::Less);
+        assert_eq!(
+            collate_with_table("vase", "wasp", &table, opt),
+            Ordering::Greater
+        );
+        assert_eq!(
+            collate_with_table("Vase", "wasp", &table, opt),
+            Ordering::Greater
+        );
+
+        // Decomposed accented forms follow along, and everything else is as before
+        assert_eq!(
+            collate_with_table("w\u{302}", "v", &table, opt),
+            Ordering::Less
+        );
+        assert_eq!(collate_with_table("u", "w", &table, opt), Ordering::Less);
+        assert_eq!(collate_with_table("v", "x", &table, opt), Ordering::Less);
+
+        // The options still apply: at primary strength, case doesn't matter
+        let primary = CollationOptions {
+            strength: CollationStrength::Primary,
+            ..opt
+        };
+
+        assert_eq!(
+            collate_no_tiebreak("Vase", "vase", primary),
+            Ordering::Equal
+        );
+        assert_eq!(
+            collate_with_table("Wasp", "vase", &table, primary),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn codepoint_order_disagreement() {
+        let opt = CollationOptions::default();
+
+        // Code point order puts all capitals first
+        assert!(disagrees_with_codepoint_order("Z", "a", opt));
+        assert!(disagrees_with_codepoint_order("é", "f", opt));
+
+        assert!(!disagrees_with_codepoint_order("abc", "abd", opt));
+        assert!(!disagrees_with_codepoint_order("abc", "abc", opt));
+        assert!(!disagrees_with_codepoint_order("apple", "banana", opt));
+    }
+
+    #[test]
+    #[cfg(feature = "compressed-tables")]
+    fn compressed_tables_match() {
+        for (table, [low, singles, multis]) in [
+            (&*DUCET, ["low", "singles", "multis"]),
+            (&*CLDR, ["low_cldr", "singles_cldr", "multis_cldr"]),
+        ] {
+            let raw = |name: &str| std::fs::read(format!("src/bincode/{name}")).unwrap();
+
+            let low: TableMap<u32, Weights> = bincode::deserialize(&raw(low)).unwrap();
+            let singles: TableMap<u32, Vec<Weights>> = bincode::deserialize(&raw(singles)).unwrap();
+            let multis: TableMap<ArrayVec<[u32; 3]>, Vec<Weights>> =
+                bincode::deserialize(&raw(multis)).unwrap();
+
+            // Same tables, so necessarily the same results
+            assert!((0..LOW_END).all(|cp| table.low[cp as usize] == low.get(&cp).copied()));
+            assert_eq!(table.singles, singles);
+            assert_eq!(table.multis, multis);
+        }
+    }
+
+    #[test]
+    fn collated_string_set() {
+        use std::collections::BTreeSet;
+
+        let opt = CollationOptions::default();
+
+        let set: BTreeSet<CollatedString> = ["zeta", "Éclair", "apple", "eclair", "Apple", "apple"]
+            .iter()
+            .map(|s| CollatedString(s.to_string(), opt))
+            .collect();
+
+        let sorted: Vec<&str> = set.iter().map(|s| s.0.as_str()).collect();
+        assert_eq!(sorted, ["apple", "Apple", "eclair", "Éclair", "zeta"]);
+
+        let mut borrowed: Vec<CollatedStr> =
+            sorted.iter().rev().map(|s| CollatedStr(s, opt)).collect();
+        borrowed.sort();
+
+        assert!(borrowed.iter().map(|s| s.0).eq(sorted));
+
+        // Equality agrees with the ordering, including when ties aren't broken
+        let untied = CollationOptions {
+            tiebreak: TiebreakMode::None,
+            ..opt
+        };
+
+        for (o, equal) in [(opt, false), (untied, true)] {
+            let (a, b) = (CollatedStr("a", o), CollatedStr("a\u{200B}", o));
+            assert_eq!(a == b, equal);
+            assert_eq!(a.cmp(&b) == Ordering::Equal, equal);
+
+            let (a, b) = (
+                CollatedString("a".to_string(), o),
+                CollatedString("a\u{200B}".to_string(), o),
+            );
+            assert_eq!(a == b, equal);
+            assert_eq!(a.cmp(&b) == Ordering::Equal, equal);
+        }
+
+        let set: BTreeSet<CollatedStr> = ["a", "a\u{200B}"]
+            .into_iter()
+            .map(|s| CollatedStr(s, untied))
+            .collect();
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn collation_map() {
+        let opt = CollationOptions::default();
+        let keys = [
+            "zeta",
+            "Éclair",
+            "apple",
+            "eclair",
+            "Apple",
+            "éclair",
+            "e\u{301}clair",
+            "ab",
+            "Ab",
+        ];
+
+        let mut map = CollationMap::new(opt);
+        assert!(map.is_empty());
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(map.insert(key, i), None);
+        }
+
+        // Canonically equivalent keys are still distinct, as they are in collate
+        assert_eq!(map.len(), keys.len());
+
+        let mut sorted = keys;
+        sorted.sort_by(|a, b| collate(a, b, opt));
+
+        assert!(map.iter().map(|(key, _)| key).eq(sorted));
+        assert!(map.iter().all(|(key, value)| keys[*value] == key));
+
+        assert_eq!(map.get("Apple"), Some(&4));
+        assert_eq!(map.get("APPLE"), None);
+
+        assert_eq!(map.insert("Apple", 10), Some(4));
+        assert_eq!(map.remove("Apple"), Some(10));
+        assert_eq!(map.get("Apple"), None);
+        assert_eq!(map.len(), keys.len() - 1);
+    }
+
+    #[test]
+    fn find_all_matches() {
+        let opt = CollationOptions::default();
+
+        // Adjacent, accent- and case-insensitive
+        let haystack = "Éléele";
+        let found = find_all(haystack, "ele", opt);
+
+        assert_eq!(found, [0..5, 5..8]);
+        assert_eq!(&haystack[found[0].clone()], "Élé");
+
+        // Decomposed accents stay with their letters, and a match can't start on a mark
+        let haystack = "xe\u{301}le\u{323}\u{302} \u{301}ele";
+        let found = find_all(haystack, "ELE", opt);
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(&haystack[found[0].clone()], "e\u{301}le\u{323}\u{302}");
+        assert_eq!(&haystack[found[1].clone()], "ele");
+
+        // Not overlapping
+        assert_eq!(find_all("aaaa", "aa", opt), [0..2, 2..4]);
+        assert_eq!(find_all("aaa", "aa", opt), vec![0..2]);
+
+        // Shifted punctuation inside a match is skipped, but not taken on at either end
+        assert_eq!(find_all("-e-le-", "ele", opt), vec![1..5]);
+
+        // Not shifting, punctuation counts
+        let non_ignorable = CollationOptions {
+            shifting: false,
+            ..opt
+        };
+        assert!(find_all("-e-le-", "ele", non_ignorable).is_empty());
+
+        assert!(find_all("abc", "d", opt).is_empty());
+        assert!(find_all("abc", "", opt).is_empty());
+        assert!(find_all("", "a", opt).is_empty());
+    }
+
+    #[test]
+    fn default_ignorables() {
+        let ignoring = CollationOptions {
+            ignore_default_ignorables: true,
+            ..Default::default()
+        };
+
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let opt = CollationOptions {
+                keys_source,
+                shifting: false,
+                ..Default::default()
+            };
+
+            // Soft hyphen, zero-width space, ZWNJ, ZWJ: ignorable in the tables already
+            for c in ['\u{AD}', '\u{200B}', '\u{200C}', '\u{200D}'] {
+                let s = format!("wo{c}rd");
+
+                assert!(collation_elements(&c.to_string(), opt)
+                    .iter()
+                    .all(|elem| elem.iter().all(|w| *w == 0)));
+                assert_eq!(collate_no_tiebreak(&s, "word", opt), Ordering::Equal);
+                assert_eq!(
+                    collate_no_tiebreak(
+                        &s,
+                        "word",
+                        CollationOptions {
+                            keys_source,
+                            ..ignoring
+                        }
+                    ),
+                    Ordering::Equal
+                );
+            }
+
+            // A Hangul filler and an unassigned tag aren't, unless forced
+            for c in ['\u{3164}', '\u{FFA0}', '\u{E0FFF}'] {
+                let s = format!("wo{c}rd");
+                let forced = CollationOptions {
+                    keys_source,
+                    shifting: false,
+                    ..ignoring
+                };
+
+                assert_ne!(collate_no_tiebreak(&s, "word", opt), Ordering::Equal);
+                assert_eq!(collate_no_tiebreak(&s, "word", forced), Ordering::Equal);
+                assert_eq!(sort_key(&s, forced), sort_key("word", forced));
+            }
+        }
+
+        // Even a table that gives them weights (here, those of x, which sorts after s)
+        let mut table = CollationTable::new(KeysSource::Cldr);
+        let x = table.singles[&('x' as u32)].clone();
+
+        for key in ["\u{AD}", "\u{200B}", "\u{200C}", "\u{200D}"] {
+            table.add_mapping(key, &x);
+
+            let s = format!("wo{key}rd");
+            let opt = CollationOptions::default();
+
+            assert_eq!(
+                collate_with_table(&s, "wos", &table, opt),
+                Ordering::Greater
+            );
+            assert_eq!(
+                collate_with_table(&s, "wos", &table, ignoring),
+                Ordering::Less
+            );
+        }
+    }
+
+    #[test]
+    fn cgj_blocks_reordering_and_contractions() {
+        let opt = CollationOptions::default();
+        let cps = |s: &str| s.chars().map(|c| c as u32).collect::<Vec<_>>();
+
+        // CGJ is a starter, so marks don't get reordered across it
+        assert_eq!(get_nfd("a\u{301}\u{316}", opt), cps("a\u{316}\u{301}"));
+        assert_eq!(
+            get_nfd("a\u{301}\u{34F}\u{316}", opt),
+            cps("a\u{301}\u{34F}\u{316}")
+        );
+        assert_eq!(
+            get_nfd_with_offsets("a\u{301}\u{34F}\u{316}", opt).0,
+            cps("a\u{301}\u{34F}\u{316}")
+        );
+
+        // It's completely ignorable in itself
+        assert_eq!(
+            collation_elements("\u{34F}", opt),
+            [ArrayVec::from([0, 0, 0, 0])]
+        );
+
+        // But it blocks contractions, contiguous and discontiguous
+        let short_i = primary_weights("й", opt);
+        let i = primary_weights("и", opt);
+        assert_ne!(short_i, i);
+
+        assert_eq!(primary_weights("и\u{306}", opt), short_i);
+        assert_eq!(primary_weights("и\u{316}\u{306}", opt), short_i);
+
+        assert_eq!(primary_weights("и\u{34F}\u{306}", opt), i);
+        assert_eq!(primary_weights("и\u{34F}\u{316}\u{306}", opt), i);
+
+        // So the breve ends up as a separate secondary weight
+        assert_ne!(collate("и\u{34F}\u{306}", "и", opt), Ordering::Equal);
+        assert_ne!(
+            collate_no_tiebreak("и\u{34F}\u{306}", "й", opt),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn many_discontiguous_matches() {
+        // Each и + U+0316 + breve is a discontiguous match for й; the result should be the same
+        // as for the precomposed form, however many there are
+        for opt in [
+            CollationOptions::default(),
+            CollationOptions {
+                keys_source: KeysSource::Ducet,
+                ..Default::default()
+            },
+        ] {
+            let decomposed = "и\u{316}\u{306}".repeat(100);
+            let precomposed = "й\u{316}".repeat(100);
+
+            assert_eq!(sort_key(&decomposed, opt), sort_key(&precomposed, opt));
+            assert_eq!(divergence_index(&decomposed, &precomposed, opt), None);
+
+            // And a long run of marks after a contraction starter
+            let marks: String = "\u{316}\u{301}\u{345}\u{334}\u{327}\u{308}\u{306}"
+                .chars()
+                .cycle()
+                .take(100)
+                .collect();
+            let run = format!("и{marks}");
+
+            assert_eq!(
+                sort_key(&run, opt),
+                sort_key(&run.nfc().collect::<String>(), opt)
+            );
+        }
+    }
+
+    #[test]
+    fn decompose_and_reorder_match_nfd() {
+        let check = |s: &str| {
+            let mut code_points: Vec<u32> = s.chars().map(|c| c as u32).collect();
+            decompose(&mut code_points);
+            canonical_reorder(&mut code_points);
+
+            let expected: Vec<u32> = s.nfd().map(|c| c as u32).collect();
+            assert_eq!(code_points, expected, "{s:?}");
+        };
+
+        // Every char on its own (this includes all Hangul syllables)
+        for c in (0..=0x10_FFFF).filter_map(char::from_u32) {
+            check(c.encode_utf8(&mut [0; 4]));
+        }
+
+        for s in [
+            "a\u{301}\u{316}",
+            "ệ\u{323}",
+            "\u{1E0B}\u{323}\u{307}",
+            "한국어 ḍ̇",
+            "\u{F73}\u{F71}\u{F72}",
+            "e\u{301}\u{34F}\u{316}",
+        ] {
+            check(s);
+        }
+    }
+
+    #[test]
+    fn assert_nfd_offsets() {
+        for s in [
+            "",
+            "abc",
+            "cafe\u{301}",
+            "a\u{316}\u{301}",
+            "\u{1100}\u{1161}",
+            "e\u{301}\u{301}",
+            "e\u{301}\u{34F}\u{316}",
+        ] {
+            assert_eq!(assert_nfd(s), Ok(()), "{s:?}");
+            assert_eq!(s.nfd().collect::<String>(), s);
+        }
+
+        // Precomposed, at the start or after multibyte chars
+        assert_eq!(assert_nfd("café"), Err(3));
+        assert_eq!(assert_nfd("é"), Err(0));
+        assert_eq!(assert_nfd("ḿḿé"), Err(0));
+        assert_eq!(assert_nfd("m\u{301}m\u{301}é"), Err(6));
+        assert_eq!(assert_nfd("a한"), Err(1));
+
+        // Marks out of order, and a singleton decomposition (Ohm sign)
+        assert_eq!(assert_nfd("xa\u{301}\u{316}"), Err(4));
+        assert_eq!(assert_nfd("1\u{2126}"), Err(1));
+
+        // Ok exactly when the string is its own NFD
+        for s in ["ệ\u{323}", "\u{1E0B}\u{323}\u{307}", "\u{F73}"] {
+            let nfd: String = s.nfd().collect();
+            assert_eq!(assert_nfd(&nfd), Ok(()), "{nfd:?}");
+            assert!(assert_nfd(s).is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn fcd_supplementary_marks() {
+        // Every supplementary character with a canonical decomposition is in the FCD table, with
+        // the CCCs of the first and last code points it decomposes to
+        for c in (0x1_0000..=0x10_FFFF).filter_map(char::from_u32) {
+            let nfd: Vec<u32> = c.to_string().nfd().map(|d| d as u32).collect();
+            if nfd == [c as u32] {
+                continue;
+            }
+
+            let expected =
+                u16::from_be_bytes([get_ccc_u32(nfd[0]), get_ccc_u32(*nfd.last().unwrap())]);
+
+            assert_eq!(FCD.get(&(c as u32)), Some(&expected), "{c:?}");
+        }
+
+        // Musical symbols (e.g., U+1D15F decomposes to U+1D158 U+1D165, with a trailing CCC of
+        // 216), Kharoshthi marks, a Grantha vowel sign
+        let not_fcd = [
+            "\u{1D15F}\u{1D167}",
+            "\u{1D160}\u{1D16D}\u{1D165}",
+            "a\u{1D16D}\u{1D165}",
+            "\u{1D1BB}\u{1D167}",
+            "\u{10A38}\u{10A0D}",
+            "\u{1D165}\u{301}\u{1D167}",
+        ];
+
+        let fcd_ok = [
+            "\u{1D15F}",
+            "\u{1D15F}\u{1D16E}",
+            "\u{1D158}\u{1D165}\u{1D16D}",
+            "\u{1134B}",
+            "\u{1D1BB}\u{1D17B}",
+            "\u{10A0D}\u{10A0F}",
+        ];
+
+        for s in not_fcd {
+            assert!(!fcd(s.chars().map(|c| c as u32)), "{s:?}");
+        }
+
+        for s in fcd_ok {
+            assert!(fcd(s.chars().map(|c| c as u32)), "{s:?}");
+        }
+
+        // Either way, the result matches full NFD
+        for s in not_fcd.into_iter().chain(fcd_ok) {
+            let nfd: String = s.nfd().collect();
+
+            for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+                let opt = CollationOptions {
+                    keys_source,
+                    ..Default::default()
+                };
+
+                assert_eq!(sort_key(s, opt), sort_key(&nfd, opt), "{s:?}");
+                assert_eq!(collate_no_tiebreak(s, &nfd, opt), Ordering::Equal, "{s:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn detailed_tie_reasons() {
+        let opt = CollationOptions::default();
+
+        let cases = [
+            ("abc", "abc", TieReason::Identical),
+            // Not FCD, so this gets reordered to match the other
+            ("a\u{301}\u{316}", "a\u{316}\u{301}", TieReason::EqualNfd),
+            // Zero-width space is completely ignorable
+            ("ab\u{200B}c", "abc", TieReason::EqualKeys),
+            ("abc", "abd", TieReason::NotTied),
+        ];
+
+        for (a, b, reason) in cases {
+            let (ordering, tie_reason) = collate_detailed(a, b, opt);
+
+            assert_eq!(tie_reason, reason, "{a:?} vs. {b:?}");
+            assert_eq!(ordering, collate(a, b, opt), "{a:?} vs. {b:?}");
+        }
+
+        // With case folding, the early out catches case differences too
+        let folding = CollationOptions {
+            case_fold: true,
+            ..opt
+        };
+        assert_eq!(
+            collate_detailed("ABC", "abc", folding).1,
+            TieReason::EqualNfd
+        );
+    }
+
+    #[test]
+    fn filtered_table() {
+        let opt = CollationOptions::default();
+        let full = builtin_table(KeysSource::Cldr);
+
+        // Basic Latin and Latin-1 Supplement
+        let latin = CollationTable::filtered(KeysSource::Cldr, &[0..=0x7F, 0x80..=0xFF]);
+        assert!(latin.singles.len() < full.singles.len() / 100);
+
+        // Within those blocks, nothing changes
+        let words = [
+            "apple", "Äpfel", "zebra", "Ñandú", "æble", "façade", "l·l", "(x)",
+        ];
+
+        for a in words {
+            for b in words {
+                assert_eq!(
+                    collate_with_table(a, b, &latin, opt),
+                    collate(a, b, opt),
+                    "{a} vs. {b}"
+                );
+            }
+        }
+
+        // Cyrillic falls to implicit weights: after Latin, in code point order
+        assert_eq!(
+            collate_with_table("яблоко", "zebra", &latin, opt),
+            Ordering::Greater
+        );
+        assert_eq!(
+            collate_with_table("яблоко", "банан", &latin, opt),
+            Ordering::Greater
+        );
+        assert_eq!(collate("яблоко", "банан", opt), Ordering::Greater);
+        assert_eq!(
+            collate_with_table("ёж", "жук", &latin, opt),
+            Ordering::Greater
+        );
+        assert_eq!(collate("ёж", "жук", opt), Ordering::Less);
+    }
+
+    #[test]
+    fn variation_selectors() {
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let table = builtin_table(keys_source);
+            assert!(table
+                .singles
+                .values()
+                .flatten()
+                .all(|w| w.tertiary < VS_TERTIARY));
+
+            for shifting in [true, false] {
+                let ignoring = CollationOptions {
+                    keys_source,
+                    shifting,
+                    ..Default::default()
+                };
+                let keeping = CollationOptions {
+                    variation_selectors: true,
+                    ..ignoring
+                };
+
+                // 葛 with and without VS17; then with VS18; then the next ideograph, 葜
+                let strings = ["葛", "葛\u{E0100}", "葛\u{E0101}", "葜"];
+
+                assert_eq!(
+                    collate_no_tiebreak(strings[0], strings[1], ignoring),
+                    Ordering::Equal
+                );
+                assert_eq!(
+                    collate_no_tiebreak(strings[1], strings[2], ignoring),
+                    Ordering::Equal
+                );
+
+                for pair in strings.windows(2) {
+                    assert_eq!(
+                        collate_no_tiebreak(pair[0], pair[1], keeping),
+                        Ordering::Less,
+                        "{pair:?}"
+                    );
+                }
+
+                // Only a tertiary difference
+                let secondary = CollationOptions {
+                    strength: CollationStrength::Secondary,
+                    ..keeping
+                };
+                assert_eq!(
+                    collate_no_tiebreak(strings[0], strings[2], secondary),
+                    Ordering::Equal
+                );
+
+                // Emoji presentation selector. (After a variable character, e.g. ☺ in DUCET, it
+                // would lose its weight when shifting, like any other ignorable)
+                assert_eq!(
+                    collate_no_tiebreak("1", "1\u{FE0F}", keeping),
+                    Ordering::Less
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn similarity() {
+        let opt = CollationOptions::default();
+
+        assert_eq!(collation_similarity("cafe", "cafe", opt), 1.0);
+        assert_eq!(collation_similarity("", "", opt), 1.0);
+        assert_eq!(collation_similarity("cafe", "zebra", opt), 0.0);
+
+        let cafeteria = collation_similarity("cafe", "cafeteria", opt);
+        assert!((cafeteria - 4.0 / 9.0).abs() < f64::EPSILON);
+        assert!(cafeteria > collation_similarity("cafe", "crab", opt));
+
+        // Case matters at full strength, but not at primary
+        assert_eq!(collation_similarity("cafe", "Cafe", opt), 0.0);
+
+        let primary = CollationOptions {
+            strength: CollationStrength::Primary,
+            ..opt
+        };
+        assert_eq!(collation_similarity("café", "CAFE", primary), 1.0);
+        assert_eq!(collation_similarity("ca-fe", "cafe", primary), 1.0);
+    }
+
+    #[test]
+    fn nfkd_normalization() {
+        let nfd = CollationOptions::default();
+        let nfkd = CollationOptions {
+            normalization: NormalizationForm::Nfkd,
+            ..nfd
+        };
+
+        for (a, b) in [
+            ("ﬁle", "file"),
+            ("ａｂｃ", "abc"),
+            ("x²", "x2"),
+            ("ǆ", "dž"),
+        ] {
+            assert_ne!(
+                collate_no_tiebreak(a, b, nfd),
+                Ordering::Equal,
+                "{a} vs. {b}"
+            );
+            assert_eq!(
+                collate_no_tiebreak(a, b, nfkd),
+                Ordering::Equal,
+                "{a} vs. {b}"
+            );
+
+            assert_eq!(sort_key(a, nfkd), sort_key(b, nfkd));
+            assert_eq!(divergence_index(a, b, nfkd), None);
+
+            let (a16, b16): (Vec<u16>, Vec<u16>) =
+                (a.encode_utf16().collect(), b.encode_utf16().collect());
+            assert_eq!(collate_utf16(&a16, &b16, nfkd), a.cmp(b));
+        }
+    }
+
+    #[test]
+    fn ignore_above_bmp() {
+        let opt = CollationOptions::default();
+        let bmp_only = CollationOptions {
+            ignore_above: Some(0xFFFF),
+            ..opt
+        };
+
+        assert_ne!(collate_no_tiebreak("note", "note😀", opt), Ordering::Equal);
+        assert_eq!(
+            collate_no_tiebreak("note", "note😀", bmp_only),
+            Ordering::Equal
+        );
+        assert_eq!(
+            collate_no_tiebreak("note🎵", "note😀", bmp_only),
+            Ordering::Equal
+        );
+        assert_eq!(sort_key("𝄞 clef", bmp_only), sort_key(" clef", bmp_only));
+
+        // The tiebreaker still applies
+        assert_eq!(collate("note", "note😀", bmp_only), Ordering::Less);
+
+        // An ignored code point at the end of a shared prefix mustn't hide the variable before it.
+        // (U+20000 has implicit weights, so the table alone doesn't stop the prefix being trimmed)
+        let shifted_ducet = CollationOptions {
+            keys_source: KeysSource::Ducet,
+            ..bmp_only
+        };
+        assert_eq!(
+            collate_no_tiebreak("-𠀀\u{301}", "-𠀀\u{302}", shifted_ducet),
+            Ordering::Equal
+        );
+        assert_eq!(
+            collate_no_tiebreak("-\u{301}", "-\u{302}", shifted_ducet),
+            Ordering::Equal
+        );
+
+        // Nothing in the BMP is affected
+        assert_eq!(sort_key("ノート", bmp_only), sort_key("ノート", opt));
+    }
+
+    #[test]
+    fn secondary_compression() {
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let table = builtin_table(keys_source);
+
+            // The scheme depends on this
+            assert!(table
+                .singles
+                .values()
+                .chain(table.multis.values())
+                .flatten()
+                .all(|w| w.secondary == 0 || w.secondary >= COMMON_SECONDARY));
+        }
+
+        let plain = CollationOptions::default();
+        let compressed = CollationOptions {
+            compress_secondaries: true,
+            ..plain
+        };
+
+        // Runs of common secondaries of every length around the chunk size, ending in an accent
+        // (or two), or not
+        let mut strings: Vec<String> = Vec::new();
+
+        for n in [0, 1, 2, 14, 15, 16, 17, 31, 32, 33, 40] {
+            for ending in ["", "\u{301}", "\u{300}", "\u{301}\u{323}", "e\u{301}", "x"] {
+                strings.push(format!("{}{ending}", "e".repeat(n)));
+                strings.push(format!("\u{301}{}{ending}", "e".repeat(n)));
+            }
+        }
+
+        strings.extend(
+            conformance_strings("test-data/CollationTest_CLDR_SHIFTED_SHORT.txt")
+                .into_iter()
+                .step_by(97),
+        );
+
+        let keys: Vec<(SortKey, SortKey)> = strings
+            .iter()
+            .map(|s| (sort_key(s, plain), sort_key(s, compressed)))
+            .collect();
+
+        // Compare as big-endian bytes, as with memcmp
+        let bytes = SortKey::as_bytes;
+
+        for (i, (plain_a, compressed_a)) in keys.iter().enumerate() {
+            for (plain_b, compressed_b) in keys.iter().skip(i).step_by(7) {
+                assert_eq!(
+                    bytes(compressed_a).cmp(&bytes(compressed_b)),
+                    plain_a.cmp(plain_b),
+                    "{plain_a:X?} vs. {plain_b:X?}"
+                );
+            }
+        }
+
+        // Plain text has almost nothing but common secondaries
+        let text = "the quick brown fox jumps over the lazy dog";
+        let (plain_key, compressed_key) = (sort_key(text, plain), sort_key(text, compressed));
+
+        let level_two = |key: &SortKey| key.as_slice().split(|w| *w == 0).nth(1).unwrap().len();
+
+        assert_eq!(level_two(&plain_key), 35);
+        assert_eq!(level_two(&compressed_key), 3);
+        assert_eq!(
+            compressed_key.as_slice().len(),
+            plain_key.as_slice().len() - 32
+        );
+
+        // The other levels are untouched
+        assert_eq!(
+            compressed_key.to_strength(CollationStrength::Primary),
+            plain_key.to_strength(CollationStrength::Primary)
+        );
+    }
+
+    #[test]
+    fn prefix_trimming_changes_nothing() {
+        let files = [
+            (
+                "test-data/CollationTest_NON_IGNORABLE_SHORT.txt",
+                KeysSource::Ducet,
+                false,
+            ),
+            (
+                "test-data/CollationTest_SHIFTED_SHORT.txt",
+                KeysSource::Ducet,
+                true,
+            ),
+            (
+                "test-data/CollationTest_CLDR_NON_IGNORABLE_SHORT.txt",
+                KeysSource::Cldr,
+                false,
+            ),
+            (
+                "test-data/CollationTest_CLDR_SHIFTED_SHORT.txt",
+                KeysSource::Cldr,
+                true,
+            ),
+        ];
+
+        for (path, keys_source, shifting) in files {
+            let trimming = CollationOptions {
+                keys_source,
+                shifting,
+                ..Default::default()
+            };
+            let not_trimming = CollationOptions {
+                trim_prefix: false,
+                ..trimming
+            };
+
+            // Neighbors in the (sorted) test files often share a prefix
+            for pair in conformance_strings(path).windows(2).step_by(3) {
+                let (a, b) = (&pair[0], &pair[1]);
+
+                assert_eq!(
+                    collate(a, b, trimming),
+                    collate(a, b, not_trimming),
+                    "{a:?} vs. {b:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn prefix_ending_in_variable() {
+        // Under shifting, a mark after the hyphen loses its weights. If the shared "x-" were
+        // skipped, the marks would start the rest of each string and count at the secondary level
+        let pairs = [
+            ("x-\u{301}a", "x-\u{302}a"),
+            ("x-\u{301}", "x-\u{302}"),
+            ("x- \u{301}b", "x- \u{302}b"),
+            ("x-\u{301}a", "x-a"),
+        ];
+
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let opt = CollationOptions {
+                keys_source,
+                ..Default::default()
+            };
+            let not_trimming = CollationOptions {
+                trim_prefix: false,
+                ..opt
+            };
+
+            for (a, b) in pairs {
+                let (a_nfd, b_nfd) = (get_nfd(a, opt), get_nfd(b, opt));
+                assert_eq!(
+                    trim_prefix(&a_nfd, &b_nfd, builtin_table(keys_source), opt),
+                    0
+                );
+
+                assert_eq!(collate_no_tiebreak(a, b, opt), Ordering::Equal, "{a:?}");
+                assert_eq!(collate_no_tiebreak(a, b, not_trimming), Ordering::Equal);
+                assert_eq!(sort_key(a, opt), sort_key(b, opt));
+            }
+
+            // A prefix ending in a letter is still skipped
+            let (a_nfd, b_nfd) = (get_nfd("x-y\u{301}", opt), get_nfd("x-y\u{302}", opt));
+            assert_eq!(
+                trim_prefix(&a_nfd, &b_nfd, builtin_table(keys_source), opt),
+                3
+            );
+        }
+    }
+
+    #[test]
+    fn weight_overrides() {
+        let singles = &builtin_table(KeysSource::Cldr).singles;
+
+        // & sorts like "and"
+        let and: Vec<Weights> = "and"
+            .chars()
+            .flat_map(|c| singles[&(c as u32)].clone())
+            .collect();
+        let overrides = BTreeMap::from([('&' as u32, and)]);
+
+        let opt = CollationOptions::default();
+        let overriding = CollationOptions {
+            weight_overrides: Some(&overrides),
+            ..opt
+        };
+
+        let mut words = ["ant", "& co", "ample", "and so"];
+        words.sort_by(|a, b| collate(a, b, overriding));
+        assert_eq!(words, ["ample", "& co", "and so", "ant"]);
+
+        // Otherwise, & is ignored (when shifting), and "& co" sorts like "co"
+        words.sort_by(|a, b| collate(a, b, opt));
+        assert_eq!(words, ["ample", "and so", "ant", "& co"]);
+
+        assert_eq!(collate_no_tiebreak("&", "and", overriding), Ordering::Equal);
+        assert_eq!(
+            sort_key("rock & roll", overriding),
+            sort_key("rock and roll", overriding)
+        );
+
+        // A shared prefix ending in an override that makes it ignorable mustn't be trimmed
+        let ignorable = BTreeMap::from([('x' as u32, vec![Weights::new()])]);
+        let ignoring_x = CollationOptions {
+            weight_overrides: Some(&ignorable),
+            ..opt
+        };
+        assert_eq!(
+            collate_no_tiebreak("-x\u{301}", "-x\u{302}", ignoring_x),
+            Ordering::Equal
+        );
+
+        // An override for a mark applies whether or not it's precomposed
+        let grave = singles[&0x300].clone();
+        let acute_as_grave = BTreeMap::from([(0x301, grave)]);
+        let overriding = CollationOptions {
+            weight_overrides: Some(&acute_as_grave),
+            ..opt
+        };
+        assert_eq!(
+            collate_no_tiebreak("é", "e\u{301}", overriding),
+            Ordering::Equal
+        );
+        assert_eq!(sort_key("é", overriding), sort_key("e\u{301}", overriding));
+        assert_eq!(collate_no_tiebreak("é", "è", overriding), Ordering::Equal);
+
+        // Options compare and hash by the overrides' contents, not where they live
+        let copied = acute_as_grave.clone();
+        let same = CollationOptions {
+            weight_overrides: Some(&copied),
+            ..opt
+        };
+        assert_eq!(overriding, same);
+        assert_eq!(overriding.cmp(&same), Ordering::Equal);
+        assert_eq!(HashSet::from([overriding, same]).len(), 1);
+        assert_ne!(overriding.cmp(&opt), Ordering::Equal);
+    }
+
+    #[test]
+    fn sort_key_text_encodings() {
+        let opt = CollationOptions::default();
+
+        // Without shifting, a quaternary-strength key has only three levels
+        let non_shifting = CollationOptions {
+            shifting: false,
+            ..opt
+        };
+        let no_quaternary = CollationOptions {
+            quaternary: QuaternarySource::None,
+            ..opt
+        };
+
+        for (s, opt) in [
+            "",
+            "a",
+            "café",
+            "Ǆemal",
+            "\u{1F600} smile",
+            "ab\u{301}\u{323}c",
+        ]
+        .into_iter()
+        .flat_map(|s| [(s, opt), (s, non_shifting), (s, no_quaternary)])
+        {
+            let key = sort_key(s, opt);
+            assert_eq!(
+                key.compare(&SortKey::from_hex(&key.to_hex()).unwrap()),
+                Ordering::Equal
+            );
+
+            let hex = key.to_hex();
+            assert_eq!(hex.len(), key.as_slice().len() * 4);
+            assert_eq!(hex, hex.to_lowercase());
+            assert_eq!(SortKey::from_hex(&hex), Ok(key.clone()));
+
+            let base32 = key.to_base32();
+            assert!(base32.len() < hex.len() || hex.is_empty());
+            assert_eq!(SortKey::from_base32(&base32), Ok(key.clone()));
+            assert_eq!(SortKey::from_base32(&base32.to_uppercase()), Ok(key));
+        }
+
+        let key = SortKey::from_hex("00ff12340000abcd").unwrap();
+        assert_eq!(key.as_slice(), [0x00FF, 0x1234, 0, 0xABCD]);
+        assert_eq!(key.to_base32(), "ad7renaaacv42");
+
+        for bad in ["0", "00f", "+fff", "12 4", "zzzz", "é000"] {
+            assert_eq!(SortKey::from_hex(bad), Err(ParseSortKeyError));
+        }
+
+        // Not an alphabet character, a dangling character, nonzero padding bits, an odd byte
+        for bad in ["ab1a", "aaa", "ad7renaaacv43", "ae"] {
+            assert_eq!(SortKey::from_base32(bad), Err(ParseSortKeyError));
+        }
+    }
+
+    #[test]
+    fn reorder_groups() {
+        let strings = ["漢", "a", "か", "カナ", "α", "я", "1", "!", "Z"];
+
+        let mut sorted = strings;
+        sorted.sort_by(|a, b| collate(a, b, CollationOptions::default()));
+
+        assert_eq!(sorted, ["!", "1", "a", "Z", "α", "я", "か", "カナ", "漢"]);
+
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let reorder = [ReorderGroup::Kana, ReorderGroup::Latin, ReorderGroup::Han];
+
+            let options = CollationOptions {
+                keys_source,
+                reorder: &reorder,
+                ..Default::default()
+            };
+
+            assert_eq!(collate("か", "a", options), Ordering::Less);
+
+            let mut sorted = strings;
+            sorted.sort_by(|a, b| collate(a, b, options));
+
+            // Greek and Cyrillic weren't listed, so they come after Han
+            assert_eq!(sorted, ["!", "1", "か", "カナ", "a", "Z", "漢", "α", "я"]);
+
+            for a in strings {
+                for b in strings {
+                    assert_eq!(
+                        sort_key(a, options).cmp(&sort_key(b, options)),
+                        collate_no_tiebreak(a, b, options)
+                    );
+                }
+            }
+        }
+
+        // A group listed alone moves ahead of Latin
+        let options = CollationOptions {
+            reorder: &[ReorderGroup::Cyrillic],
+            ..Default::default()
+        };
+
+        let mut sorted = strings;
+        sorted.sort_by(|a, b| collate(a, b, options));
+
+        assert_eq!(sorted, ["!", "1", "я", "a", "Z", "α", "か", "カナ", "漢"]);
+    }
+
+    #[test]
+    fn primary_offset() {
+        let strings = ["漢", "a", "1", "!", "Z", "я", "9a", "a1"];
+
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let table = builtin_table(keys_source);
+            let digits = table.reorder_range(ReorderGroup::Digit).unwrap();
+            let offset = 0x6000 - i32::from(*digits.start());
+
+            assert_eq!(
+                table.shifted_range(ReorderGroup::Digit, offset),
+                Some(0x6000..=0x6000 + (digits.end() - digits.start()))
+            );
+
+            let options = CollationOptions {
+                keys_source,
+                primary_offset: Some((ReorderGroup::Digit, offset)),
+                ..Default::default()
+            };
+
+            let mut sorted = strings;
+            sorted.sort_by(|a, b| collate(a, b, options));
+
+            // After every script with a table entry, but still before Han
+            assert_eq!(sorted, ["!", "a", "a1", "Z", "я", "1", "9a", "漢"]);
+
+            for a in strings {
+                for b in strings {
+                    assert_eq!(
+                        sort_key(a, options).cmp(&sort_key(b, options)),
+                        collate_no_tiebreak(a, b, options)
+                    );
+                }
+            }
+
+            // Onto Latin's primaries: not applied at all
+            let offset = i32::from(*table.reorder_range(ReorderGroup::Latin).unwrap().start())
+                - i32::from(*digits.start());
+
+            assert_eq!(table.shifted_range(ReorderGroup::Digit, offset), None);
+
+            let options = CollationOptions {
+                keys_source,
+                primary_offset: Some((ReorderGroup::Digit, offset)),
+                ..Default::default()
+            };
+
+            assert_eq!(collate("1", "a", options), Ordering::Less);
+
+            // Way past the end is clamped; Han can't go up at all
+            assert!(table.shifted_range(ReorderGroup::Digit, 0x10000).is_some());
+            assert_eq!(table.shifted_range(ReorderGroup::Han, 1), None);
+            assert!(table.shifted_range(ReorderGroup::Han, -0x9000).is_some());
+        }
+
+        // Digits can be listed for reorder, too
+        let options = CollationOptions {
+            reorder: &[ReorderGroup::Latin, ReorderGroup::Digit],
+            ..Default::default()
+        };
+
+        let mut sorted = strings;
+        sorted.sort_by(|a, b| collate(a, b, options));
+
+        assert_eq!(sorted, ["!", "a", "a1", "Z", "1", "9a", "я", "漢"]);
+    }
+
+    #[test]
+    fn reorder_special_groups() {
+        let strings = ["a", "1", "$", "`", "_", " ", "α"];
+
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            for shifting in [false, true] {
+                let sorted = |reorder: &[ReorderGroup]| {
+                    let options = CollationOptions {
+                        keys_source,
+                        shifting,
+                        reorder,
+                        ..Default::default()
+                    };
+
+                    let mut sorted = strings;
+                    sorted.sort_by(|a, b| collate(a, b, options));
+
+                    for a in strings {
+                        for b in strings {
+                            assert_eq!(
+                                sort_key(a, options).cmp(&sort_key(b, options)),
+                                collate_no_tiebreak(a, b, options)
+                            );
+                        }
+                    }
+
+                    sorted
+                };
+
+                let canonical = [" ", "_", "`", "$", "1", "a", "α"];
+                assert_eq!(sorted(&[]), canonical);
+
+                // Digits are already right after the other special groups
+                assert_eq!(sorted(&[ReorderGroup::Digit]), canonical);
+                assert_eq!(
+                    sorted(&[ReorderGroup::Greek, ReorderGroup::Digit]),
+                    [" ", "_", "`", "$", "α", "1", "a"]
+                );
+
+                // The unlisted special groups stay first, in order. (Symbols are variable in
+                // DUCET, though, so when shifting they're ignorable until the fourth level)
+                if !(shifting && keys_source == KeysSource::Ducet) {
+                    assert_eq!(
+                        sorted(&[
+                            ReorderGroup::Latin,
+                            ReorderGroup::Digit,
+                            ReorderGroup::Symbol
+                        ]),
+                        [" ", "_", "$", "a", "1", "`", "α"]
+                    );
+                }
+
+                // Variable elements move too, even when shifted
+                assert_eq!(
+                    sorted(&[ReorderGroup::Punctuation, ReorderGroup::Space]),
+                    ["_", " ", "`", "$", "1", "a", "α"]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn max_variable() {
+        for keys_source in [KeysSource::Cldr, KeysSource::Ducet] {
+            let ties = |a: &str, b: &str, max_variable: Option<MaxVariable>| {
+                let opt = CollationOptions {
+                    keys_source,
+                    strength: CollationStrength::Tertiary,
+                    max_variable,
+                    ..Default::default()
+                };
+
+                assert_eq!(
+                    sort_key(a, opt).cmp(&sort_key(b, opt)),
+                    collate_no_tiebreak(a, b, opt)
+                );
+
+                collate_no_tiebreak(a, b, opt) == Ordering::Equal
+            };
+
+            // A symbol is variable under Symbol, but not under Punct
+            assert!(!ties("a+b", "ab", Some(MaxVariable::Punct)));
+            assert!(ties("a+b", "ab", Some(MaxVariable::Symbol)));
+            assert!(ties("a+b", "ab", Some(MaxVariable::Currency)));
+
+            // Each level includes the ones below
+            for (s, from) in [
+                ("a b", MaxVariable::Space),
+                ("a_b", MaxVariable::Punct),
+                ("a©b", MaxVariable::Symbol),
+                ("a$b", MaxVariable::Currency),
+            ] {
+                for max in [
+                    MaxVariable::Space,
+                    MaxVariable::Punct,
+                    MaxVariable::Symbol,
+                    MaxVariable::Currency,
+                ] {
+                    assert_eq!(ties(s, "ab", Some(max)), max >= from, "{s:?} {max:?}");
+                }
+            }
+
+            // Digits and letters never are
+            assert!(!ties("a1b", "ab", Some(MaxVariable::Currency)));
+
+            // Trailing ignorables lose their weights after a newly variable symbol, too
+            assert!(ties("a+\u{301}", "a", Some(MaxVariable::Symbol)));
+        }
+
+        // By default, the tables' own flags apply; for CLDR, that's Punct
+        let (lo, hi) = variable_range(KeysSource::Cldr);
+        assert_eq!(
+            variable_override(
+                builtin_table(KeysSource::Cldr),
+                CollationOptions {
+                    max_variable: Some(MaxVariable::Punct),
+                    ..Default::default()
+                }
+            ),
+            Some((lo, hi))
+        );
+        for (keys_source, tie) in [(KeysSource::Cldr, false), (KeysSource::Ducet, true)] {
+            let opt = CollationOptions {
+                keys_source,
+                strength: CollationStrength::Tertiary,
+                ..Default::default()
+            };
+            assert_eq!(collate_no_tiebreak("a+b", "ab", opt).is_eq(), tie);
+        }
+    }
+
+    #[test]
+    fn ignore_trailing_variable() {
+        for shifting in [false, true] {
+            let opt = CollationOptions {
+                shifting,
+                ignore_trailing_variable: true,
+                ..Default::default()
+            };
+
+            // Trailing punctuation (and spaces, and marks on them) makes no difference at all
+            for (a, b) in [
+                ("hello!", "hello"),
+                ("hello?! ", "hello"),
+                ("Hello...", "Hello"),
+                ("hello!\u{301}", "hello"),
+                ("-", ""),
+            ] {
+                assert_eq!(collate_no_tiebreak(a, b, opt), Ordering::Equal, "{a:?}");
+                assert_eq!(sort_key(a, opt), sort_key(b, opt), "{a:?}");
+            }
+
+            // But an accent on the last letter still counts
+            assert_ne!(collate_no_tiebreak("hellé!", "helle", opt), Ordering::Equal);
+
+            // Internal punctuation is as significant as ever
+            let plain = CollationOptions {
+                shifting,
+                ..Default::default()
+            };
+            for (a, b) in [
+                ("a-b", "ab"),
+                ("a-b!", "ab"),
+                ("a b", "ab"),
+                ("co-op", "coop"),
+            ] {
+                assert_ne!(collate_no_tiebreak(a, b, opt), Ordering::Equal, "{a:?}");
+                assert_eq!(
+                    collate_no_tiebreak(a, b, opt),
+                    collate_no_tiebreak(a, b, plain),
+                    "{a:?}"
+                );
+            }
+        }
+
+        // Without the option, trailing punctuation counts as usual
+        assert_ne!(
+            collate_no_tiebreak("hello!", "hello", CollationOptions::default()),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn quaternary_sources() {
+        let with = |shifting, quaternary| CollationOptions {
+            shifting,
+            quaternary,
+            ..Default::default()
+        };
+
+        // Equal at the tertiary level, whatever the options
+        let (hyphen, space) = ("a-b", "a b");
+        let (ignorable, plain) = ("a\u{200B}b", "ab");
+
+        for shifting in [false, true] {
+            for quaternary in [
+                QuaternarySource::Shifted,
+                QuaternarySource::CodePoints,
+                QuaternarySource::None,
+            ] {
+                let opt = with(shifting, quaternary);
+
+                for (a, b) in [(hyphen, space), (ignorable, plain), ("ab", "ab\u{200B}")] {
+                    let direct = collate_no_tiebreak(a, b, opt);
+                    assert_eq!(direct, sort_key(a, opt).cmp(&sort_key(b, opt)), "{a:?}");
+
+                    // Cutting down to tertiary always gives a tie (when not shifting, only for
+                    // the pairs that differ in an ignorable)
+                    let tertiary = CollationOptions {
+                        strength: CollationStrength::Tertiary,
+                        ..opt
+                    };
+                    if shifting || a != hyphen {
+                        assert_eq!(collate_no_tiebreak(a, b, tertiary), Ordering::Equal);
+                    }
+                }
+            }
+        }
+
+        // Shifted: a fourth level of variable primaries when shifting, and none otherwise
+        let opt = with(true, QuaternarySource::Shifted);
+        assert_eq!(sort_key(hyphen, opt).levels(), 4);
+        assert_eq!(collate_no_tiebreak(hyphen, space, opt), Ordering::Greater);
+        assert_eq!(collate_no_tiebreak(ignorable, plain, opt), Ordering::Equal);
+
+        let opt = with(false, QuaternarySource::Shifted);
+        assert_eq!(sort_key(ignorable, opt).levels(), 3);
+        assert_eq!(collate_no_tiebreak(ignorable, plain, opt), Ordering::Equal);
+
+        // CodePoints: a fourth level either way, which tells apart anything not canonically
+        // equivalent (here, by U+002D vs. U+0020, and by U+200B vs. U+0062)
+        for shifting in [false, true] {
+            let opt = with(shifting, QuaternarySource::CodePoints);
+
+            assert_eq!(sort_key(ignorable, opt).levels(), 4);
+            assert_eq!(
+                collate_no_tiebreak(ignorable, plain, opt),
+                Ordering::Greater
+            );
+            assert_eq!(collate_no_tiebreak("ab", "ab\u{200B}", opt), Ordering::Less);
+            assert_eq!(
+                collate_no_tiebreak("a\u{301}", "\u{E1}", opt),
+                Ordering::Equal
+            );
+        }
+
+        let opt = with(true, QuaternarySource::CodePoints);
+        assert_eq!(collate_no_tiebreak(hyphen, space, opt), Ordering::Greater);
+
+        // None: no fourth level, so when shifting, variable elements are ignored entirely
+        let opt = with(true, QuaternarySource::None);
+        assert_eq!(sort_key(hyphen, opt).levels(), 3);
+        assert_eq!(collate_no_tiebreak(hyphen, space, opt), Ordering::Equal);
+        assert_eq!(collate_no_tiebreak(ignorable, plain, opt), Ordering::Equal);
+    }
+
+    #[test]
+    fn sort_key_into_buffer() {
+        let quaternary = CollationOptions {
+            strength: CollationStrength::Quaternary,
+            quaternary: QuaternarySource::CodePoints,
+            ..Default::default()
+        };
+
+        let mut buf = Vec::new();
+
+        for opt in [CollationOptions::default(), quaternary] {
+            // Longest first, so that a buffer that wasn't cleared would show
+            for s in ["long enough to leave leftovers", "Ångström", "a-b", "", "x"] {
+                sort_key_into(s, opt, &mut buf);
+                assert_eq!(buf, sort_key(s, opt).as_slice(), "{s}");
+            }
+        }
+    }
+
+    #[test]
+    fn sort_key_from_code_point_iter() {
+        let mut sample: Vec<String> =
+            conformance_strings("test-data/CollationTest_CLDR_SHIFTED_SHORT.txt")
+                .into_iter()
+                .step_by(31)
+                .collect();
+
+        sample.extend(
+            [
+                "Ǆemal STRASSE straße",
+                "ḍ̇ a\u{301}\u{316} \u{F73}\u{F71}\u{F72}",
+                "hello, world!! ",
+                "l·l L·L ch Ch",
+                "한국어 日本語 ﬁ ①",
+                "e\u{301}\u{34F}\u{316}",
+            ]
+            .map(String::from),
+        );
+
+        // A long one, with many pieces
+        sample.push("Ça déménage! ".repeat(500));
+
+        let variations = [
+            CollationOptions::default(),
+            CollationOptions {
+                keys_source: KeysSource::Ducet,
+                shifting: false,
+                ..Default::default()
+            },
+            CollationOptions {
+                case_fold: true,
+                normalization: NormalizationForm::Nfkd,
+                ..Default::default()
+            },
+            CollationOptions {
+                ignore_trailing_variable: true,
+                quaternary: QuaternarySource::CodePoints,
+                compress_secondaries: true,
+                ..Default::default()
+            },
+        ];
+
+        for opt in variations {
+            for s in &sample {
+                let streamed = sort_key_from_iter(s.chars().map(|c| c as u32), opt);
+                assert_eq!(streamed, sort_key(s, opt), "{s:?} with {opt:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn reversed_element_order() {
+        let opt = CollationOptions::default();
+
+        let mut words = [
+            "cat", "dog", "Bat", "fog", "hat", "log", "cart", "start", "chat", "art", "frog",
+        ];
+        words.sort_by(|a, b| collate_reversed_elements(a, b, opt));
+
+        assert_eq!(
+            words,
+            ["dog", "fog", "log", "frog", "Bat", "cat", "hat", "chat", "art", "cart", "start"]
+        );
+
+        // Accents and case still come after the base letters, level by level
+        assert_eq!(
+            collate_reversed_elements("cafe", "café", opt),
+            Ordering::Less
+        );
+        assert_eq!(
+            collate_reversed_elements("café", "cafF", opt),
+            Ordering::Less
+        );
+
+        // Ignorables make no difference, apart from the tiebreaker
+        assert_eq!(
+            collate_reversed_elements("ab\u{200B}", "ab", opt),
+            "ab\u{200B}".cmp("ab")
+        );
     }
 
-    // BBBB always gets bitwise ORed with this value
-    bbbb |= 32_768;
+    #[test]
+    fn locale_presets() {
+        let sorted = |locale: &str| {
+            let mut words = ["côté", "cote", "coté", "côte"];
+            words.sort_by(|a, b| collate_locale(a, b, locale));
+            words
+        };
 
-    #[allow(clippy::cast_possible_truncation)]
-    let second_weights = if shifting {
-        // Add an arbitrary fourth weight if shifting
-        ArrayVec::from([bbbb as u16, 0, 0, 65_535])
-    } else {
-        array_vec!([u16; 4] => bbbb as u16, 0, 0)
-    };
+        // The last accent counts the most in French
+        assert_eq!(sorted("fr"), ["cote", "côte", "coté", "côté"]);
+        assert_eq!(sorted("fr-CA"), sorted("fr"));
+        assert_eq!(sorted("en"), ["cote", "coté", "côte", "côté"]);
+
+        // Backwards secondaries agree between sort keys and direct comparison, also when a shared
+        // prefix would otherwise be skipped
+        let french = locale_options("fr");
+        for (a, b) in [
+            ("côte", "cote"),
+            ("cotée", "côte"),
+            ("ab\u{301}c", "a\u{301}bc"),
+        ] {
+            assert_eq!(
+                collate_no_tiebreak(a, b, french),
+                sort_key(a, french).cmp(&sort_key(b, french)),
+                "{a:?} vs. {b:?}"
+            );
+        }
 
-    second_weights
-}
+        // Unknown locales are just root
+        assert_eq!(locale_options("en-US"), CollationOptions::default());
+        assert_eq!(locale_options("xx"), CollationOptions::default());
+        assert_eq!(locale_options(""), CollationOptions::default());
+
+        assert_eq!(collate_locale("я", "z", "ru"), Ordering::Less);
+        assert_eq!(collate_locale("я", "z", "en"), Ordering::Greater);
+        assert_eq!(collate_locale("yra", "jis", "lt"), Ordering::Less);
+        assert_eq!(collate_locale("zoo", "tee", "et"), Ordering::Less);
+        assert_eq!(collate_locale("漢", "α", "ja"), Ordering::Less);
+        assert_eq!(collate_locale("a", "か", "ja"), Ordering::Less);
+    }
 
-//
-// Parsing Unicode data (not usually run)
-//
+    #[test]
+    fn unify_whitespace() {
+        let variants = ["a b", "a\tb", "a\nb", "a\r\nb", "a\u{A0}b", "a\u{3000}b"];
+
+        for opt in [
+            CollationOptions::default(),
+            CollationOptions {
+                shifting: false,
+                ..Default::default()
+            },
+        ] {
+            let unified = CollationOptions {
+                unify_whitespace: true,
+                ..opt
+            };
+
+            // (No-break space and ideographic space already mostly weigh the same as space)
+            for v in ["a\tb", "a\nb"] {
+                assert_ne!(collate_no_tiebreak("a b", v, opt), Ordering::Equal, "{v:?}");
+            }
 
-pub fn parse_keys_sing() {
-    let keys = std::fs::read_to_string("test-data/allkeys.txt").unwrap();
-    let mut map: HashMap<u32, Vec<Weights>> = HashMap::new();
+            // Only the two-character line break is longer
+            for v in variants.iter().filter(|v| !v.contains('\r')) {
+                assert_eq!(
+                    collate_no_tiebreak("a b", v, unified),
+                    Ordering::Equal,
+                    "{v:?}"
+                );
+                assert_eq!(sort_key("a b", unified), sort_key(v, unified), "{v:?}");
+            }
 
-    for line in keys.lines() {
-        if line.is_empty() || line.starts_with('@') || line.starts_with('#') {
-            continue;
+            assert_eq!(
+                collate_no_tiebreak("a\r\nb", "a  b", unified),
+                Ordering::Equal
+            );
         }
+    }
 
-        let mut split_at_semicolon = line.split(';');
-        let left_of_semicolon = split_at_semicolon.next().unwrap();
-        let right_of_semicolon = split_at_semicolon.next().unwrap();
-        let left_of_hash = right_of_semicolon.split('#').next().unwrap();
+    #[test]
+    fn weights_shifting_unchanged() {
+        // The branches as originally written, before they were reordered
+        let reference = |weights: &Weights, last_variable: bool| -> ArrayVec<[u16; 4]> {
+            if weights.primary == 0 && weights.secondary == 0 && weights.tertiary == 0 {
+                ArrayVec::from([0, 0, 0, 0])
+            } else if weights.variable {
+                ArrayVec::from([0, 0, 0, weights.primary])
+            } else if last_variable && weights.primary == 0 && weights.tertiary != 0 {
+                ArrayVec::from([0, 0, 0, 0])
+            } else {
+                ArrayVec::from([weights.primary, weights.secondary, weights.tertiary, 65_535])
+            }
+        };
 
-        let mut points = ArrayVec::<[u32; 3]>::new();
-        let re_key = regex!(r"[\dA-F]{4,5}");
-        for m in re_key.find_iter(left_of_semicolon) {
-            let as_u32 = u32::from_str_radix(m.as_str(), 16).unwrap();
-            points.push(as_u32);
+        for keys_source in [KeysSource::Ducet, KeysSource::Cldr] {
+            let table = builtin_table(keys_source);
+
+            let rows = table
+                .low
+                .iter()
+                .flatten()
+                .copied()
+                .chain(table.singles.values().flatten().copied())
+                .chain(table.multis.values().flatten().copied());
+
+            for weights in rows {
+                for last_variable in [false, true] {
+                    assert_eq!(
+                        get_weights_shifting(&weights, last_variable),
+                        reference(&weights, last_variable),
+                        "{weights:?}, {last_variable}"
+                    );
+                }
+            }
         }
 
-        if points.len() > 1 {
-            continue;
+        // Combinations the tables may not have
+        for (primary, secondary, tertiary) in [(0, 0, 0), (0, 0, 2), (0, 32, 0), (0, 32, 2)] {
+            for last_variable in [false, true] {
+                let weights = Weights {
+                    variable: false,
+                    primary,
+                    secondary,
+                    tertiary,
+                };
+
+                assert_eq!(
+                    get_weights_shifting(&weights, last_variable),
+                    reference(&weights, last_variable)
+                );
+            }
         }
+    }
 
-        let k = points[0];
+    #[test]
+    fn index_buckets() {
+        let opt = CollationOptions::default();
+
+        assert_eq!(index_bucket("Ångström", opt), Some('A'));
+        assert_eq!(index_bucket("ångström", opt), Some('A'));
+        assert_eq!(index_bucket("A\u{30A}ngstro\u{308}m", opt), Some('A'));
+        assert_eq!(index_bucket("zebra", opt), Some('Z'));
+        assert_eq!(index_bucket("Émile", opt), Some('E'));
+        assert_eq!(index_bucket("ßtraße", opt), Some('S'));
+        assert_eq!(index_bucket("ωμέγα", opt), Some('Ω'));
+        assert_eq!(index_bucket("漢字", opt), Some('漢'));
+
+        // Leading punctuation and spaces are skipped when shifting, but not otherwise
+        assert_eq!(index_bucket("\"...quoted\"", opt), Some('Q'));
+        assert_eq!(index_bucket(" \u{301}x", opt), Some('X'));
+        assert_eq!(index_bucket("...", opt), None);
+        assert_eq!(index_bucket("", opt), None);
+
+        let non_ignorable = CollationOptions {
+            shifting: false,
+            ..opt
+        };
+        assert_eq!(index_bucket("...", non_ignorable), Some('.'));
+
+        // Swedish-style å as a letter of its own, after z. Precomposed input that's already FCD
+        // isn't decomposed, so the table needs both forms
+        let mut table = CollationTable::new(KeysSource::Cldr);
+        let z = table.singles[&('z' as u32)][0];
+        let upper_z = table.singles[&('Z' as u32)][0];
+
+        for (key, case) in [
+            ("a\u{30A}", z),
+            ("\u{E5}", z),
+            ("A\u{30A}", upper_z),
+            ("\u{C5}", upper_z),
+        ] {
+            let weights = Weights {
+                primary: z.primary + 1,
+                ..case
+            };
+            table.add_mapping(key, &[weights]);
+        }
 
-        let mut v: Vec<Weights> = Vec::new();
-        let re_weights = regex!(r"[*.\dA-F]{15}");
-        let re_value = regex!(r"[\dA-F]{4}");
+        assert_eq!(index_bucket_with_table("Ångström", &table, opt), Some('Å'));
+        assert_eq!(index_bucket_with_table("ångström", &table, opt), Some('Å'));
+        assert_eq!(index_bucket_with_table("Anders", &table, opt), Some('A'));
+        assert_eq!(index_bucket_with_table("Ängel", &table, opt), Some('A'));
+    }
 
-        for m in re_weights.find_iter(left_of_hash) {
-            let weights_str = m.as_str();
-            let mut weights = Weights::new();
+    #[test]
+    fn all_ignorable_strings() {
+        for keys_source in [KeysSource::Ducet, KeysSource::Cldr] {
+            for shifting in [false, true] {
+                for strength in [
+                    CollationStrength::Primary,
+                    CollationStrength::Secondary,
+                    CollationStrength::Tertiary,
+                    CollationStrength::Quaternary,
+                ] {
+                    let opt = CollationOptions {
+                        keys_source,
+                        shifting,
+                        strength,
+                        ..Default::default()
+                    };
 
-            if weights_str.starts_with('*') {
-                weights.variable = true;
+                    // Under shifting, punctuation only counts at the quaternary level (if there is
+                    // one); otherwise it's like any other character
+                    let expected = if shifting && strength != CollationStrength::Quaternary {
+                        Ordering::Equal
+                    } else {
+                        Ordering::Less
+                    };
+
+                    for (a, b) in [("---", "..."), ("-", "--"), ("", "-"), ("\t", " ")] {
+                        let context = format!("{a:?} vs. {b:?}, {opt:?}");
+
+                        let comparison = collate_no_tiebreak(a, b, opt);
+                        assert_eq!(comparison, expected, "{context}");
+                        assert_eq!(
+                            sort_key(a, opt).cmp(&sort_key(b, opt)),
+                            comparison,
+                            "{context}"
+                        );
+                        assert_eq!(collate_no_tiebreak(b, a, opt), expected.reverse());
+                    }
+
+                    // Completely ignorable code points never count, whatever the mode
+                    for (a, b) in [("", "\u{AD}"), ("\u{200B}", "\u{AD}\u{200B}")] {
+                        assert_eq!(collate_no_tiebreak(a, b, opt), Ordering::Equal);
+                        assert_eq!(sort_key(a, opt), sort_key(b, opt));
+                    }
+
+                    // An empty primary level with something after it still sorts first
+                    let key = sort_key("-", opt);
+                    assert!(key < sort_key("a", opt));
+                    assert_eq!(key.levels(), sort_key("a", opt).levels());
+                }
             }
+        }
+    }
 
-            let mut vals = re_value.find_iter(weights_str);
-            weights.primary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
-            weights.secondary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
-            weights.tertiary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
+    #[test]
+    fn compare_hand_built_elements() {
+        let opt = CollationOptions::default();
+
+        let a = collation_elements("a", opt);
+        let b = collation_elements("b", opt);
+        assert_eq!(compare_elements(&a, &b, opt), Ordering::Less);
+        assert_eq!(compare_elements(&a, &a, opt), Ordering::Equal);
+
+        // Give "a" a primary just past b's
+        let mut edited = a.clone();
+        edited[0][0] = b[0][0] + 1;
+        assert_eq!(compare_elements(&edited, &b, opt), Ordering::Greater);
+
+        // Three weights are enough; the missing quaternary only matters at that level
+        let short = |p: u16, s: u16, t: u16| array_vec!([u16; 4] => p, s, t);
+        let x = [short(b[0][0], 32, 2)];
+        let y = [short(b[0][0], 32, 2), short(0, 33, 2)];
+        assert_eq!(compare_elements(&x, &y, opt), Ordering::Less);
+        assert_eq!(compare_elements(&x, &b, opt), Ordering::Less);
+
+        let tertiary = CollationOptions {
+            strength: CollationStrength::Tertiary,
+            ..opt
+        };
+        assert_eq!(compare_elements(&x, &b, tertiary), Ordering::Equal);
+
+        // Agrees with comparing the strings, where the elements came from them
+        for (s, t) in [
+            ("abc", "abd"),
+            ("Abc", "abc"),
+            ("a-b", "ab"),
+            ("côte", "coté"),
+        ] {
+            assert_eq!(
+                compare_elements(
+                    &collation_elements(s, opt),
+                    &collation_elements(t, opt),
+                    opt
+                ),
+                collate_no_tiebreak(s, t, opt),
+                "{s:?} vs. {t:?}"
+            );
+        }
+    }
 
-            v.push(weights);
+    #[test]
+    fn tailored_context_mapping() {
+        let opt = CollationOptions::default();
+
+        // x | y < z, roughly: z sorts right after x, but only when it follows y
+        let mut table = CollationTable::new(KeysSource::Cldr);
+        let x = table.singles[&('x' as u32)][0];
+        table.add_context_mapping(
+            "y",
+            "z",
+            &[Weights {
+                primary: x.primary + 1,
+                ..x
+            }],
+        );
+
+        assert!(table.low['z' as usize].is_none());
+
+        let cmp = |a: &str, b: &str| collate_with_table(a, b, &table, opt);
+
+        assert_eq!(collate("yz", "yy", opt), Ordering::Greater);
+        assert_eq!(cmp("yz", "yy"), Ordering::Less);
+        assert_eq!(cmp("yz", "yx"), Ordering::Greater);
+
+        // The shared prefix is skipped when comparing, but still counts as context
+        assert_eq!(cmp("abcyz", "abcyy"), Ordering::Less);
+
+        // Elsewhere, z is as before
+        assert_eq!(cmp("az", "ay"), Ordering::Greater);
+        assert_eq!(cmp("z", "y"), Ordering::Greater);
+        assert_eq!(cmp("zy", "zz"), Ordering::Less);
+
+        // A longer prefix wins over a shorter one
+        let w = table.singles[&('w' as u32)][0];
+        table.add_context_mapping(
+            "ay",
+            "z",
+            &[Weights {
+                primary: w.primary + 1,
+                ..w
+            }],
+        );
+
+        let cmp = |a: &str, b: &str| collate_with_table(a, b, &table, opt);
+
+        assert_eq!(cmp("ayz", "ayx"), Ordering::Less);
+        assert_eq!(cmp("ayz", "ayw"), Ordering::Greater);
+        assert_eq!(cmp("byz", "byx"), Ordering::Greater);
+    }
+
+    #[test]
+    fn first_letter_case() {
+        let opt = CollationOptions {
+            first_letter_case: true,
+            ..Default::default()
+        };
+
+        // Normally lowercase comes first, and case counts anywhere in the string
+        let default = CollationOptions::default();
+        assert_eq!(collate("iPad", "IPad", default), Ordering::Less);
+        assert_eq!(
+            collate_no_tiebreak("iPad", "ipad", default),
+            Ordering::Greater
+        );
+
+        // Now only the first letter's case counts, uppercase first
+        assert_eq!(collate("iPad", "IPad", opt), Ordering::Greater);
+        assert_eq!(collate("Iphone", "iPhone", opt), Ordering::Less);
+        assert_eq!(collate_no_tiebreak("iPad", "ipad", opt), Ordering::Equal);
+        assert_eq!(collate_no_tiebreak("IPAD", "Ipad", opt), Ordering::Equal);
+
+        // Leading punctuation isn't the first letter when shifting; a shared prefix is skipped,
+        // but its first letter still counts as such
+        assert_eq!(collate("\"iPad", "\"IPad", opt), Ordering::Greater);
+        assert_eq!(collate_no_tiebreak("aBc", "abC", opt), Ordering::Equal);
+        assert_eq!(collate_no_tiebreak("aBc", "Abc", opt), Ordering::Greater);
+
+        // Primary and secondary differences still come first, and keys agree
+        assert_eq!(collate("Ipad", "ipäd", opt), Ordering::Less);
+        assert_eq!(collate("ipad", "Ipäd", opt), Ordering::Less);
+        for (a, b) in [("iPad", "IPad"), ("aBc", "abC"), ("ＩPad", "ｉpad")] {
+            assert_eq!(
+                sort_key(a, opt).cmp(&sort_key(b, opt)),
+                collate_no_tiebreak(a, b, opt),
+                "{a:?} vs. {b:?}"
+            );
         }
 
-        map.insert(k, v);
+        // Streaming in pieces doesn't make every piece's first letter count
+        for s in ["ab가", "Ab가", "iPad 가나", "x-Y z"] {
+            assert_eq!(
+                sort_key_from_iter(s.chars().map(|c| c as u32), opt),
+                sort_key(s, opt),
+                "{s:?}"
+            );
+        }
+
+        let mut products = ["iPhone", "Iphone", "IPAD", "ipad", "iPad"];
+        products.sort_by(|a, b| collate(a, b, opt));
+        assert_eq!(products, ["IPAD", "iPad", "ipad", "Iphone", "iPhone"]);
     }
 
-    let bytes = bincode::serialize(&map).unwrap();
-    std::fs::write("byte_dump", bytes).unwrap();
-}
+    #[test]
+    fn group_by_collation_strength() {
+        let items: Vec<String> = ["cafe", "café", "CAFE", "zeta", "Café"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let opt = CollationOptions::default();
+
+        assert_eq!(
+            group_by_collation(&items[..4], CollationStrength::Primary, opt),
+            [vec![0, 1, 2], vec![3]]
+        );
+        assert_eq!(
+            group_by_collation(&items, CollationStrength::Secondary, opt),
+            [vec![0, 2], vec![1, 4], vec![3]]
+        );
+        assert_eq!(
+            group_by_collation(&items, CollationStrength::Tertiary, opt),
+            [vec![0], vec![1], vec![2], vec![3], vec![4]]
+        );
+        assert!(group_by_collation(&[], CollationStrength::Primary, opt).is_empty());
+    }
 
-pub fn parse_keys_multi() {
-    let keys = std::fs::read_to_string("test-data/allkeys.txt").unwrap();
-    let mut map: HashMap<ArrayVec<[u32; 3]>, Vec<Weights>> = HashMap::new();
+    #[test]
+    fn implicit_override() {
+        let default = CollationOptions::default();
+        let a = builtin_table(KeysSource::Cldr).singles[&('a' as u32)][0];
+
+        // U+E000 sorts as "aa"; other private-use code points are left alone
+        let pua = |cp: u32| (cp == 0xE000).then_some([a, a]);
+        let opt = CollationOptions {
+            implicit_override: Some(ImplicitOverride(&pua)),
+            ..default
+        };
 
-    for line in keys.lines() {
-        if line.is_empty() || line.starts_with('@') || line.starts_with('#') {
-            continue;
+        assert_eq!(collate("\u{E000}", "b", default), Ordering::Greater);
+        assert_eq!(collate("\u{E000}", "b", opt), Ordering::Less);
+        assert_eq!(collate_no_tiebreak("\u{E000}", "aa", opt), Ordering::Equal);
+        assert_eq!(sort_key("x\u{E000}y", opt), sort_key("xaay", opt));
+        assert_eq!(collate("\u{E001}", "b", opt), Ordering::Greater);
+        assert_eq!(
+            collate("\u{E000}", "\u{E001}", opt),
+            collate("\u{E000}", "\u{E001}", default)
+        );
+
+        // Code points in the table are unaffected
+        let everything = |_: u32| Some([a, a]);
+        let opt = CollationOptions {
+            implicit_override: Some(ImplicitOverride(&everything)),
+            ..default
+        };
+        assert_eq!(collate("b", "aa", opt), Ordering::Greater);
+
+        // A variable override is shifted like any other, including after a shared prefix
+        let dash = builtin_table(KeysSource::Cldr).singles[&('-' as u32)][0];
+        let variable = |cp: u32| (cp == 0xE000).then_some([dash, dash]);
+        let opt = CollationOptions {
+            implicit_override: Some(ImplicitOverride(&variable)),
+            ..default
+        };
+        assert!(dash.variable);
+
+        // The accent after the override is ignored, as after any variable
+        assert_eq!(
+            collate_no_tiebreak("\u{E000}\u{301}x", "\u{E000}x", opt),
+            Ordering::Equal
+        );
+
+        // Skipping a shared prefix ending in the override would be wrong
+        let untrimmed = CollationOptions {
+            trim_prefix: false,
+            ..opt
+        };
+        for (a, b) in [
+            ("x\u{E000}\u{301}", "x\u{E000}"),
+            ("x\u{E000}\u{301}", "x\u{E000}\u{300}"),
+        ] {
+            assert_eq!(
+                collate_no_tiebreak(a, b, opt),
+                collate_no_tiebreak(a, b, untrimmed),
+                "{a:?} vs. {b:?}"
+            );
         }
+    }
 
-        let mut split_at_semicolon = line.split(';');
-        let left_of_semicolon = split_at_semicolon.next().unwrap();
-        let right_of_semicolon = split_at_semicolon.next().unwrap();
-        let left_of_hash = right_of_semicolon.split('#').next().unwrap();
+    #[test]
+    fn weights_pack_round_trip() {
+        let mut all: Vec<Weights> = Vec::new();
 
-        let mut k = ArrayVec::<[u32; 3]>::new();
-        let re_key = regex!(r"[\dA-F]{4,5}");
-        for m in re_key.find_iter(left_of_semicolon) {
-            let as_u32 = u32::from_str_radix(m.as_str(), 16).unwrap();
-            k.push(as_u32);
+        for keys_source in [KeysSource::Ducet, KeysSource::Cldr] {
+            let table = builtin_table(keys_source);
+            all.extend(table.singles.values().flatten());
+            all.extend(table.multis.values().flatten());
         }
 
-        if k.len() < 2 {
-            continue;
+        all.push(Weights::new());
+        all.push(Weights {
+            variable: true,
+            primary: u16::MAX,
+            secondary: u16::MAX,
+            tertiary: u16::MAX,
+        });
+
+        for weights in &all {
+            assert_eq!(Weights::unpack(weights.pack()), *weights);
         }
 
-        let mut v: Vec<Weights> = Vec::new();
-        let re_weights = regex!(r"[*.\dA-F]{15}");
-        let re_value = regex!(r"[\dA-F]{4}");
+        // Packing preserves order
+        all.sort();
+        assert!(all.windows(2).all(|w| w[0].pack() <= w[1].pack()));
 
-        for m in re_weights.find_iter(left_of_hash) {
-            let weights_str = m.as_str();
-            let mut weights = Weights::new();
+        assert_eq!(Weights::unpack(u64::MAX).pack(), (1 << 49) - 1);
 
-            if weights_str.starts_with('*') {
-                weights.variable = true;
-            }
+        // The bool already fits in what would otherwise be padding, so a packed table wouldn't be
+        // any smaller
+        assert_eq!(std::mem::size_of::<Weights>(), std::mem::size_of::<u64>());
+    }
 
-            let mut vals = re_value.find_iter(weights_str);
-            weights.primary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
-            weights.secondary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
-            weights.tertiary = u16::from_str_radix(vals.next().unwrap().as_str(), 16).unwrap();
+    #[test]
+    fn fcd_cross_check() {
+        // FCD by definition: the first code point of each char's full decomposition never has a
+        // (non-zero) CCC lower than the last of the previous char's
+        let reference = |s: &[char]| {
+            let mut prev_trail = 0;
+
+            s.iter().all(|c| {
+                let nfd: Vec<u32> = c.to_string().nfd().map(|d| d as u32).collect();
+                let lead = get_ccc_u32(nfd[0]);
+                let ok = lead == 0 || lead >= prev_trail;
+                prev_trail = get_ccc_u32(*nfd.last().unwrap());
+                ok
+            })
+        };
 
-            v.push(weights);
+        let key = |cps: &mut [u32], opt: CollationOptions| {
+            let cea = get_cea(cps, 0, builtin_table(opt.keys_source), opt, None);
+            get_sort_key(&cea, opt)
+        };
+
+        // Anything with a decomposition or a CCC, plus some starters, Hangul and Tibetan
+        let mut pool: Vec<char> = (0..=0x10_FFFF)
+            .filter_map(char::from_u32)
+            .filter(|c| get_ccc(*c) as u8 != 0 || c.to_string().nfd().ne([*c]))
+            .step_by(3)
+            .collect();
+        pool.extend("aAlL·\u{1100}\u{1161}\u{11A8}\u{F40}\u{F71}\u{F72}\u{F74}\u{F80}".chars());
+
+        // A fixed xorshift sequence, so failures reproduce
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut next = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            usize::try_from(state % n as u64).unwrap()
+        };
+
+        let opts = [KeysSource::Ducet, KeysSource::Cldr].map(|keys_source| CollationOptions {
+            keys_source,
+            ..Default::default()
+        });
+
+        for _ in 0..5_000 {
+            let len = 1 + next(4);
+            let s: Vec<char> = (0..len).map(|_| pool[next(pool.len())]).collect();
+            let cps: Vec<u32> = s.iter().map(|c| *c as u32).collect();
+
+            let tibetan = cps.iter().any(|cp| TIBETAN_DECOMPOSABLE.contains(cp));
+
+            assert_eq!(
+                fcd(cps.iter().copied()),
+                reference(&s) && !tibetan,
+                "{cps:X?}"
+            );
+
+            // When it's FCD, skipping normalization (apart from Hangul) makes no difference
+            if fcd(cps.iter().copied()) {
+                let string: String = s.iter().collect();
+                let mut nfd: Vec<u32> = string.nfd().map(|c| c as u32).collect();
+
+                for opt in opts {
+                    let mut raw = decompose_hangul(cps.iter().copied());
+                    assert_eq!(key(&mut raw, opt), key(&mut nfd, opt), "{cps:X?}");
+                }
+            }
         }
 
-        map.insert(k, v);
+        // A U+0F71 before one of the vowel signs is the case that needs decomposition: the input
+        // is FCD, but its collation elements differ from those of the NFD
+        for sign in TIBETAN_DECOMPOSABLE.map(|cp| char::from_u32(cp).unwrap()) {
+            let s = ['\u{F71}', sign];
+            let string: String = s.iter().collect();
+            let nfd: String = string.nfd().collect();
+
+            assert!(reference(&s));
+            assert!(!fcd(s.iter().map(|c| *c as u32)));
+
+            let mut raw: Vec<u32> = s.iter().map(|c| *c as u32).collect();
+            let mut decomposed: Vec<u32> = nfd.chars().map(|c| c as u32).collect();
+            assert_ne!(
+                key(&mut raw, opts[0]),
+                key(&mut decomposed, opts[0]),
+                "{sign:?}"
+            );
+
+            for opt in opts {
+                assert_eq!(sort_key(&string, opt), sort_key(&nfd, opt), "{sign:?}");
+            }
+        }
     }
 
-    let bytes = bincode::serialize(&map).unwrap();
-    std::fs::write("byte_dump", bytes).unwrap();
-}
+    #[test]
+    fn distinguishing_prefix_depth() {
+        let opt = CollationOptions::default();
+
+        let check = |a: &str, b: &str| {
+            let n = distinguishing_prefix(a, b, opt);
+            let (a_key, b_key) = (sort_key(a, opt).as_bytes(), sort_key(b, opt).as_bytes());
+
+            // The first n bytes tell them apart, and the first n - 1 don't
+            let prefix = |key: &[u8], len: usize| key[..len.min(key.len())].to_vec();
+            assert_ne!(prefix(&a_key, n), prefix(&b_key, n), "{a:?} vs. {b:?}");
+            assert_eq!(
+                prefix(&a_key, n - 1),
+                prefix(&b_key, n - 1),
+                "{a:?} vs. {b:?}"
+            );
+
+            n
+        };
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // A primary difference shows up in the first weight (its second byte, since both primaries
+        // are in the same 256 block)
+        let primary = check("abc", "xbc");
+        assert!(primary <= 2);
+
+        // A case difference only at the tertiary level, after all the primaries and secondaries
+        let tertiary = check("abc", "abC");
+        let primaries_and_secondaries = (3 + 1 + 3 + 1) * 2;
+        assert!(tertiary > primaries_and_secondaries);
+
+        // One string is a prefix of the other
+        assert_eq!(check("ab", "abc"), 2 * 2 + 1);
+
+        // Equal keys need all their bytes
+        let key_len = sort_key("abc", opt).as_bytes().len();
+        assert_eq!(distinguishing_prefix("abc", "abc", opt), key_len);
+        assert_eq!(
+            distinguishing_prefix("", "", opt),
+            sort_key("", opt).as_bytes().len()
+        );
+    }
 
     #[test]
-    fn deluge_shifted() {
-        let mut scrambled = [
-            "demark", "de-luge", "deluge", "de-Luge", "de luge", "de-luge", "deLuge", "de Luge",
-            "de-Luge", "death",
-        ];
+    fn implicit_weights_shifted() {
+        let opt = CollationOptions::default();
+        let cea = |s: &str, opt| -> Vec<Vec<u16>> {
+            collation_elements(s, opt)
+                .iter()
+                .map(|e| e.to_vec())
+                .collect()
+        };
 
-        let options = CollationOptions {
-            keys_source: KeysSource::Ducet,
-            shifting: true,
+        // U+4E00 and U+4E01 aren't in the tables, so they get implicit weights (CJK1)
+        for keys_source in [KeysSource::Ducet, KeysSource::Cldr] {
+            let opt = CollationOptions { keys_source, ..opt };
+
+            assert_eq!(
+                cea("\u{4E00}\u{4E01}", opt),
+                [
+                    [0xFB40, 0x20, 0x02, 0xFFFF],
+                    [0xCE00, 0, 0, 0xFFFF],
+                    [0xFB40, 0x20, 0x02, 0xFFFF],
+                    [0xCE01, 0, 0, 0xFFFF],
+                ]
+            );
+
+            // The quaternaries are the same, so the primaries decide; and they tie with nothing
+            // shifted at that level
+            let key = |s: &str| sort_key(s, opt).into_vec();
+            let quaternary = |s: &str| key(s).rsplit(|w| *w == 0).next().unwrap().to_vec();
+
+            assert_eq!(quaternary("\u{4E00}"), [0xFFFF, 0xFFFF]);
+            assert_eq!(quaternary("\u{4E00}"), quaternary("\u{4E01}"));
+            assert_eq!(collate("\u{4E00}", "\u{4E01}", opt), Ordering::Less);
+
+            // A shifted variable comes before them at the quaternary level
+            assert_eq!(quaternary("-\u{4E00}")[0], cea("-", opt)[0][3]);
+            assert!(quaternary("-\u{4E00}")[0] < 0xFFFF);
+            assert_eq!(
+                collate_no_tiebreak("-\u{4E00}", "\u{4E00}", opt),
+                Ordering::Less
+            );
+        }
+
+        // Without shifting, there's no fourth weight
+        let non_ignorable = CollationOptions {
+            shifting: false,
+            ..opt
         };
+        assert_eq!(
+            cea("\u{4E00}", non_ignorable),
+            [[0xFB40, 0x20, 0x02], [0xCE00, 0, 0]]
+        );
+    }
 
-        scrambled.sort_by(|a, b| collate(a, b, options));
+    #[test]
+    fn merge_sorted_key_runs() {
+        let opt = CollationOptions::default();
 
-        let sorted = [
-            "death", "de luge", "de-luge", "de-luge", "deluge", "de Luge", "de-Luge", "de-Luge",
-            "deLuge", "demark",
+        let words = [
+            "apple", "Äpfel", "banana", "cherry", "côte", "cote", "date", "éclair", "fig", "grape",
+            "kiwi", "lemon", "Mango", "mango", "ñame", "olive", "peach", "zucchini",
         ];
 
-        assert_eq!(scrambled, sorted);
+        // Three runs, each sorted on its own
+        let runs: Vec<Vec<SortKey>> = (0..3)
+            .map(|r| {
+                let mut run: Vec<SortKey> = words
+                    .iter()
+                    .skip(r)
+                    .step_by(3)
+                    .map(|w| sort_key(w, opt))
+                    .collect();
+                run.sort();
+                run
+            })
+            .collect();
+
+        let merged: Vec<SortKey> =
+            merge_sorted_keys(runs.into_iter().map(Vec::into_iter).collect()).collect();
+
+        let mut expected: Vec<SortKey> = words.iter().map(|w| sort_key(w, opt)).collect();
+        expected.sort();
+
+        assert_eq!(merged.len(), words.len());
+        assert!(merged.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(merged, expected);
+
+        // Empty runs, or none at all
+        let empty: Vec<std::vec::IntoIter<SortKey>> = vec![Vec::new().into_iter(); 2];
+        assert_eq!(merge_sorted_keys(empty).count(), 0);
+        assert_eq!(
+            merge_sorted_keys(Vec::<std::vec::IntoIter<SortKey>>::new()).count(),
+            0
+        );
     }
 
     #[test]
-    fn multi_script() {
-        let mut scrambled = [
-            "ｶ",
-            "ヵ",
-            "abc",
-            "abç",
-            "ab©",
-            "𝒶bc",
-            "abC",
-            "𝕒bc",
-            "File-3",
-            "ガ",
-            "が",
-            "äbc",
-            "カ",
-            "か",
-            "Abc",
-            "file-12",
-            "filé-110",
+    fn warm_tables_loads_once() {
+        // Other tests may have loaded the tables already; either way, it only happens once
+        for keys_source in [KeysSource::Ducet, KeysSource::Cldr] {
+            warm_tables(keys_source);
+
+            let table = builtin_table(keys_source);
+            assert!(table.reorder_ranges.get().is_some());
+            assert_eq!(
+                TABLE_LOADS[keys_source as usize].load(AtomicOrdering::SeqCst),
+                1
+            );
+
+            let opt = CollationOptions {
+                keys_source,
+                ..Default::default()
+            };
+            collate("warm", "cold", opt);
+            sort_key("\u{AC00}\u{301}", opt);
+            warm_tables(keys_source);
+
+            assert_eq!(
+                TABLE_LOADS[keys_source as usize].load(AtomicOrdering::SeqCst),
+                1
+            );
+        }
+
+        assert!(Lazy::get(&FCD).is_some());
+        assert!(Lazy::get(&DECOMP).is_some());
+        assert!(Lazy::get(&JAMO).is_some());
+    }
+
+    #[test]
+    fn ignore_marks() {
+        // Fatha and kasra
+        let harakat: BTreeSet<u32> = [0x64E, 0x650].into();
+        let default = CollationOptions::default();
+        let opt = CollationOptions {
+            ignore_marks: Some(&harakat),
+            ..default
+        };
+
+        let pairs = [
+            (
+                "\u{643}\u{64E}\u{62A}\u{64E}\u{628}\u{64E}",
+                "\u{643}\u{62A}\u{628}",
+            ),
+            (
+                "\u{643}\u{650}\u{62A}\u{627}\u{628}",
+                "\u{643}\u{62A}\u{627}\u{628}",
+            ),
         ];
 
-        let options = CollationOptions {
-            keys_source: KeysSource::Ducet,
-            shifting: true,
+        for (vocalized, bare) in pairs {
+            assert_ne!(
+                collate_no_tiebreak(vocalized, bare, default),
+                Ordering::Equal
+            );
+            assert_eq!(collate_no_tiebreak(vocalized, bare, opt), Ordering::Equal);
+            assert_eq!(sort_key(vocalized, opt), sort_key(bare, opt));
+        }
+
+        // Damma still counts, as do the letters
+        assert_ne!(
+            collate_no_tiebreak("\u{643}\u{64F}\u{62A}\u{628}", "\u{643}\u{62A}\u{628}", opt),
+            Ordering::Equal
+        );
+        assert_ne!(
+            collate_no_tiebreak("\u{643}\u{62A}\u{628}", "\u{643}\u{630}\u{628}", opt),
+            Ordering::Equal
+        );
+
+        // Ignored marks after a shared prefix are ignored too
+        assert_eq!(
+            collate_no_tiebreak("\u{643}\u{62A}\u{64E}", "\u{643}\u{62A}", opt),
+            Ordering::Equal
+        );
+
+        // Accents elsewhere stay significant, unlike at primary strength
+        let accents: BTreeSet<u32> = [0x301].into();
+        let opt = CollationOptions {
+            ignore_marks: Some(&accents),
+            ..default
         };
+        assert_eq!(collate_no_tiebreak("café", "cafe", opt), Ordering::Equal);
+        assert_eq!(collate("cafè", "cafe", opt), Ordering::Greater);
+        assert_eq!(collate_no_tiebreak("Cafe", "cafe", opt), Ordering::Greater);
+    }
 
-        scrambled.sort_by(|a, b| collate(a, b, options));
+    #[test]
+    fn comparator_directions() {
+        let mut words = vec!["côte", "Cote", "cote", "coté", "abc", "zèbre", "Zebra"];
+        let opt = CollationOptions::default();
+
+        let ascending = comparator(opt, false);
+        words.sort_by(|a, b| ascending(a, b));
+        assert_eq!(
+            words,
+            ["abc", "cote", "Cote", "coté", "côte", "Zebra", "zèbre"]
+        );
+
+        let descending = comparator(opt, true);
+        words.sort_by(|a, b| descending(a, b));
+        assert_eq!(
+            words,
+            ["zèbre", "Zebra", "côte", "coté", "Cote", "cote", "abc"]
+        );
+
+        // The options come along
+        let french = comparator(locale_options("fr"), false);
+        words.sort_by(|a, b| french(a, b));
+        assert_eq!(
+            words,
+            ["abc", "cote", "Cote", "côte", "coté", "Zebra", "zèbre"]
+        );
+    }
 
-        let sorted = [
-            "ab©",
-            "abc",
-            "abC",
-            "𝒶bc",
-            "𝕒bc",
-            "Abc",
-            "abç",
-            "äbc",
-            "filé-110",
-            "file-12",
-            "File-3",
-            "か",
-            "ヵ",
-            "カ",
-            "ｶ",
-            "が",
-            "ガ",
+    #[test]
+    fn fcd_special_cases() {
+        let is_fcd = |s: &str| fcd(s.chars().map(|c| c as u32));
+
+        // U+0F81 is FCD by the book (it decomposes to U+0F71 U+0F80, CCCs 129 and 130), but it's
+        // still decomposed, as are U+0F73 and U+0F75
+        assert!(!is_fcd("\u{F81}"));
+        assert!(!is_fcd("\u{F40}\u{F81}"));
+        assert!(is_fcd("\u{F71}\u{F80}"));
+        assert_eq!(
+            get_nfd("\u{F71}\u{F81}", CollationOptions::default()),
+            [0xF71, 0xF71, 0xF80]
+        );
+
+        // Hangul syllables are starters, as are the jamo they decompose to. So they're FCD, at
+        // either end of the block, and get decomposed arithmetically on the fast path
+        let opt = CollationOptions::default();
+        for (syllable, jamo) in [
+            ("\u{AC00}", &[0x1100, 0x1161][..]),
+            ("\u{AC01}", &[0x1100, 0x1161, 0x11A8]),
+            ("\u{D7A3}", &[0x1112, 0x1175, 0x11C2]),
+        ] {
+            assert!(is_fcd(syllable));
+            assert!(is_fcd(&format!("{syllable}\u{301}")));
+            assert_eq!(get_nfd(syllable, opt), jamo);
+
+            let cps: Vec<u32> = syllable.chars().map(|c| c as u32).collect();
+            assert_eq!(get_nfd_code_points(&cps, opt), jamo);
+        }
+
+        // Just outside the block, nothing happens
+        assert!(is_fcd("\u{ABFF}\u{D7A4}"));
+        assert_eq!(get_nfd("\u{ABFF}\u{D7A4}", opt), [0xABFF, 0xD7A4]);
+
+        // A syllable and a trailing jamo are canonically equivalent to the LVT syllable
+        for keys_source in [KeysSource::Ducet, KeysSource::Cldr] {
+            let opt = CollationOptions { keys_source, ..opt };
+
+            assert_eq!(sort_key("\u{AC00}\u{11A8}", opt), sort_key("\u{AC01}", opt));
+            assert_eq!(
+                sort_key("\u{D7A3}", opt),
+                sort_key("\u{1112}\u{1175}\u{11C2}", opt)
+            );
+            assert_eq!(collate("\u{AC00}", "\u{AC01}", opt), Ordering::Less);
+            assert_eq!(collate("\u{AC01}", "\u{AC1C}", opt), Ordering::Less);
+        }
+    }
+
+    #[test]
+    fn embedded_nuls() {
+        let code_points = CollationOptions {
+            quaternary: QuaternarySource::CodePoints,
+            ..Default::default()
+        };
+
+        for opt in [CollationOptions::default(), code_points] {
+            let strings = ["\0", "a\0b", "ab", "\0ab\0", "a\0\0c", "ac", ""];
+
+            for s in strings {
+                let key = sort_key(s, opt);
+
+                // Separators are the only zeros, one fewer than there are levels
+                let zeros = key.as_slice().iter().filter(|w| **w == 0).count();
+                assert_eq!(zeros, key.levels() - 1, "{s:?}");
+
+                assert_eq!(SortKey::from_hex(&key.to_hex()), Ok(key.clone()));
+                assert_eq!(SortKey::from_base32(&key.to_base32()), Ok(key.clone()));
+                assert!(!key.to_hex().contains('\0') && !key.to_base32().contains('\0'));
+
+                for t in strings {
+                    assert_eq!(
+                        key.cmp(&sort_key(t, opt)),
+                        collate_no_tiebreak(s, t, opt),
+                        "{s:?} vs. {t:?}"
+                    );
+                }
+            }
+
+            // A NUL is ignored, apart from the code point level and the tiebreaker
+            assert_eq!(collate("a\0b", "ab", opt), Ordering::Less);
+            assert_eq!(collate("\0", "", opt), Ordering::Greater);
+        }
+
+        let default = CollationOptions::default();
+        assert_eq!(sort_key("a\0b", default), sort_key("ab", default));
+        assert_ne!(sort_key("a\0b", code_points), sort_key("ab", code_points));
+    }
+
+    #[test]
+    fn conformance_files() {
+        let tests = [
+            ("NON_IGNORABLE", KeysSource::Ducet, false),
+            ("SHIFTED", KeysSource::Ducet, true),
+            ("CLDR_NON_IGNORABLE", KeysSource::Cldr, false),
+            ("CLDR_SHIFTED", KeysSource::Cldr, true),
         ];
 
-        assert_eq!(scrambled, sorted);
+        for (name, keys_source, shifting) in tests {
+            let opt = CollationOptions {
+                keys_source,
+                shifting,
+                ..Default::default()
+            };
+
+            let short = format!("test-data/CollationTest_{name}_SHORT.txt");
+            let full = format!("test-data/CollationTest_{name}.txt");
+
+            // The full files aren't bundled, but they're used if present. The short ones are
+            // required: conformance_strings panics if one is missing
+            let full = std::path::Path::new(&full).exists().then_some(full);
+
+            for path in std::iter::once(short).chain(full) {
+                for pair in conformance_strings(&path).windows(2) {
+                    assert_ne!(
+                        collate_no_tiebreak(&pair[0], &pair[1], opt),
+                        Ordering::Greater,
+                        "{path}: {:?} vs. {:?}",
+                        pair[0],
+                        pair[1]
+                    );
+                }
+            }
+        }
     }
 }
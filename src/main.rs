@@ -2,30 +2,16 @@
 
 use once_cell::sync::{Lazy, OnceCell};
 use regex::Regex;
-use std::collections::HashSet;
 use std::{cmp::Ordering, collections::HashMap};
 use unicode_canonical_combining_class::get_canonical_combining_class as get_ccc;
 use unicol_sandbox::{collate_no_tiebreak, CollationOptions, KeysSource, Weights};
 
-const S_BASE: u32 = 0xAC00;
-const L_BASE: u32 = 0x1100;
-const V_BASE: u32 = 0x1161;
-const T_BASE: u32 = 0x11A7;
-const T_COUNT: u32 = 28;
-const N_COUNT: u32 = 588;
-
 static DECOMP: Lazy<HashMap<u32, Vec<u32>>> = Lazy::new(|| {
     let data = include_bytes!("bincode/decomp");
     let decoded: HashMap<u32, Vec<u32>> = bincode::deserialize(data).unwrap();
     decoded
 });
 
-static JAMO: Lazy<HashSet<u32>> = Lazy::new(|| {
-    let data = include_bytes!("bincode/jamo");
-    let decoded: HashSet<u32> = bincode::deserialize(data).unwrap();
-    decoded
-});
-
 macro_rules! regex {
     ($re:literal $(,)?) => {{
         static RE: OnceCell<Regex> = OnceCell::new();
@@ -34,78 +20,97 @@ macro_rules! regex {
 }
 
 fn main() {
-    //
-    // DUCET, NON-IGNORABLE
-    //
-
-    let path = "test-data/CollationTest_NON_IGNORABLE_SHORT.txt";
-
-    let options = CollationOptions {
-        keys_source: KeysSource::Ducet,
-        shifting: false,
-    };
-
-    conformance(path, options);
-
-    println!("Passed CollationTest_NON_IGNORABLE");
-
-    //
-    // DUCET, SHIFTED
-    //
-
-    let path = "test-data/CollationTest_SHIFTED_SHORT.txt";
-
-    let options = CollationOptions {
-        keys_source: KeysSource::Ducet,
-        shifting: true,
-    };
-
-    conformance(path, options);
-
-    println!("Passed CollationTest_SHIFTED");
-
-    //
-    // CLDR, NON-IGNORABLE
-    //
-
-    let path = "test-data/CollationTest_CLDR_NON_IGNORABLE_SHORT.txt";
+    // With --full, also run the complete test files (CollationTest_*.txt, which aren't bundled)
+    // if they've been added to test-data
+    let full = std::env::args().any(|arg| arg == "--full");
+
+    let tests = [
+        ("NON_IGNORABLE", KeysSource::Ducet, false),
+        ("SHIFTED", KeysSource::Ducet, true),
+        ("CLDR_NON_IGNORABLE", KeysSource::Cldr, false),
+        ("CLDR_SHIFTED", KeysSource::Cldr, true),
+    ];
+
+    let mut failed = false;
+
+    for (name, keys_source, shifting) in tests {
+        let options = CollationOptions {
+            keys_source,
+            shifting,
+            ..Default::default()
+        };
 
-    let options = CollationOptions {
-        keys_source: KeysSource::Cldr,
-        shifting: false,
-    };
+        let path = format!("test-data/CollationTest_{name}_SHORT.txt");
+        failed |= !report(
+            &format!("CollationTest_{name}"),
+            &conformance(&path, options),
+        );
 
-    conformance(path, options);
+        if full {
+            let path = format!("test-data/CollationTest_{name}.txt");
 
-    println!("Passed CollationTest_CLDR_NON_IGNORABLE");
+            if std::path::Path::new(&path).exists() {
+                let failures = conformance(&path, options);
+                failed |= !report(&format!("CollationTest_{name} (full)"), &failures);
+            } else {
+                println!("Skipped CollationTest_{name} (full): {path} not found");
+            }
+        }
+    }
 
-    //
-    // CLDR, SHIFTED
-    //
+    if failed {
+        std::process::exit(1);
+    }
+}
 
-    let path = "test-data/CollationTest_CLDR_SHIFTED_SHORT.txt";
+// A line of a conformance test file that sorted before the previous one (line numbers from 1)
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct ConformanceFailure {
+    line: usize,
+    previous: String,
+    current: String,
+}
 
-    let options = CollationOptions {
-        keys_source: KeysSource::Cldr,
-        shifting: true,
-    };
+// Prints every failure, and the total; true if there were none
+fn report(name: &str, failures: &[ConformanceFailure]) -> bool {
+    if failures.is_empty() {
+        println!("Passed {name}");
+        return true;
+    }
 
-    conformance(path, options);
+    for f in failures {
+        println!("  line {}: [{}] < [{}]", f.line, f.current, f.previous);
+    }
 
-    println!("Passed CollationTest_CLDR_SHIFTED");
+    println!("Failed {name}: {} lines out of order", failures.len());
+    false
 }
 
-fn conformance(path: &str, options: CollationOptions) {
+fn conformance(path: &str, options: CollationOptions) -> Vec<ConformanceFailure> {
     let test_data = std::fs::read_to_string(path).unwrap();
+    check_order(&test_data, options)
+}
+
+// Every line that collates before the one before it (rather than stopping at the first), so that,
+// e.g., a tailoring in progress can be measured by how many are left
+fn check_order(test_data: &str, options: CollationOptions) -> Vec<ConformanceFailure> {
+    let mut failures = Vec::new();
 
     let mut max_line = String::new();
+    let mut max_code_points = "";
 
-    for line in test_data.lines() {
+    for (i, line) in test_data.lines().enumerate() {
         if line.is_empty() || line.starts_with('#') {
             continue;
         }
 
-        let hex_values: Vec<&str> = line.split(' ').collect();
+        // The full files have a comment after the code points
+        let code_points = line.split(';').next().unwrap().trim();
+        if code_points.is_empty() {
+            continue;
+        }
+
+        let hex_values: Vec<&str> = code_points.split(' ').collect();
         let mut test_string = String::new();
 
         for s in hex_values {
@@ -117,10 +122,19 @@ fn conformance(path: &str, options: CollationOptions) {
         }
 
         let comparison = collate_no_tiebreak(&test_string, &max_line, options);
-        assert!(comparison != Ordering::Less);
+        if comparison == Ordering::Less {
+            failures.push(ConformanceFailure {
+                line: i + 1,
+                previous: max_code_points.to_string(),
+                current: code_points.to_string(),
+            });
+        }
 
         max_line = test_string;
+        max_code_points = code_points;
     }
+
+    failures
 }
 
 #[allow(unused)]
@@ -129,6 +143,8 @@ fn map_decomps() {
 
     let mut map: HashMap<u32, Vec<u32>> = HashMap::new();
 
+    let re = regex!(r"[\dA-F]{4,5}");
+
     for line in data.lines() {
         if line.is_empty() {
             continue;
@@ -161,8 +177,6 @@ fn map_decomps() {
 
         let decomp_col = splits[5];
 
-        let re = regex!(r"[\dA-F]{4,5}");
-
         let mut decomp: Vec<u32> = Vec::new();
 
         for cap in re.captures_iter(decomp_col) {
@@ -174,16 +188,17 @@ fn map_decomps() {
             continue;
         } else if decomp.len() > 1 {
             // Multi-code-point canonical decomposition; recurse badly
-            decomp
-                .into_iter()
-                .flat_map(|x| {
-                    let as_str = format!("{x:04X}");
-                    get_canonical_decomp(&as_str)
-                })
-                .collect::<Vec<u32>>()
+            let mut full = Vec::new();
+
+            for x in decomp {
+                let as_str = format!("{x:04X}");
+                full.extend(get_canonical_decomp(&data, &as_str).unwrap());
+            }
+
+            full
         } else if decomp.len() == 1 {
             // Single-code-point canonical decomposition; recurse simply
-            get_canonical_decomp(splits[0])
+            get_canonical_decomp(&data, splits[0]).unwrap()
         } else {
             // No decomposition; continue
             continue;
@@ -196,51 +211,67 @@ fn map_decomps() {
     std::fs::write("byte_dump", bytes).unwrap();
 }
 
-fn get_canonical_decomp(code_point: &str) -> Vec<u32> {
-    let data = std::fs::read_to_string("test-data/UnicodeData.txt").unwrap();
+// No real decomposition goes anywhere near this deep
+const MAX_DECOMP_DEPTH: usize = 16;
 
-    for line in data.lines() {
-        if line.starts_with(code_point) {
-            let decomp_col = line.split(';').nth(5).unwrap();
+// Errors out, rather than overflowing the stack, if the data has a cycle of decompositions
+fn get_canonical_decomp(data: &str, code_point: &str) -> Result<Vec<u32>, String> {
+    follow_canonical_decomp(data, code_point, &mut Vec::new())
+}
 
-            // Further decomposition is non-canonical; return the code point itself
-            if decomp_col.contains('<') {
-                return vec![u32::from_str_radix(code_point, 16).unwrap()];
-            }
+// The path holds the code points whose decompositions are being followed
+fn follow_canonical_decomp(
+    data: &str,
+    code_point: &str,
+    path: &mut Vec<u32>,
+) -> Result<Vec<u32>, String> {
+    let value = u32::from_str_radix(code_point, 16).unwrap();
+
+    if path.contains(&value) || path.len() >= MAX_DECOMP_DEPTH {
+        path.push(value);
+        return Err(format!("cyclic decomposition: {path:04X?}"));
+    }
 
-            let re = regex!(r"[\dA-F]{4,5}");
+    let re = regex!(r"[\dA-F]{4,5}");
+    let prefix = format!("{code_point};");
 
-            let mut decomp: Vec<u32> = Vec::new();
+    let Some(line) = data.lines().find(|line| line.starts_with(&prefix)) else {
+        // This means we followed a canonical decomposition to a single code point that was then
+        // not found in the first column of the table. Return it, I guess?
+        return Ok(vec![value]);
+    };
 
-            for cap in re.captures_iter(decomp_col) {
-                decomp.push(u32::from_str_radix(&cap[0], 16).unwrap());
-            }
+    let decomp_col = line.split(';').nth(5).unwrap();
 
-            // Further multiple-code-point decomposition; recurse badly
-            if decomp.len() > 1 {
-                return decomp
-                    .into_iter()
-                    .flat_map(|x| {
-                        let as_str = format!("{x:04X}");
-                        get_canonical_decomp(&as_str)
-                    })
-                    .collect::<Vec<u32>>();
-            }
+    // Further decomposition is non-canonical; return the code point itself
+    if decomp_col.contains('<') {
+        return Ok(vec![value]);
+    }
 
-            // Further single-code-point decomposition; recurse simply
-            if decomp.len() == 1 {
-                let as_str = format!("{:04X}", decomp[0]);
-                return get_canonical_decomp(&as_str);
-            }
+    let mut decomp: Vec<u32> = Vec::new();
 
-            // No further decomposition; return the code point itself
-            return vec![u32::from_str_radix(code_point, 16).unwrap()];
-        }
+    for cap in re.captures_iter(decomp_col) {
+        decomp.push(u32::from_str_radix(&cap[0], 16).unwrap());
+    }
+
+    // No further decomposition; return the code point itself
+    if decomp.is_empty() {
+        return Ok(vec![value]);
+    }
+
+    // Further decomposition, of one code point or more; recurse
+    path.push(value);
+
+    let mut full = Vec::new();
+
+    for x in decomp {
+        let as_str = format!("{x:04X}");
+        full.extend(follow_canonical_decomp(data, &as_str, path)?);
     }
 
-    // This means we followed a canonical decomposition to a single code point that was then not
-    // found in the first column of the table. Return it, I guess?
-    vec![u32::from_str_radix(code_point, 16).unwrap()]
+    path.pop();
+
+    Ok(full)
 }
 
 #[allow(unused)]
@@ -297,89 +328,6 @@ fn map_fcd() {
     std::fs::write("byte_dump", bytes).unwrap();
 }
 
-#[allow(unused)]
-fn decompose(input: &mut Vec<u32>) {
-    let mut i: usize = 0;
-
-    while i < input.len() {
-        if input[i] >= 0xAC00 && input[i] <= 0xD7A3 {
-            let rep = decompose_jamo(input[i]);
-            let n = rep.len();
-            input.splice(i..=i, rep);
-            i += n;
-            continue;
-        }
-
-        if let Some(rep) = DECOMP.get(&input[i]) {
-            input.splice(i..=i, rep.clone());
-            i += rep.len();
-            continue;
-        }
-
-        i += 1;
-    }
-}
-
-fn decompose_jamo(s: u32) -> Vec<u32> {
-    let s_index = s - S_BASE;
-
-    let lv = JAMO.get(&s).is_some();
-
-    if lv {
-        let l_index = s_index / N_COUNT;
-        let v_index = (s_index % N_COUNT) / T_COUNT;
-
-        let l_part = L_BASE + l_index;
-        let v_part = V_BASE + v_index;
-
-        vec![l_part, v_part]
-    } else {
-        let l_index = s_index / N_COUNT;
-        let v_index = (s_index % N_COUNT) / T_COUNT;
-        let t_index = s_index % T_COUNT;
-
-        let l_part = L_BASE + l_index;
-        let v_part = V_BASE + v_index;
-        let t_part = T_BASE + t_index;
-
-        vec![l_part, v_part, t_part]
-    }
-}
-
-#[allow(unused)]
-fn reorder(input: &mut Vec<u32>) {
-    let mut n = input.len();
-
-    while n > 1 {
-        let mut new_n = 0;
-
-        let mut i = 1;
-
-        while i < n {
-            let ccc_b = get_ccc(char::from_u32(input[i]).unwrap()) as u8;
-
-            if ccc_b == 0 {
-                i += 2;
-                continue;
-            }
-
-            let ccc_a = get_ccc(char::from_u32(input[i - 1]).unwrap()) as u8;
-
-            if ccc_a == 0 || ccc_a <= ccc_b {
-                i += 1;
-                continue;
-            }
-
-            input.swap(i - 1, i);
-
-            new_n = i;
-            i += 1;
-        }
-
-        n = new_n;
-    }
-}
-
 #[allow(unused)]
 fn map_low() {
     let data = std::fs::read_to_string("test-data/allkeys.txt").unwrap();
@@ -424,3 +372,82 @@ fn map_low() {
     let bytes = bincode::serialize(&map).unwrap();
     std::fs::write("byte_dump", bytes).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_decomp_follows_chains() {
+        let data = "\
+0041;LATIN CAPITAL LETTER A;Lu;0;L;;;;;N;;;;0061;
+00C5;LATIN CAPITAL LETTER A WITH RING ABOVE;Lu;0;L;0041 030A;;;;N;;;;00E5;
+01FA;LATIN CAPITAL LETTER A WITH RING ABOVE AND ACUTE;Lu;0;L;00C5 0301;;;;N;;;;01FB;
+212B;ANGSTROM SIGN;Lu;0;L;00C5;;;;N;;;;00E5;
+FB01;LATIN SMALL LIGATURE FI;Ll;0;L;<compat> 0066 0069;;;;N;;;;;
+";
+
+        assert_eq!(
+            get_canonical_decomp(data, "01FA"),
+            Ok(vec![0x41, 0x30A, 0x301])
+        );
+        assert_eq!(get_canonical_decomp(data, "212B"), Ok(vec![0x41, 0x30A]));
+        assert_eq!(get_canonical_decomp(data, "FB01"), Ok(vec![0xFB01]));
+        assert_eq!(get_canonical_decomp(data, "0041"), Ok(vec![0x41]));
+    }
+
+    #[test]
+    fn check_order_collects_all_failures() {
+        // Out of order twice: a after b, and b after c
+        let data = "\
+# broken on purpose
+0061
+0062
+0061
+0063
+0062
+0063
+0064
+";
+
+        let options = CollationOptions::default();
+
+        assert_eq!(
+            check_order(data, options),
+            [
+                ConformanceFailure {
+                    line: 4,
+                    previous: "0062".to_string(),
+                    current: "0061".to_string(),
+                },
+                ConformanceFailure {
+                    line: 6,
+                    previous: "0063".to_string(),
+                    current: "0062".to_string(),
+                },
+            ]
+        );
+
+        assert!(check_order("0061\n0061 0301\n0062\n", options).is_empty());
+        assert!(!report("broken", &check_order(data, options)));
+    }
+
+    #[test]
+    fn canonical_decomp_cycle() {
+        // Malformed: 1234 -> 5678 0301 -> 1234 0301 0301 -> ...
+        let data = "\
+1234;TEST ONE;Lu;0;L;5678 0301;;;;N;;;;;
+5678;TEST TWO;Lu;0;L;1234;;;;N;;;;;
+9ABC;TEST THREE;Lu;0;L;9ABC;;;;N;;;;;
+";
+
+        assert_eq!(
+            get_canonical_decomp(data, "1234"),
+            Err("cyclic decomposition: [1234, 5678, 1234]".to_string())
+        );
+        assert_eq!(
+            get_canonical_decomp(data, "9ABC"),
+            Err("cyclic decomposition: [9ABC, 9ABC]".to_string())
+        );
+    }
+}